@@ -1,12 +1,84 @@
-use std::{env, path::PathBuf};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
 
-fn main() {
-    // When building with fuse3, we get an outdated version warning message
-    // and (*fuse_get_conext()).private_data gets mangled
-    println!("cargo:rustc-link-lib=fuse3");
+/// Directory (relative to the manifest) holding the checked-in bindings.
+/// Each target gets its own subdirectory, and each libfuse minor version
+/// its own file, e.g. `bindings/x86_64-linux/fuse_3_10.rs`.
+const BINDINGS_DIR: &str = "bindings";
+
+/// The newest libfuse 3 minor version the crate knows about.
+const LATEST_MINOR: u32 = 17;
+
+/// The pkg-config (and link) name of the libfuse we're binding.
+#[cfg(not(feature = "fuse2"))]
+const LIBRARY: &str = "fuse3";
+#[cfg(feature = "fuse2")]
+const LIBRARY: &str = "fuse";
+
+#[cfg(not(feature = "fuse2"))]
+const MAJOR: u32 = 3;
+#[cfg(feature = "fuse2")]
+const MAJOR: u32 = 2;
+
+fn target_dir() -> PathBuf {
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let os = env::var("CARGO_CFG_TARGET_OS").unwrap();
+    PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap())
+        .join(BINDINGS_DIR)
+        .join(format!("{arch}-{os}"))
+}
+
+fn parse_version(version: &str) -> (u32, u32) {
+    let mut parts = version.split('.').map(|part| part.parse().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+/// Finds the pre-generated bindings for the newest minor version that
+/// doesn't exceed `version`.
+fn pregenerated((major, minor): (u32, u32)) -> Option<((u32, u32), PathBuf)> {
+    let entries = fs::read_dir(target_dir()).ok()?;
+
+    entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let stem = path.file_stem()?.to_str()?.strip_prefix("fuse_")?;
+            let found = parse_version(&stem.replace('_', "."));
+            Some((found, path))
+        })
+        .filter(|(found, _)| found.0 == major && found.1 <= minor)
+        .max_by_key(|(found, _)| *found)
+}
+
+/// Emits a `fuse_3_N` cfg for every minor version up to and including the
+/// detected one, so APIs can be gated with e.g. `#[cfg(fuse_3_12)]`.
+fn emit_version_cfgs((major, minor): (u32, u32)) {
+    for known in 0..=LATEST_MINOR {
+        println!("cargo:rustc-check-cfg=cfg(fuse_3_{known})");
+    }
+
+    if major == 3 {
+        for supported in 0..=minor {
+            println!("cargo:rustc-cfg=fuse_3_{supported}");
+        }
+    }
+}
+
+#[cfg(feature = "bindgen")]
+fn generate(library: &pkg_config::Library, (major, minor): (u32, u32), out: &Path) {
+    // 3.2 added fuse_loop_config and 3.12 reworked it, and each version of
+    // the multi-threaded loops is only declared when asking for it.
+    let use_version = match (major, minor) {
+        _ if cfg!(target_os = "macos") => 26,
+        (2, _) => 26,
+        (3, 12..) => 312,
+        (3, 2..) => 32,
+        _ => 31,
+    };
 
-    let library = pkg_config::probe_library("fuse3").unwrap();
     let bindings = bindgen::Builder::default()
+        .clang_arg(format!("-DFUSE_USE_VERSION={use_version}"))
         .clang_args(
             library
                 .include_paths
@@ -18,41 +90,153 @@ fn main() {
         .generate()
         .expect("Could not generate bindings");
 
-    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("bindings.rs");
     bindings
-        .write_to_file(&out)
+        .write_to_file(out)
         .expect("Couldn't write bindings!");
+}
 
-    #[cfg(feature = "auto")]
+fn main() {
+    println!("cargo:rerun-if-changed=wrapper.h");
+    println!("cargo:rerun-if-changed={BINDINGS_DIR}");
+    println!("cargo:rerun-if-env-changed=FUSE_SYS_UPDATE_BINDINGS");
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let out = out_dir.join("bindings.rs");
+
+    // docs.rs has neither libfuse nor libclang, so just document against
+    // the newest bindings we have. Without any, carry on as a normal build
+    // does, which works wherever libfuse is installed.
+    if env::var_os("DOCS_RS").is_some() {
+        if let Some((version, source)) = pregenerated((MAJOR, u32::MAX)) {
+            emit_version_cfgs(version);
+            fs::copy(source, &out).unwrap();
+            annotate(&out);
+            return;
+        }
+    }
+
+    // When building with fuse3, we get an outdated version warning message
+    // and (*fuse_get_conext()).private_data gets mangled
+    #[cfg(not(feature = "static"))]
+    println!("cargo:rustc-link-lib={LIBRARY}");
+
+    // Static builds pull in libfuse's private dependencies (pthread, dl)
+    // too. Those directives are emitted by hand, as pkg-config leaves
+    // libraries in the system's directories to be linked dynamically.
+    let library = pkg_config::Config::new()
+        .statik(cfg!(feature = "static"))
+        .cargo_metadata(!cfg!(feature = "static"))
+        .probe(LIBRARY)
+        .unwrap();
+    #[cfg(feature = "static")]
+    link_static(&library);
+    let version = parse_version(&library.version);
+    let found = pregenerated(version);
+
+    // Only bindings for exactly this version are worth comparing against.
+    println!(
+        "cargo:rustc-env=FUSE_SYS_PREGENERATED={}",
+        found
+            .as_ref()
+            .filter(|(found, _)| *found == version)
+            .map(|(_, path)| path.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    );
+
+    // With bindgen (the default), the bindings are generated for this
+    // libfuse, whether or not any are checked in.
+    #[cfg(feature = "bindgen")]
     {
-        use std::fs;
-
-        let mut bindings_raw = fs::read_to_string(&out).unwrap();
-
-        let operations_loc = bindings_raw
-            .find("pub struct fuse_operations")
-            .expect("Could not find struct fuse_operations");
-
-        // The attributes on the fuse_operations macro correspond
-        // to the fuse operations that are blacklisted
-        // for versioning issues. In theory these operations
-        // shouldn't show up on the struct at all, but whatever
-        // I'm not mad or anything like that's totally fine I'm fine.
-        #[cfg(not(target_os = "macos"))]
-        let blacklisted = ["getdir, utime"];
-
-        // macOS requires more operations to be blacklisted.
-        #[cfg(target_os = "macos")]
-        let blacklisted = ["getdir", "utime", "reserved00, reserved01"];
-
-        bindings_raw.insert_str(
-            operations_loc,
-            &format!(
-                "#[filesystem_macro::fuse_operations[{}]]\n",
-                blacklisted.join(", ")
-            ),
-        );
-
-        fs::write(out, bindings_raw).unwrap();
+        emit_version_cfgs(version);
+
+        // Keep the untouched output around so tests can compare it against
+        // the checked-in copy.
+        let generated = out_dir.join("bindings_generated.rs");
+        generate(&library, version, &generated);
+
+        if env::var_os("FUSE_SYS_UPDATE_BINDINGS").is_some() {
+            let dir = target_dir();
+            fs::create_dir_all(&dir).unwrap();
+            fs::copy(
+                &generated,
+                dir.join(format!("fuse_{}_{}.rs", version.0, version.1)),
+            )
+            .unwrap();
+        }
+
+        fs::copy(&generated, &out).unwrap();
     }
+
+    // Without it, the newest checked-in bindings not above this libfuse are
+    // used. libfuse keeps its ABI, so older ones work, but only what they
+    // declare is available.
+    #[cfg(not(feature = "bindgen"))]
+    {
+        let (found, source) = found.unwrap_or_else(|| {
+            panic!(
+                "No pre-generated bindings for libfuse {}.{} in {}; \
+                 build with the default `bindgen` feature \
+                 (and FUSE_SYS_UPDATE_BINDINGS=1 to check them in)",
+                version.0,
+                version.1,
+                target_dir().display(),
+            )
+        });
+        emit_version_cfgs(found);
+        fs::copy(source, &out).unwrap();
+    }
+
+    annotate(&out);
+}
+
+#[cfg(feature = "static")]
+fn link_static(library: &pkg_config::Library) {
+    let libdir = pkg_config::get_variable(LIBRARY, "libdir").unwrap();
+    for path in library
+        .link_paths
+        .iter()
+        .map(|path| path.to_string_lossy().into_owned())
+        .chain([libdir])
+    {
+        println!("cargo:rustc-link-search=native={path}");
+    }
+
+    println!("cargo:rustc-link-lib=static={LIBRARY}");
+    for lib in library.libs.iter().filter(|lib| *lib != LIBRARY) {
+        println!("cargo:rustc-link-lib={lib}");
+    }
+}
+
+#[cfg(not(feature = "auto"))]
+fn annotate(_out: &Path) {}
+
+#[cfg(feature = "auto")]
+fn annotate(out: &Path) {
+    let mut bindings_raw = fs::read_to_string(out).unwrap();
+
+    let operations_loc = bindings_raw
+        .find("pub struct fuse_operations")
+        .expect("Could not find struct fuse_operations");
+
+    // The attributes on the fuse_operations macro correspond
+    // to the fuse operations that are blacklisted
+    // for versioning issues. In theory these operations
+    // shouldn't show up on the struct at all, but whatever
+    // I'm not mad or anything like that's totally fine I'm fine.
+    #[cfg(not(target_os = "macos"))]
+    let blacklisted = ["getdir, utime"];
+
+    // macOS requires more operations to be blacklisted.
+    #[cfg(target_os = "macos")]
+    let blacklisted = ["getdir", "utime", "reserved00, reserved01"];
+
+    bindings_raw.insert_str(
+        operations_loc,
+        &format!(
+            "#[filesystem_macro::fuse_operations[{}]]\n",
+            blacklisted.join(", ")
+        ),
+    );
+
+    fs::write(out, bindings_raw).unwrap();
 }
@@ -0,0 +1,118 @@
+use clap::StructOpt;
+use fuse_sys::{fuse_file_info, lowlevel::*, off_t, stat};
+use libc::{S_IFDIR, S_IFREG};
+use nix::errno::Errno;
+use std::{env, ffi::OsStr, time::Duration};
+
+const HELLO_INO: Ino = 2;
+const HELLO_NAME: &str = "hello";
+const HELLO_CONTENTS: &[u8] = b"Hello, world!\n";
+const TTL: Duration = Duration::from_secs(1);
+
+struct Hello;
+
+impl Hello {
+    fn attr(ino: Ino) -> Option<stat> {
+        let (mode, nlink, size) = match ino {
+            ROOT_INO => (S_IFDIR | 0o755, 2, 0),
+            HELLO_INO => (S_IFREG | 0o444, 1, HELLO_CONTENTS.len()),
+            _ => return None,
+        };
+
+        Some(stat {
+            st_ino: ino,
+            st_mode: mode,
+            st_nlink: nlink,
+            st_size: size as _,
+            ..Default::default()
+        })
+    }
+}
+
+impl LowLevelFileSystem for Hello {
+    fn lookup(&self, _req: &Request, parent: Ino, name: &OsStr, reply: ReplyEntry) {
+        if parent == ROOT_INO && name == HELLO_NAME {
+            reply.entry(TTL, &Self::attr(HELLO_INO).unwrap(), 0);
+        } else {
+            reply.error(Errno::ENOENT);
+        }
+    }
+
+    fn getattr(&self, _req: &Request, ino: Ino, _fi: Option<&fuse_file_info>, reply: ReplyAttr) {
+        match Self::attr(ino) {
+            Some(attr) => reply.attr(TTL, &attr),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        ino: Ino,
+        size: usize,
+        off: off_t,
+        _fi: &fuse_file_info,
+        reply: ReplyData,
+    ) {
+        if ino != HELLO_INO {
+            return reply.error(Errno::EISDIR);
+        }
+
+        let start = (off as usize).min(HELLO_CONTENTS.len());
+        let end = (start + size).min(HELLO_CONTENTS.len());
+        reply.data(&HELLO_CONTENTS[start..end]);
+    }
+
+    fn readdir(
+        &self,
+        _req: &Request,
+        ino: Ino,
+        off: off_t,
+        _fi: &fuse_file_info,
+        mut reply: ReplyDirectory,
+    ) {
+        if ino != ROOT_INO {
+            return reply.error(Errno::ENOTDIR);
+        }
+
+        let entries = [
+            (ROOT_INO, S_IFDIR, "."),
+            (ROOT_INO, S_IFDIR, ".."),
+            (HELLO_INO, S_IFREG, HELLO_NAME),
+        ];
+
+        for (i, (ino, mode, name)) in entries.into_iter().enumerate().skip(off as usize) {
+            if !reply.add(ino, i as off_t + 1, mode, OsStr::new(name)) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+}
+
+#[derive(clap::Parser)]
+struct Args {
+    /// The path of filesystem's mount
+    #[clap(short, long, default_value = "/tmp/fsmnt")]
+    mount: String,
+    /// Whether or not to run fuse in debug mode
+    #[clap(short, long)]
+    debug: bool,
+}
+
+fn main() {
+    let bin = env::args().next().unwrap();
+    let Args { mount, debug } = Args::parse();
+
+    let mut fuse_args = vec![bin.as_str()];
+    if debug {
+        fuse_args.push("-d");
+    }
+
+    let mut session = Session::new(Hello, &fuse_args).unwrap();
+    session.mount(&mount).unwrap();
+
+    println!("Mounted hello filesystem at {mount}...");
+    session.run().unwrap();
+}
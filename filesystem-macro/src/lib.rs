@@ -1,14 +1,15 @@
 use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
-use quote::quote;
+use quote::{format_ident, quote};
 use std::collections::HashSet;
 use syn::{
     parse::Parser,
     parse_macro_input,
     punctuated::Punctuated,
     token::{Comma, Semi},
-    BareFnArg, Expr, Fields, GenericArgument, Ident, ItemStruct, PathArguments, ReturnType, Stmt,
-    Type, TypeBareFn, TypePtr,
+    visit_mut::VisitMut,
+    BareFnArg, Expr, Fields, GenericArgument, Ident, ItemStruct, Lifetime, PathArguments,
+    ReturnType, Stmt, Type, TypeBareFn, TypePtr, TypeReference,
 };
 
 const IDENT_CHARS: &str = "_qwertyuiopasdfghjklzxcvbnmQWERTYUIOPASDFGHJKLZXCVBNM";
@@ -16,6 +17,33 @@ const PRIMITIVE_IDENTS: &[&str] = &[
     "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize",
 ];
 
+// Arguments libfuse 3 appended to operations that already existed in libfuse 2,
+// keyed by the operation and its libfuse 2 argument count. When we're handed
+// libfuse 2 bindings these get added to the safe signatures and filled in with
+// null/zero, so the same FileSystem implementation works against both.
+const FUSE2_COMPAT: &[(&str, usize, &str)] = &[
+    ("getattr", 2, "fi: *mut fuse_file_info"),
+    ("rename", 2, "flags: ::std::os::raw::c_uint"),
+    ("chmod", 2, "fi: *mut fuse_file_info"),
+    ("chown", 3, "fi: *mut fuse_file_info"),
+    ("truncate", 2, "fi: *mut fuse_file_info"),
+    ("utimens", 2, "fi: *mut fuse_file_info"),
+    ("readdir", 5, "flags: fuse_readdir_flags"),
+];
+
+// The arguments of each operation that are paths within the filesystem,
+// which adapters like routers rewrite. Everything else just has the first.
+const PATH_ARGS: &[(&str, &[usize])] = &[
+    ("symlink", &[1]),
+    ("rename", &[0, 1]),
+    ("link", &[0, 1]),
+    ("copy_file_range", &[0, 3]),
+];
+
+// Pointer arguments that point at a fixed number of values rather than one,
+// keyed by the operation and the argument's position, with that number.
+const ARRAY_ARGS: &[(&str, usize, usize)] = &[("utimens", 1, 2)];
+
 fn gen_ident(base: &str) -> Ident {
     syn::parse(
         format!("{base}{}", random_string::generate(10, IDENT_CHARS))
@@ -29,6 +57,29 @@ fn is_ident(ty: &Type, ident: &str) -> bool {
     matches!(ty, Type::Path(path) if path.path.segments.last().unwrap().ident == ident)
 }
 
+fn camel_case(name: &Ident) -> Ident {
+    let camel: String = name
+        .to_string()
+        .split('_')
+        .map(|word| word[..1].to_uppercase() + &word[1..])
+        .collect();
+
+    format_ident!("{camel}")
+}
+
+/// Gives every elided reference the lifetime `'a`, so argument types can be
+/// stored in `Call`.
+struct NameLifetimes;
+
+impl VisitMut for NameLifetimes {
+    fn visit_type_reference_mut(&mut self, reference: &mut TypeReference) {
+        if reference.lifetime.is_none() {
+            reference.lifetime = Some(Lifetime::new("'a", proc_macro2::Span::call_site()));
+        }
+        syn::visit_mut::visit_type_reference_mut(self, reference);
+    }
+}
+
 struct UnsafeFnConvert {
     new_inputs: Punctuated<BareFnArg, Comma>,
     unconverted_call: Punctuated<Expr, Comma>,
@@ -55,7 +106,7 @@ impl UnsafeFnConvert {
         .unwrap()
     }
 
-    fn new(inputs: Punctuated<BareFnArg, Comma>) -> Self {
+    fn new(name: &Ident, inputs: Punctuated<BareFnArg, Comma>) -> Self {
         let mut reexport_types = HashSet::new();
         let mut new_inputs = Punctuated::new();
         let mut unconverted_call = Punctuated::new();
@@ -63,11 +114,19 @@ impl UnsafeFnConvert {
         let mut conversions: Vec<Stmt> = vec![];
 
         let mut lookahead = inputs.clone().into_iter().skip(1);
-        let mut inputs = inputs.into_iter();
+        let mut inputs = inputs.into_iter().enumerate();
 
-        while let Some(arg) = inputs.next() {
+        while let Some((position, arg)) = inputs.next() {
             let next = lookahead.next();
-            let sized = matches!(&next, Some(next) if is_ident(&next.ty, "usize"));
+            let array = ARRAY_ARGS
+                .iter()
+                .find(|(op, at, _)| name == op && *at == position)
+                .map(|(_, _, len)| *len);
+            // A buffer and its size, never the path every operation starts
+            // with, like bmap's path and block size.
+            let sized = position > 0
+                && matches!(&arg.ty, Type::Ptr(ptr) if is_ident(&ptr.elem, "c_char"))
+                && matches!(&next, Some(next) if is_ident(&next.ty, "usize"));
             let size_ident = next.map(|n| n.name.unwrap().0);
 
             let ident = arg.name.unwrap().0;
@@ -122,6 +181,38 @@ impl UnsafeFnConvert {
                     ty
                 }
 
+                Type::Ptr(TypePtr {
+                    mutability,
+                    const_token,
+                    elem,
+                    ..
+                }) if array.is_some() => {
+                    let len = array.unwrap();
+                    let ty =
+                        syn::parse(quote!(Option<& #mutability [#elem; #len]>).into()).unwrap();
+                    if let Type::Path(path) = &*elem {
+                        if let Some(ident) = path.path.get_ident() {
+                            reexport_types.insert(ident.to_string());
+                        }
+                    }
+
+                    let ref_from: Ident = syn::parse(
+                        if mutability.is_none() {
+                            quote!(as_ref)
+                        } else {
+                            quote!(as_mut)
+                        }
+                        .into(),
+                    )
+                    .unwrap();
+
+                    conversions.push(
+                        syn::parse(quote!(let #new_ident = (#ident as * #const_token #mutability [#elem; #len]) . #ref_from ();).into()).unwrap(),
+                    );
+
+                    ty
+                }
+
                 Type::Ptr(TypePtr {
                     mutability, elem, ..
                 }) => {
@@ -234,6 +325,19 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
     let mut threaded_fns = TokenStream2::new();
 
     let mut op_assignments: Vec<Stmt> = vec![];
+
+    let mut operation_variants = TokenStream2::new();
+    let mut operation_names = TokenStream2::new();
+    let mut operation_from_names = TokenStream2::new();
+    let mut call_variants = TokenStream2::new();
+    let mut call_operations = TokenStream2::new();
+    let mut call_forwards = TokenStream2::new();
+    let mut call_paths = TokenStream2::new();
+    let mut call_with_paths = TokenStream2::new();
+    let mut dispatch_fns = TokenStream2::new();
+    let mut dispatched_fns = TokenStream2::new();
+    let mut unthreaded_forwards = TokenStream2::new();
+    let mut forwarded_init = TokenStream2::new();
     let mut all_reexport_types = HashSet::new();
 
     for field in fields {
@@ -270,21 +374,51 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
             _ => continue,
         };
 
+        // init hands back the private data libfuse should use from then on,
+        // so it's the one operation we accept that doesn't return a c_int.
+        let is_init = name == "init";
+
         if variadic.is_some()
-            || !matches!(output, ReturnType::Type(_, ty)
-                if is_ident(ty, "c_int")
-            )
+            || !(is_init
+                || matches!(output, ReturnType::Type(_, ty)
+                    if is_ident(ty, "c_int")
+                ))
         {
             continue;
         }
 
+        let mut safe_inputs = inputs.clone();
+        let mut defaults: Vec<Stmt> = vec![];
+
+        for (_, _, arg) in FUSE2_COMPAT
+            .iter()
+            .filter(|(op, arity, _)| name == op && inputs.len() == *arity)
+        {
+            let arg: BareFnArg = syn::parse_str(arg).unwrap();
+            let ident = &arg.name.as_ref().unwrap().0;
+            let ty = &arg.ty;
+            let default = match ty {
+                Type::Ptr(_) => quote!(std::ptr::null_mut()),
+                _ => quote!(0),
+            };
+
+            defaults.push(syn::parse(quote!(let #ident: #ty = #default;).into()).unwrap());
+            safe_inputs.push(arg);
+        }
+
         let UnsafeFnConvert {
             new_inputs,
-            unconverted_call,
+            mut unconverted_call,
             converted_call,
             reexport_types,
             conversion,
-        } = UnsafeFnConvert::new(inputs.clone());
+        } = UnsafeFnConvert::new(&name, safe_inputs);
+
+        // The defaults only exist on our side, libfuse doesn't take them.
+        for _ in 0..defaults.len() {
+            unconverted_call.pop();
+        }
+        let conversion: Punctuated<Stmt, Semi> = defaults.into_iter().chain(conversion).collect();
 
         all_reexport_types.extend(reexport_types);
 
@@ -295,6 +429,66 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
 
         let fuse_fs_name: TokenStream2 = format!("crate::fuse_fs_{name}").parse().unwrap();
 
+        if is_init {
+            unthreaded_fns.extend([quote! {
+                fn init(&mut self, #new_inputs) {}
+            }]);
+            threaded_fns.extend([quote! {
+                fn init(&self, #new_inputs) {}
+            }]);
+
+            raw_trait_fn_sigs.extend([quote! {
+                #unsafety #abi fn init(#inputs) #output;
+            }]);
+
+            let init_args: Punctuated<Ident, Comma> = new_inputs
+                .iter()
+                .map(|arg| arg.name.as_ref().unwrap().0.clone())
+                .collect();
+            dispatch_fns.extend([quote! {
+                fn init(&self, #new_inputs) {}
+            }]);
+            dispatched_fns.extend([quote! {
+                fn init(&self, #new_inputs) {
+                    Dispatch::init(self, #init_args)
+                }
+            }]);
+            unthreaded_forwards.extend([quote! {
+                fn init(&mut self, #new_inputs) {
+                    UnthreadedFileSystem::init(&mut **self, #init_args)
+                }
+            }]);
+            forwarded_init.extend([quote! {
+                fn init(&self, #new_inputs) {
+                    FileSystem::init(&**self, #init_args)
+                }
+            }]);
+
+            for (stream, convert_ptr) in [
+                (&mut raw_threaded_fns, quote!(as_ref)),
+                (&mut raw_unthreaded_fns, quote!(as_mut)),
+            ] {
+                stream.extend([quote! {
+                    #unsafety #abi fn init(#inputs) #output {
+                        #conversion
+
+                        let #private_data_ident = private_data();
+
+                        Self::init(
+                            UserData::<Self>::from_raw(#private_data_ident).this.#convert_ptr().expect("Private data mangled"),
+                            #converted_call
+                        );
+
+                        #private_data_ident
+                    }
+                }]);
+            }
+
+            op_assignments
+                .push(syn::parse(quote!(operations.init = Some(Self::init);).into()).unwrap());
+            continue;
+        }
+
         unthreaded_fns.extend([quote! {
             fn #name (&mut self, #new_inputs) -> anyhow::Result<i32> {
                 Err(std::io::Error::from_raw_os_error(38).into())
@@ -310,6 +504,80 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
             #unsafety #abi fn #name (#inputs) #output;
         }]);
 
+        let variant = camel_case(&name);
+        let arg_names: Vec<_> = new_inputs
+            .iter()
+            .map(|arg| arg.name.as_ref().unwrap().0.clone())
+            .collect();
+        let arg_types: Vec<_> = new_inputs
+            .iter()
+            .map(|arg| {
+                let mut ty = arg.ty.clone();
+                NameLifetimes.visit_type_mut(&mut ty);
+                ty
+            })
+            .collect();
+        let bindings: Vec<_> = (0..arg_names.len())
+            .map(|i| format_ident!("arg{i}"))
+            .collect();
+
+        let path_indices = PATH_ARGS
+            .iter()
+            .find(|(op, _)| name == op)
+            .map_or(&[0][..], |(_, indices)| indices);
+        let path_indices: Vec<_> = path_indices
+            .iter()
+            .copied()
+            .filter(
+                |&i| matches!(arg_types.get(i), Some(ty) if quote!(#ty).to_string() == "& 'a str"),
+            )
+            .collect();
+        let path_bindings: Vec<_> = path_indices.iter().map(|&i| &bindings[i]).collect();
+        let path_patterns: Vec<_> = bindings
+            .iter()
+            .enumerate()
+            .map(|(i, binding)| match path_indices.contains(&i) {
+                true => quote!(#binding),
+                false => quote!(_),
+            })
+            .collect();
+        let with_paths_args: Vec<_> = bindings
+            .iter()
+            .enumerate()
+            .map(
+                |(i, binding)| match path_indices.iter().position(|&p| p == i) {
+                    Some(k) => quote!(paths[#k].as_str()),
+                    None => quote!(#binding),
+                },
+            )
+            .collect();
+
+        operation_variants.extend([quote!(#variant,)]);
+        operation_names.extend([quote!(Operation::#variant => stringify!(#name),)]);
+        let name_literal = name.to_string();
+        operation_from_names.extend([quote!(#name_literal => Some(Operation::#variant),)]);
+        call_variants.extend([quote!(#variant(#(#arg_types),*),)]);
+        call_operations.extend([quote!(Call::#variant(..) => Operation::#variant,)]);
+        call_forwards.extend([quote! {
+            Call::#variant(#(#bindings),*) => fs.#name(#(#bindings),*),
+        }]);
+        call_paths.extend([quote! {
+            Call::#variant(#(#path_patterns),*) => vec![#(*#path_bindings),*],
+        }]);
+        call_with_paths.extend([quote! {
+            Call::#variant(#(#bindings),*) => Call::#variant(#(#with_paths_args),*),
+        }]);
+        dispatched_fns.extend([quote! {
+            fn #name (&self, #new_inputs) -> anyhow::Result<i32> {
+                self.dispatch(Call::#variant(#(#arg_names),*))
+            }
+        }]);
+        unthreaded_forwards.extend([quote! {
+            fn #name (&mut self, #new_inputs) -> anyhow::Result<i32> {
+                UnthreadedFileSystem::#name(&mut **self, #(#arg_names),*)
+            }
+        }]);
+
         for (stream, convert_ptr) in [
             (&mut raw_threaded_fns, quote!(as_ref)),
             (&mut raw_unthreaded_fns, quote!(as_mut)),
@@ -318,7 +586,7 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
                 #unsafety #abi fn #name (#inputs) #output {
                     #conversion
 
-                    let mut #private_data_ident = UserData::<Self>::from_raw((*fuse_get_context()).private_data);
+                    let mut #private_data_ident = UserData::<Self>::from_raw(private_data());
 
                     let #out_ident = Self::#name(
                         #private_data_ident.this.#convert_ptr().expect("Private data mangled"),
@@ -353,7 +621,8 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
                         }
                     };
 
-                    if #out_ident == -38 {
+                    // There's no libfuse to fall back on when driven by a mock.
+                    if #out_ident == -38 && !mocked() {
                         let mut #dummy_private_data_ident = {
                             let mut ops = #private_data_ident.ops.clone();
                             ops.#name = None;
@@ -393,15 +662,26 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
 
     quote! {
         #[allow(unused_variables)]
-        pub trait UnthreadedFileSystem: Sized {
+        pub trait UnthreadedFileSystem {
             #unthreaded_fns
         }
-        pub trait FileSystem: Sized {
+
+        impl<F: UnthreadedFileSystem + ?Sized> UnthreadedFileSystem for std::boxed::Box<F> {
+            #unthreaded_forwards
+        }
+        pub trait FileSystem {
             #threaded_fns
         }
 
         pub trait FileSystemRaw<const UNTHREADED: bool> {
             #raw_trait_fn_sigs
+
+            /// The operations table libfuse calls this filesystem through.
+            fn operations() -> crate::fuse_operations where Self: Sized {
+                let mut operations = crate::fuse_operations::default();
+                #op_assignments
+                operations
+            }
         }
         impl<F: UnthreadedFileSystem> FileSystemRaw<true> for F {
             #raw_unthreaded_fns
@@ -410,6 +690,104 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
             #raw_threaded_fns
         }
 
+        /// Every operation a [`FileSystem`] can implement.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum Operation {
+            #operation_variants
+        }
+
+        impl Operation {
+            /// The name of the [`FileSystem`] method for the operation.
+            pub fn name(self) -> &'static str {
+                match self {
+                    #operation_names
+                }
+            }
+
+            /// The operation whose [`FileSystem`] method is called `name`.
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    #operation_from_names
+                    _ => None,
+                }
+            }
+        }
+
+        /// A call to a [`FileSystem`] operation, holding its arguments, for
+        /// adapters to inspect before passing it on with [`Call::forward`].
+        #[derive(Debug)]
+        pub enum Call<'a> {
+            #call_variants
+        }
+
+        impl<'a> Call<'a> {
+            pub fn operation(&self) -> Operation {
+                match self {
+                    #call_operations
+                }
+            }
+
+            /// Makes the call on `fs`.
+            pub fn forward<F: FileSystem + ?Sized>(self, fs: &F) -> anyhow::Result<i32> {
+                match self {
+                    #call_forwards
+                }
+            }
+
+            /// The arguments that are paths within the filesystem, which is
+            /// just the first for everything but `rename`, `link` and
+            /// `symlink`.
+            pub fn paths(&self) -> std::vec::Vec<&str> {
+                match self {
+                    #call_paths
+                }
+            }
+
+            /// The same call with the arguments [`Call::paths`] returns
+            /// replaced by `paths`, in order.
+            pub fn with_paths<'b>(self, paths: &'b [std::string::String]) -> Call<'b>
+            where
+                'a: 'b,
+            {
+                match self {
+                    #call_with_paths
+                }
+            }
+        }
+
+        /// Implements every [`FileSystem`] operation by handing it to
+        /// [`Dispatch::dispatch`] as a [`Call`], for adapters that treat
+        /// operations uniformly.
+        #[allow(unused_variables)]
+        pub trait Dispatch: Send + Sync {
+            fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32>;
+
+            #dispatch_fns
+        }
+
+        impl<D: Dispatch> FileSystem for D {
+            #dispatched_fns
+        }
+
+        // Boxes and Arcs, including of trait objects, are filesystems too.
+        // They go through Dispatch because a FileSystem impl for Box would
+        // overlap with the one above.
+        impl<F: FileSystem + Send + Sync + ?Sized> Dispatch for std::boxed::Box<F> {
+            #forwarded_init
+
+            fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+                call.forward(&**self)
+            }
+        }
+
+        impl<F: FileSystem + Send + Sync + ?Sized> Dispatch for std::sync::Arc<F> {
+            #forwarded_init
+
+            fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+                call.forward(&**self)
+            }
+        }
+
         pub trait FuseMain<const UNTHREADED: bool>: FileSystemRaw<UNTHREADED> + 'static {
             fn run(self, fuse_args: &[&str]) -> Result<(), i32>;
         }
@@ -432,10 +810,36 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
             }
         }
 
+        // Lets the mock driver stand in for libfuse, whose context doesn't
+        // exist outside a mount.
+        #[cfg(feature = "testing")]
+        std::thread_local! {
+            static MOCK_PRIVATE_DATA: std::cell::Cell<*mut std::ffi::c_void> =
+                std::cell::Cell::new(std::ptr::null_mut());
+        }
+
+        #[cfg(feature = "testing")]
+        fn mocked() -> bool {
+            MOCK_PRIVATE_DATA.with(|data| !data.get().is_null())
+        }
+
+        #[cfg(not(feature = "testing"))]
+        fn mocked() -> bool {
+            false
+        }
+
+        unsafe fn private_data() -> *mut std::ffi::c_void {
+            #[cfg(feature = "testing")]
+            if mocked() {
+                return MOCK_PRIVATE_DATA.with(|data| data.get());
+            }
+
+            (*fuse_get_context()).private_data
+        }
+
         impl<const UNTHREADED: bool, F: FileSystemRaw<UNTHREADED> + 'static> FuseMain<UNTHREADED> for F {
             fn run(self, fuse_args: &[&str]) -> Result<(), i32> {
-                let mut operations = crate::fuse_operations::default();
-                #op_assignments
+                let operations = Self::operations();
 
                 let mut this = self;
                 let mut user_data = UserData::new(
@@ -473,6 +877,9 @@ pub fn fuse_operations(attr: TokenStream, item: TokenStream) -> TokenStream {
                 UnthreadedFileSystem,
                 FileSystem,
                 FuseMain,
+                Call,
+                Dispatch,
+                Operation,
                 #reexport_list
             };
         }
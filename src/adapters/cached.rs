@@ -0,0 +1,374 @@
+use crate::{
+    dir_filler::DirFiller, errno, fuse_config, fuse_conn_info, fuse_fill_dir_flags, off_t, stat,
+    Call, Dispatch, FileSystem,
+};
+use nix::errno::Errno;
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::{c_void, CStr, OsStr, OsString},
+    os::{raw::c_char, unix::ffi::OsStrExt},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+struct Timed<T> {
+    value: T,
+    expires: Instant,
+}
+
+/// What's cached for one path.
+#[derive(Default)]
+struct Slot {
+    id: u64,
+    /// `ENOENT` is cached too, so repeated lookups of missing files are
+    /// answered here.
+    attr: Option<Timed<Result<stat, Errno>>>,
+    link: Option<Timed<Vec<u8>>>,
+    dir: Option<Timed<Vec<(OsString, stat)>>>,
+}
+
+struct Cache {
+    slots: HashMap<String, Slot>,
+    /// Paths in the order their slots were made, to evict the oldest first.
+    /// Slots that have since been invalidated are skipped by their id, and
+    /// pruned once they outnumber the live ones.
+    order: VecDeque<(String, u64)>,
+    next_id: u64,
+    /// Bumped on every invalidation, so a lookup that raced with a change
+    /// doesn't cache what it saw from before it.
+    generation: u64,
+}
+
+impl Cache {
+    fn get<T: Clone>(&self, path: &str, field: impl Fn(&Slot) -> &Option<Timed<T>>) -> Option<T> {
+        let timed = field(self.slots.get(path)?).as_ref()?;
+        (timed.expires > Instant::now()).then(|| timed.value.clone())
+    }
+
+    fn insert(
+        &mut self,
+        path: &str,
+        generation: u64,
+        capacity: usize,
+        update: impl FnOnce(&mut Slot),
+    ) {
+        if generation != self.generation || capacity == 0 {
+            return;
+        }
+
+        if !self.slots.contains_key(path) {
+            while self.slots.len() >= capacity {
+                let Some((oldest, id)) = self.order.pop_front() else {
+                    break;
+                };
+                if self.slots.get(&oldest).is_some_and(|slot| slot.id == id) {
+                    self.slots.remove(&oldest);
+                }
+            }
+
+            // Invalidating leaves slots' places behind, which would otherwise
+            // pile up for as long as nothing is evicted.
+            if self.order.len() >= 2 * self.slots.len().max(1) {
+                let slots = &self.slots;
+                self.order
+                    .retain(|(path, id)| slots.get(path).is_some_and(|slot| slot.id == *id));
+            }
+
+            self.next_id += 1;
+            self.order.push_back((path.to_string(), self.next_id));
+            self.slots.insert(
+                path.to_string(),
+                Slot {
+                    id: self.next_id,
+                    ..Default::default()
+                },
+            );
+        }
+
+        update(self.slots.get_mut(path).unwrap());
+    }
+
+    /// Forgets `path`, everything under it and its parent directory, whose
+    /// listing and times it's part of.
+    fn invalidate(&mut self, path: &str) {
+        let path = path.trim_end_matches('/');
+        let parent = match path.rsplit_once('/') {
+            Some(("", _)) | None => "/",
+            Some((parent, _)) => parent,
+        };
+        let below = format!("{path}/");
+
+        self.generation += 1;
+        self.slots.retain(|cached, _| {
+            let cached = cached.trim_end_matches('/');
+            cached != path && cached != parent && !cached.starts_with(&below)
+        });
+    }
+}
+
+unsafe extern "C" fn collect_entries(
+    buf: *mut c_void,
+    name: *const c_char,
+    stat: *const stat,
+    _off: off_t,
+    _flags: fuse_fill_dir_flags,
+) -> i32 {
+    let entries = &mut *(buf as *mut Vec<(OsString, stat)>);
+    let name = OsStr::from_bytes(CStr::from_ptr(name).to_bytes()).to_owned();
+    entries.push((name, stat.as_ref().copied().unwrap_or_default()));
+    0
+}
+
+/// Caches `getattr`, `readlink` and `readdir` for filesystems that are slow
+/// to ask, like remote ones.
+///
+/// Anything changed through the cache invalidates what it affects. Changes
+/// made some other way show up once their entries expire, or after
+/// [`Cached::invalidate`]. The kernel keeps a cache of its own, whose
+/// timeouts can be set here too.
+pub struct Cached<F> {
+    inner: F,
+    ttl: Duration,
+    capacity: usize,
+    entry_timeout: Option<Duration>,
+    attr_timeout: Option<Duration>,
+    negative_timeout: Option<Duration>,
+    cache: Mutex<Cache>,
+}
+
+impl<F: FileSystem> Cached<F> {
+    /// Caches for a second, holding up to 10,000 paths.
+    pub fn new(inner: F) -> Self {
+        Self {
+            inner,
+            ttl: Duration::from_secs(1),
+            capacity: 10_000,
+            entry_timeout: None,
+            attr_timeout: None,
+            negative_timeout: None,
+            cache: Mutex::new(Cache {
+                slots: HashMap::new(),
+                order: VecDeque::new(),
+                next_id: 0,
+                generation: 0,
+            }),
+        }
+    }
+
+    /// How long results are kept.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// How many paths results are kept for, beyond which the oldest are
+    /// dropped.
+    pub fn with_capacity(mut self, paths: usize) -> Self {
+        self.capacity = paths;
+        self
+    }
+
+    /// How long the kernel may cache names it has looked up.
+    pub fn with_entry_timeout(mut self, timeout: Duration) -> Self {
+        self.entry_timeout = Some(timeout);
+        self
+    }
+
+    /// How long the kernel may cache attributes.
+    pub fn with_attr_timeout(mut self, timeout: Duration) -> Self {
+        self.attr_timeout = Some(timeout);
+        self
+    }
+
+    /// How long the kernel may cache that a name doesn't exist.
+    pub fn with_negative_timeout(mut self, timeout: Duration) -> Self {
+        self.negative_timeout = Some(timeout);
+        self
+    }
+
+    /// Forgets what's cached for `path`, everything under it and its parent,
+    /// after it's been changed without going through the cache.
+    ///
+    /// This doesn't reach the kernel's cache, which
+    /// [`Notifier`](crate::notify::Notifier) can invalidate.
+    pub fn invalidate(&self, path: &str) {
+        self.cache().invalidate(path);
+    }
+
+    /// Forgets everything.
+    pub fn clear(&self) {
+        let mut cache = self.cache();
+        cache.generation += 1;
+        cache.slots.clear();
+        cache.order.clear();
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap()
+    }
+
+    fn expires(&self) -> Instant {
+        Instant::now() + self.ttl
+    }
+}
+
+/// Whether a call changes what's cached.
+fn invalidates(call: &Call<'_>) -> bool {
+    match call {
+        Call::Open(_, Some(fi)) => fi.flags & libc::O_TRUNC != 0,
+        call => call.operation().is_mutating(),
+    }
+}
+
+/// Copies a symlink's target into `buf` the way `readlink` answers,
+/// truncated and nul terminated.
+fn copy_link(target: &[u8], buf: &mut [u8]) {
+    if buf.is_empty() {
+        return;
+    }
+
+    let length = target.len().min(buf.len() - 1);
+    buf[..length].copy_from_slice(&target[..length]);
+    buf[length] = 0;
+}
+
+impl<F: FileSystem + Send + Sync> Dispatch for Cached<F> {
+    fn init(&self, conn: Option<&mut fuse_conn_info>, mut config: Option<&mut fuse_config>) {
+        self.inner.init(conn, config.as_deref_mut());
+
+        if let Some(config) = config {
+            for (timeout, field) in [
+                (self.entry_timeout, &mut config.entry_timeout),
+                (self.attr_timeout, &mut config.attr_timeout),
+                (self.negative_timeout, &mut config.negative_timeout),
+            ] {
+                if let Some(timeout) = timeout {
+                    *field = timeout.as_secs_f64();
+                }
+            }
+        }
+    }
+
+    fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+        match call {
+            // An open file's attributes are asked for by handle, which may
+            // not be reachable by its path any more.
+            Call::Getattr(path, Some(stat), None) => {
+                let (cached, generation) = {
+                    let cache = self.cache();
+                    (cache.get(path, |slot| &slot.attr), cache.generation)
+                };
+
+                let out = match cached {
+                    Some(out) => out,
+                    None => {
+                        let mut fresh = crate::stat::default();
+                        let out = match self.inner.getattr(path, Some(&mut fresh), None) {
+                            Ok(_) => Ok(fresh),
+                            Err(e) => Err(errno(&e)),
+                        };
+
+                        if matches!(out, Ok(_) | Err(Errno::ENOENT)) {
+                            let expires = self.expires();
+                            self.cache()
+                                .insert(path, generation, self.capacity, |slot| {
+                                    slot.attr = Some(Timed {
+                                        value: out,
+                                        expires,
+                                    });
+                                });
+                        }
+                        out
+                    }
+                };
+
+                *stat = out?;
+                Ok(0)
+            }
+            Call::Readlink(path, buf) => {
+                let (cached, generation) = {
+                    let cache = self.cache();
+                    (cache.get(path, |slot| &slot.link), cache.generation)
+                };
+
+                let target = match cached {
+                    Some(target) => target,
+                    None => {
+                        let mut fresh = vec![0; libc::PATH_MAX as usize + 1];
+                        self.inner.readlink(path, &mut fresh)?;
+                        fresh.truncate(fresh.iter().position(|&b| b == 0).unwrap_or(fresh.len()));
+
+                        let (value, expires) = (fresh.clone(), self.expires());
+                        self.cache()
+                            .insert(path, generation, self.capacity, |slot| {
+                                slot.link = Some(Timed { value, expires });
+                            });
+                        fresh
+                    }
+                };
+
+                copy_link(&target, buf);
+                Ok(0)
+            }
+            Call::Readdir(path, buf, filler, off, fi, flags) => {
+                let mut filler = match DirFiller::new(buf, filler) {
+                    Some(filler) => filler,
+                    None => return Ok(0),
+                };
+                let (cached, generation) = {
+                    let cache = self.cache();
+                    (cache.get(path, |slot| &slot.dir), cache.generation)
+                };
+
+                let entries = match cached {
+                    Some(entries) => entries,
+                    None => {
+                        let mut fresh: Vec<(OsString, stat)> = vec![];
+                        let fresh_buf = &mut fresh as *mut Vec<(OsString, stat)> as *mut c_void;
+                        self.inner.readdir(
+                            path,
+                            Some(unsafe { &mut *fresh_buf }),
+                            Some(collect_entries),
+                            0,
+                            fi,
+                            flags,
+                        )?;
+
+                        let (value, expires) = (fresh.clone(), self.expires());
+                        self.cache()
+                            .insert(path, generation, self.capacity, |slot| {
+                                slot.dir = Some(Timed { value, expires });
+                            });
+                        fresh
+                    }
+                };
+
+                for (i, (name, stat)) in entries.iter().enumerate().skip(off as usize) {
+                    if !filler.add(name, Some(stat), i as off_t + 1) {
+                        break;
+                    }
+                }
+                Ok(0)
+            }
+            call if invalidates(&call) => {
+                let paths: Vec<String> = call.paths().into_iter().map(String::from).collect();
+                let out = call.forward(&self.inner);
+
+                let mut cache = self.cache();
+                for path in &paths {
+                    cache.invalidate(path);
+                }
+                out
+            }
+            call => call.forward(&self.inner),
+        }
+    }
+}
@@ -0,0 +1,473 @@
+use crate::{
+    fuse_config, fuse_conn_info, fuse_file_info, stat, timespec, Call, Dispatch, FileSystem,
+    Operation,
+};
+use anyhow::{anyhow, bail};
+use nix::errno::Errno;
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// What happens to a call a [`Rule`] fires on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Fails the call.
+    Error(Errno),
+    /// Reads and writes transfer at most this many bytes. Other calls go
+    /// through as they are.
+    Short(usize),
+    /// Waits before making the call.
+    Delay(Duration),
+}
+
+/// Which of the matching calls a [`Rule`] fires on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Trigger {
+    Always,
+    Probability(f64),
+    /// Only the nth, counting from 1.
+    Nth(u64),
+    Every(u64),
+}
+
+/// When to inject a [`Fault`], matching calls by operation and path.
+///
+/// Rules are also written one per line, e.g. in the control file:
+///
+/// ```text
+/// # operation (or *), path glob, fault, and optionally when
+/// read /data/** EIO p=0.1
+/// write * short=512 every=2
+/// * /slow/* delay=200ms
+/// open /config ENOENT nth=3
+/// ```
+///
+/// In the glob `*` and `?` don't match `/`, while `**` matches anything.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    operation: Option<Operation>,
+    pattern: Option<String>,
+    fault: Fault,
+    trigger: Trigger,
+    /// How many calls have matched so far.
+    matched: u64,
+}
+
+impl Rule {
+    /// Injects `fault` into every call.
+    pub fn new(fault: Fault) -> Self {
+        Self {
+            operation: None,
+            pattern: None,
+            fault,
+            trigger: Trigger::Always,
+            matched: 0,
+        }
+    }
+
+    /// Only matches calls to `operation`.
+    pub fn on(mut self, operation: Operation) -> Self {
+        self.operation = Some(operation);
+        self
+    }
+
+    /// Only matches calls on paths matching `glob`, like `/logs/*.txt`, or
+    /// with one that does for `rename` and `link`. `*` on its own matches
+    /// everything.
+    pub fn at(mut self, glob: &str) -> Self {
+        self.pattern = (glob != "*").then(|| glob.to_string());
+        self
+    }
+
+    /// Fires on each matching call with the given probability, clamped to
+    /// between 0 and 1, with NaN never firing.
+    pub fn with_probability(mut self, probability: f64) -> Self {
+        self.trigger = Trigger::Probability(match probability.is_nan() {
+            true => 0.0,
+            false => probability.clamp(0.0, 1.0),
+        });
+        self
+    }
+
+    /// Fires on the nth matching call only, counting from 1.
+    pub fn nth(mut self, n: u64) -> Self {
+        self.trigger = Trigger::Nth(n.max(1));
+        self
+    }
+
+    /// Fires on every nth matching call.
+    pub fn every(mut self, n: u64) -> Self {
+        self.trigger = Trigger::Every(n.max(1));
+        self
+    }
+
+    fn matches(&self, call: &Call<'_>) -> bool {
+        if self
+            .operation
+            .is_some_and(|operation| operation != call.operation())
+        {
+            return false;
+        }
+
+        match &self.pattern {
+            Some(pattern) => call
+                .paths()
+                .iter()
+                .any(|path| glob(pattern.as_bytes(), path.as_bytes())),
+            None => true,
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operation = self.operation.map_or("*", Operation::name);
+        let pattern = self.pattern.as_deref().unwrap_or("*");
+        write!(f, "{operation} {pattern} ")?;
+
+        match self.fault {
+            Fault::Error(errno) => write!(f, "{errno:?}")?,
+            Fault::Short(bytes) => write!(f, "short={bytes}")?,
+            Fault::Delay(delay) => write!(f, "delay={}ms", delay.as_millis())?,
+        }
+
+        match self.trigger {
+            Trigger::Always => Ok(()),
+            Trigger::Probability(probability) => write!(f, " p={probability}"),
+            Trigger::Nth(n) => write!(f, " nth={n}"),
+            Trigger::Every(n) => write!(f, " every={n}"),
+        }
+    }
+}
+
+fn parse_errno(name: &str) -> Option<Errno> {
+    (1..4096)
+        .map(Errno::from_i32)
+        .filter(|&errno| errno != Errno::UnknownErrno)
+        .find(|errno| format!("{errno:?}") == name)
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> anyhow::Result<Self> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (operation, pattern, fault, trigger) = match words[..] {
+            [operation, pattern, fault] => (operation, pattern, fault, None),
+            [operation, pattern, fault, trigger] => (operation, pattern, fault, Some(trigger)),
+            _ => bail!("Expected an operation, a path, a fault and maybe a trigger in {line:?}"),
+        };
+
+        let fault = match fault.split_once('=') {
+            Some(("short", bytes)) => Fault::Short(bytes.parse()?),
+            Some(("delay", delay)) => {
+                let millis = delay
+                    .strip_suffix("ms")
+                    .ok_or_else(|| anyhow!("Delays are in milliseconds, like 100ms"))?;
+                Fault::Delay(Duration::from_millis(millis.parse()?))
+            }
+            Some(_) => bail!("Unknown fault {fault:?}"),
+            None => match parse_errno(fault) {
+                Some(errno) => Fault::Error(errno),
+                None => bail!("Unknown errno {fault:?}"),
+            },
+        };
+
+        let mut rule = Rule::new(fault).at(pattern);
+        if operation != "*" {
+            let operation = Operation::from_name(operation)
+                .ok_or_else(|| anyhow!("Unknown operation {operation:?}"))?;
+            rule = rule.on(operation);
+        }
+
+        let Some(trigger) = trigger else {
+            return Ok(rule);
+        };
+        Ok(match trigger.split_once('=') {
+            Some(("p", probability)) => {
+                let probability: f64 = probability.parse()?;
+                if !(0.0..=1.0).contains(&probability) {
+                    bail!("Probabilities are from 0 to 1, not {probability}");
+                }
+                rule.with_probability(probability)
+            }
+            Some(("nth", n)) => rule.nth(n.parse()?),
+            Some(("every", n)) => rule.every(n.parse()?),
+            _ => bail!("Unknown trigger {trigger:?}"),
+        })
+    }
+}
+
+/// Whether `path` matches `pattern`, where `*` and `?` match within a
+/// component and `**` across them.
+fn glob(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob(rest, &path[i..])),
+        [b'*', rest @ ..] => {
+            let component = path.iter().position(|&b| b == b'/').unwrap_or(path.len());
+            (0..=component).any(|i| glob(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => matches!(path, [b, tail @ ..] if *b != b'/' && glob(rest, tail)),
+        [c, rest @ ..] => matches!(path, [b, tail @ ..] if b == c && glob(rest, tail)),
+    }
+}
+
+/// A xorshift generator, which is plenty for deciding when to fail.
+struct Random(u64);
+
+impl Random {
+    /// A number in `[0, 1)`.
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Injects errors, short reads and writes, and latency into calls to a
+/// filesystem by [`Rule`], to test how applications cope with flaky
+/// storage.
+///
+/// With [`FaultInjector::with_control_file`], the rules can be read and
+/// replaced from inside the mount, one per line:
+///
+/// ```sh
+/// echo 'read /data/** EIO p=0.5' > /mnt/.faults
+/// echo 'write * ENOSPC' >> /mnt/.faults
+/// cat /mnt/.faults
+/// : > /mnt/.faults  # back to normal
+/// ```
+///
+/// The control file isn't listed in its directory, and no faults are
+/// injected into it.
+pub struct FaultInjector<F> {
+    inner: F,
+    rules: Mutex<Vec<Rule>>,
+    random: Mutex<Random>,
+    control: Option<String>,
+    /// What's being written to the control file, by handle, until it's
+    /// closed.
+    pending: Mutex<HashMap<u64, Vec<u8>>>,
+    next_fh: AtomicU64,
+    created: timespec,
+}
+
+impl<F: FileSystem> FaultInjector<F> {
+    /// Injects nothing until rules are added.
+    pub fn new(inner: F) -> Self {
+        let created = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Self {
+            inner,
+            rules: Mutex::default(),
+            random: Mutex::new(Random(created.as_nanos() as u64 | 1)),
+            control: None,
+            pending: Mutex::default(),
+            next_fh: AtomicU64::new(1),
+            created: timespec {
+                tv_sec: created.as_secs() as _,
+                tv_nsec: created.subsec_nanos() as _,
+            },
+        }
+    }
+
+    pub fn with_rule(self, rule: Rule) -> Self {
+        self.rules().push(rule);
+        self
+    }
+
+    /// Seeds the probabilities, to make a run repeatable.
+    pub fn with_seed(self, seed: u64) -> Self {
+        // Xorshift gets stuck at zero.
+        *self.random.lock().unwrap() = Random(seed.max(1));
+        self
+    }
+
+    /// Exposes the rules at `path`, e.g. `/.faults`, to be read and written.
+    pub fn with_control_file(mut self, path: &str) -> Self {
+        self.control = Some(path.to_string());
+        self
+    }
+
+    /// Replaces the rules, starting their counts again.
+    pub fn set_rules(&self, rules: Vec<Rule>) {
+        *self.rules() = rules;
+    }
+
+    /// The rules, as the control file shows them.
+    pub fn rules_text(&self) -> String {
+        self.rules()
+            .iter()
+            .map(|rule| format!("{rule}\n"))
+            .collect()
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    fn rules(&self) -> MutexGuard<'_, Vec<Rule>> {
+        self.rules.lock().unwrap()
+    }
+
+    /// The faults the rules fire for a call, in the order of the rules.
+    fn faults(&self, call: &Call<'_>) -> Vec<Fault> {
+        let mut rules = self.rules();
+        let mut faults = vec![];
+
+        for rule in rules.iter_mut().filter(|rule| rule.matches(call)) {
+            rule.matched += 1;
+            let fires = match rule.trigger {
+                Trigger::Always => true,
+                Trigger::Probability(probability) => {
+                    self.random.lock().unwrap().next() < probability
+                }
+                Trigger::Nth(n) => rule.matched == n,
+                Trigger::Every(n) => rule.matched % n == 0,
+            };
+
+            if fires {
+                faults.push(rule.fault);
+            }
+        }
+
+        faults
+    }
+
+    fn control_stat(&self) -> stat {
+        stat {
+            st_mode: libc::S_IFREG | 0o644,
+            st_nlink: 1,
+            st_uid: unsafe { libc::geteuid() },
+            st_gid: unsafe { libc::getegid() },
+            st_size: self.rules_text().len() as _,
+            st_atim: self.created,
+            st_mtim: self.created,
+            st_ctim: self.created,
+            ..Default::default()
+        }
+    }
+
+    /// Replaces the rules with what's been written to the control file
+    /// through handle `fh`. Rules that don't parse are refused with `EINVAL`.
+    fn apply_pending(&self, fh: u64) -> anyhow::Result<()> {
+        let Some(pending) = self.pending.lock().unwrap().remove(&fh) else {
+            return Ok(());
+        };
+
+        let rules = String::from_utf8(pending)
+            .map_err(|_| Errno::EINVAL)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Rule::from_str)
+            .collect::<anyhow::Result<_>>()
+            .map_err(|_| Errno::EINVAL)?;
+
+        self.set_rules(rules);
+        Ok(())
+    }
+
+    /// Answers a call on the control file.
+    fn control(&self, call: Call<'_>) -> anyhow::Result<i32> {
+        match call {
+            Call::Getattr(_, Some(stat), _) => *stat = self.control_stat(),
+            Call::Getattr(_, None, _) => return Err(Errno::EINVAL.into()),
+            Call::Access(..) | Call::Truncate(..) | Call::Utimens(..) => {}
+            Call::Open(_, fi) => {
+                let Some(fi) = fi else {
+                    return Ok(0);
+                };
+
+                fi.fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+                if fi.flags & libc::O_ACCMODE != libc::O_RDONLY {
+                    let start = match fi.flags & libc::O_APPEND {
+                        0 => vec![],
+                        _ => self.rules_text().into_bytes(),
+                    };
+                    self.pending.lock().unwrap().insert(fi.fh, start);
+                }
+            }
+            Call::Read(_, buf, off, _) => {
+                let text = self.rules_text().into_bytes();
+                let start = (off as usize).min(text.len());
+                let n = (text.len() - start).min(buf.len());
+                buf[..n].copy_from_slice(&text[start..start + n]);
+                return Ok(n as i32);
+            }
+            Call::Write(_, buf, off, fi) => {
+                let mut pending = self.pending.lock().unwrap();
+                let pending = pending.entry(fh(&fi)).or_default();
+                let end = off as usize + buf.len();
+                if pending.len() < end {
+                    pending.resize(end, 0);
+                }
+                pending[off as usize..end].copy_from_slice(buf);
+                return Ok(buf.len() as i32);
+            }
+            Call::Flush(_, fi) | Call::Fsync(_, _, fi) | Call::Release(_, fi) => {
+                self.apply_pending(fh(&fi))?
+            }
+            Call::Getxattr(..) => return Err(Errno::ENODATA.into()),
+            Call::Listxattr(..) => {}
+            _ => return Err(Errno::EPERM.into()),
+        }
+
+        Ok(0)
+    }
+}
+
+fn fh(fi: &Option<&mut fuse_file_info>) -> u64 {
+    fi.as_ref().map_or(0, |fi| fi.fh)
+}
+
+impl<F: FileSystem + Send + Sync> Dispatch for FaultInjector<F> {
+    fn init(&self, conn: Option<&mut fuse_conn_info>, config: Option<&mut fuse_config>) {
+        self.inner.init(conn, config);
+    }
+
+    fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+        if let Some(control) = &self.control {
+            if call.paths().contains(&control.as_str()) {
+                return self.control(call);
+            }
+        }
+
+        let mut short = None;
+        for fault in self.faults(&call) {
+            match fault {
+                Fault::Delay(delay) => thread::sleep(delay),
+                Fault::Error(errno) => return Err(errno.into()),
+                Fault::Short(bytes) => short = Some(short.unwrap_or(bytes).min(bytes)),
+            }
+        }
+
+        match (call, short) {
+            (Call::Read(path, buf, off, fi), Some(bytes)) => {
+                let bytes = bytes.min(buf.len());
+                self.inner.read(path, &mut buf[..bytes], off, fi)
+            }
+            (Call::Write(path, buf, off, fi), Some(bytes)) => {
+                let bytes = bytes.min(buf.len());
+                self.inner.write(path, &buf[..bytes], off, fi)
+            }
+            (call, _) => call.forward(&self.inner),
+        }
+    }
+}
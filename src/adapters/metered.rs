@@ -0,0 +1,255 @@
+use crate::{errno, Call, Dispatch, FileSystem, Operation};
+use nix::errno::Errno;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, Write},
+    os::unix::net::UnixListener,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// The upper bounds of the latency histogram buckets, in seconds.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+    5.0, 10.0,
+];
+
+/// A latency histogram over [`LATENCY_BUCKETS`], plus an overflow bucket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Histogram {
+    /// How many calls fell in each bucket (not cumulative).
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    pub sum: Duration,
+    pub count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; LATENCY_BUCKETS.len() + 1],
+            sum: Duration::ZERO,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        self.buckets[bucket] += 1;
+        self.sum += latency;
+        self.count += 1;
+    }
+}
+
+/// What's been recorded for one operation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OperationMetrics {
+    pub calls: u64,
+    /// Failed calls, by the errno they were reported as.
+    pub errors: BTreeMap<i32, u64>,
+    /// Bytes transferred, for `read`, `write` and `write_buf`.
+    pub bytes: u64,
+    pub latency: Histogram,
+}
+
+/// A copy of the metrics at one point in time.
+pub type Snapshot = HashMap<Operation, OperationMetrics>;
+
+/// The metrics a [`Metered`] filesystem records, which can be held on to
+/// after the filesystem has been handed over to be mounted.
+#[derive(Default)]
+pub struct Metrics {
+    operations: Mutex<Snapshot>,
+}
+
+impl Metrics {
+    fn record(&self, operation: Operation, out: &anyhow::Result<i32>, latency: Duration) {
+        let mut operations = self.operations.lock().unwrap();
+        let metrics = operations.entry(operation).or_default();
+
+        metrics.calls += 1;
+        metrics.latency.record(latency);
+
+        match out {
+            Ok(n) if is_transfer(operation) => metrics.bytes += *n as u64,
+            Ok(_) => {}
+            Err(e) => *metrics.errors.entry(errno(e) as i32).or_default() += 1,
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.operations.lock().unwrap().clone()
+    }
+
+    /// Writes the metrics in Prometheus' text exposition format.
+    pub fn write_prometheus(&self, w: &mut impl Write) -> io::Result<()> {
+        let mut operations: Vec<_> = self.snapshot().into_iter().collect();
+        operations.sort_by_key(|(operation, _)| operation.name());
+
+        writeln!(w, "# HELP fuse_operations_total Operations handled.")?;
+        writeln!(w, "# TYPE fuse_operations_total counter")?;
+        for (operation, metrics) in &operations {
+            let op = operation.name();
+            writeln!(w, "fuse_operations_total{{op=\"{op}\"}} {}", metrics.calls)?;
+        }
+
+        writeln!(
+            w,
+            "# HELP fuse_errors_total Operations that failed, by errno."
+        )?;
+        writeln!(w, "# TYPE fuse_errors_total counter")?;
+        for (operation, metrics) in &operations {
+            let op = operation.name();
+            for (&errno, count) in &metrics.errors {
+                let errno = Errno::from_i32(errno);
+                writeln!(
+                    w,
+                    "fuse_errors_total{{op=\"{op}\",errno=\"{errno:?}\"}} {count}"
+                )?;
+            }
+        }
+
+        writeln!(w, "# HELP fuse_bytes_total Bytes read or written.")?;
+        writeln!(w, "# TYPE fuse_bytes_total counter")?;
+        for (operation, metrics) in &operations {
+            if is_transfer(*operation) {
+                let op = operation.name();
+                writeln!(w, "fuse_bytes_total{{op=\"{op}\"}} {}", metrics.bytes)?;
+            }
+        }
+
+        writeln!(
+            w,
+            "# HELP fuse_operation_duration_seconds How long operations took."
+        )?;
+        writeln!(w, "# TYPE fuse_operation_duration_seconds histogram")?;
+        for (operation, metrics) in &operations {
+            let op = operation.name();
+            let histogram = &metrics.latency;
+            let mut cumulative = 0;
+
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                writeln!(
+                    w,
+                    "fuse_operation_duration_seconds_bucket{{op=\"{op}\",le=\"{bound}\"}} {cumulative}"
+                )?;
+            }
+            writeln!(
+                w,
+                "fuse_operation_duration_seconds_bucket{{op=\"{op}\",le=\"+Inf\"}} {}",
+                histogram.count
+            )?;
+            writeln!(
+                w,
+                "fuse_operation_duration_seconds_sum{{op=\"{op}\"}} {}",
+                histogram.sum.as_secs_f64()
+            )?;
+            writeln!(
+                w,
+                "fuse_operation_duration_seconds_count{{op=\"{op}\"}} {}",
+                histogram.count
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the metrics to `path` for a node exporter's textfile collector,
+    /// replacing it atomically so it's never read half written.
+    pub fn write_prometheus_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        let mut file = fs::File::create(&tmp)?;
+        self.write_prometheus(&mut file)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Serves the metrics on a Unix socket at `path` from a background
+    /// thread, writing them to each connection and closing it.
+    pub fn serve_prometheus(
+        self: &Arc<Self>,
+        path: impl AsRef<Path>,
+    ) -> io::Result<JoinHandle<()>> {
+        let listener = UnixListener::bind(path)?;
+        let metrics = self.clone();
+
+        Ok(thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = metrics.write_prometheus(&mut stream);
+            }
+        }))
+    }
+}
+
+fn is_transfer(operation: Operation) -> bool {
+    matches!(
+        operation,
+        Operation::Read | Operation::Write | Operation::WriteBuf
+    )
+}
+
+/// Records how often each operation is called, how it fails, how long it
+/// takes and how much data it moves.
+pub struct Metered<F> {
+    inner: F,
+    metrics: Arc<Metrics>,
+}
+
+impl<F: FileSystem> Metered<F> {
+    pub fn new(inner: F) -> Self {
+        Self {
+            inner,
+            metrics: Arc::default(),
+        }
+    }
+
+    /// A handle on the metrics, which stays usable once the filesystem is
+    /// mounted.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.metrics.snapshot()
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+impl<F: FileSystem + Send + Sync> Dispatch for Metered<F> {
+    fn init(
+        &self,
+        conn: Option<&mut crate::fuse_conn_info>,
+        config: Option<&mut crate::fuse_config>,
+    ) {
+        self.inner.init(conn, config);
+    }
+
+    fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+        let operation = call.operation();
+        let start = Instant::now();
+        let out = call.forward(&self.inner);
+
+        self.metrics.record(operation, &out, start.elapsed());
+        out
+    }
+}
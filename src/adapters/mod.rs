@@ -0,0 +1,66 @@
+//! Filesystems that wrap another [`FileSystem`](crate::FileSystem) to change
+//! or observe how it behaves. Most are built on [`Dispatch`](crate::Dispatch),
+//! so they see every operation as a [`Call`](crate::Call).
+
+mod cached;
+mod fault_injector;
+mod metered;
+mod read_cache;
+mod read_only;
+mod recorder;
+mod throttled;
+#[cfg(feature = "tracing")]
+mod traced;
+mod write_back;
+
+pub use cached::Cached;
+pub use fault_injector::{Fault, FaultInjector, Rule};
+pub use metered::{Histogram, Metered, Metrics, OperationMetrics, Snapshot, LATENCY_BUCKETS};
+pub use read_cache::{ReadCache, ReadCacheStats};
+pub use read_only::ReadOnly;
+pub use recorder::{Op, Record, Recorder};
+pub use throttled::{Class, Limits, Policy, Throttled, MIN_RATE};
+#[cfg(feature = "tracing")]
+pub use traced::Traced;
+pub use write_back::WriteBack;
+
+pub(crate) use recorder::checksum;
+
+use crate::Operation;
+
+impl Operation {
+    /// Whether the operation changes the filesystem's contents or metadata.
+    /// `open` and `create` depend on their flags, and only `create` counts.
+    /// `ioctl` counts too, as there's no telling what a command does.
+    ///
+    /// What changes may be any of the call's [`paths`](crate::Call::paths),
+    /// like the destination of `rename` or `copy_file_range`.
+    pub fn is_mutating(self) -> bool {
+        #[cfg(fuse_3_4)]
+        if self == Operation::CopyFileRange {
+            return true;
+        }
+
+        matches!(
+            self,
+            Operation::Mknod
+                | Operation::Mkdir
+                | Operation::Unlink
+                | Operation::Rmdir
+                | Operation::Symlink
+                | Operation::Rename
+                | Operation::Link
+                | Operation::Chmod
+                | Operation::Chown
+                | Operation::Truncate
+                | Operation::Write
+                | Operation::WriteBuf
+                | Operation::Setxattr
+                | Operation::Removexattr
+                | Operation::Create
+                | Operation::Utimens
+                | Operation::Fallocate
+                | Operation::Ioctl
+        )
+    }
+}
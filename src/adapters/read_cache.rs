@@ -0,0 +1,451 @@
+use crate::{errno, fuse_file_info, off_t, stat, Call, Dispatch, FileSystem};
+use nix::errno::Errno;
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+};
+
+type Block = Arc<[u8]>;
+/// A file's size and modification time.
+type Version = (off_t, u64, u32);
+
+/// What a reader waiting on another's fetch of the same block gets.
+#[derive(Default)]
+struct Fetch {
+    done: Mutex<Option<Result<Block, Errno>>>,
+    ready: Condvar,
+}
+
+impl Fetch {
+    fn wait(&self) -> Result<Block, Errno> {
+        let mut done = self.done.lock().unwrap();
+        while done.is_none() {
+            done = self.ready.wait(done).unwrap();
+        }
+        done.clone().unwrap()
+    }
+
+    fn finish(&self, out: Result<Block, Errno>) {
+        *self.done.lock().unwrap() = Some(out);
+        self.ready.notify_all();
+    }
+}
+
+/// Fetches a reader has registered in [`State::fetching`], given up with
+/// `EIO` if it goes without finishing them, like when the inner filesystem
+/// panics, so others don't wait on them forever.
+struct Pending<'a> {
+    state: &'a Mutex<State>,
+    path: &'a str,
+    index: u64,
+    fetches: Vec<Arc<Fetch>>,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if self.fetches.is_empty() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        for (i, fetch) in self.fetches.drain(..).enumerate() {
+            let index = self.index + i as u64;
+            state.fetching.remove(&(self.path.to_string(), index));
+            fetch.finish(Err(Errno::EIO));
+        }
+    }
+}
+
+/// A file's cached blocks, by index, along with when each was last used.
+/// It's dropped along with its last block.
+struct File {
+    blocks: HashMap<u64, (Block, u64)>,
+    /// The size and modification time the blocks were read at, to notice
+    /// the file changing behind our back when it's next opened.
+    version: Option<Version>,
+}
+
+#[derive(Default)]
+struct State {
+    files: HashMap<String, File>,
+    /// Every cached block by when it was last used, oldest first.
+    lru: BTreeMap<u64, (String, u64)>,
+    cached: usize,
+    tick: u64,
+    fetching: HashMap<(String, u64), Arc<Fetch>>,
+    /// Where each open handle's last read ended, to spot sequential reads.
+    positions: HashMap<u64, off_t>,
+    /// The version of its file each open handle saw, which the blocks it
+    /// reads are cached under.
+    versions: HashMap<u64, Version>,
+    /// Bumped on every invalidation, so a fetch that raced with a change
+    /// doesn't cache what it read from before it.
+    generation: u64,
+    stats: ReadCacheStats,
+}
+
+impl State {
+    fn lookup(&mut self, path: &str, index: u64) -> Option<Block> {
+        self.tick += 1;
+        let (block, used) = self.files.get_mut(path)?.blocks.get_mut(&index)?;
+
+        let key = self.lru.remove(used).unwrap();
+        *used = self.tick;
+        self.lru.insert(self.tick, key);
+
+        Some(block.clone())
+    }
+
+    fn insert(
+        &mut self,
+        path: &str,
+        index: u64,
+        block: Block,
+        version: Option<Version>,
+        capacity: usize,
+    ) {
+        while self.cached >= capacity {
+            let Some((_, (path, index))) = self.lru.pop_first() else {
+                return;
+            };
+            if let Some(file) = self.files.get_mut(&path) {
+                file.blocks.remove(&index);
+                if file.blocks.is_empty() {
+                    self.files.remove(&path);
+                }
+            }
+            self.cached -= 1;
+        }
+
+        self.tick += 1;
+        let file = self.files.entry(path.to_string()).or_insert_with(|| File {
+            blocks: HashMap::new(),
+            version,
+        });
+        if let Some((_, used)) = file.blocks.insert(index, (block, self.tick)) {
+            self.lru.remove(&used);
+            self.cached -= 1;
+        }
+        self.lru.insert(self.tick, (path.to_string(), index));
+        self.cached += 1;
+    }
+
+    fn invalidate(&mut self, path: &str) {
+        self.generation += 1;
+
+        if let Some(file) = self.files.remove(path) {
+            for (_, used) in file.blocks.into_values() {
+                self.lru.remove(&used);
+                self.cached -= 1;
+            }
+        }
+    }
+}
+
+/// How well a [`ReadCache`] is doing, in blocks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReadCacheStats {
+    /// Blocks found in the cache.
+    pub hits: u64,
+    /// Blocks that had to be read.
+    pub misses: u64,
+    /// Blocks read ahead of a sequential reader, along with a miss.
+    pub prefetched: u64,
+    /// Blocks another reader was already fetching, which were waited for.
+    pub coalesced: u64,
+}
+
+/// Keeps recently read data in memory, in aligned blocks, so small reads
+/// from a slow filesystem don't each make a round trip.
+///
+/// A reader working through a file in order has the blocks after the one it
+/// needs read in the same request. Readers wanting a block that's already
+/// being read wait for it instead of reading it again.
+///
+/// Writes and other changes through the cache drop what's cached for the
+/// file. Changes made some other way are noticed when the file is next
+/// opened, if they change its size or modification time.
+pub struct ReadCache<F> {
+    inner: F,
+    block_size: usize,
+    capacity: usize,
+    readahead: usize,
+    state: Mutex<State>,
+}
+
+impl<F: FileSystem> ReadCache<F> {
+    /// Caches 64 MiB in 128 KiB blocks, reading up to 4 blocks ahead.
+    pub fn new(inner: F) -> Self {
+        Self {
+            inner,
+            block_size: 128 * 1024,
+            capacity: 512,
+            readahead: 4,
+            state: Mutex::default(),
+        }
+    }
+
+    /// How many bytes are read and cached together. The capacity is kept in
+    /// bytes, so set this first.
+    pub fn with_block_size(mut self, bytes: usize) -> Self {
+        let capacity = self.capacity * self.block_size;
+        self.block_size = bytes.max(1);
+        self.capacity = capacity / self.block_size;
+        self
+    }
+
+    /// How many bytes may be cached.
+    pub fn with_capacity(mut self, bytes: usize) -> Self {
+        self.capacity = bytes / self.block_size;
+        self
+    }
+
+    /// How many blocks to read ahead of a sequential reader.
+    pub fn with_readahead(mut self, blocks: usize) -> Self {
+        self.readahead = blocks;
+        self
+    }
+
+    pub fn stats(&self) -> ReadCacheStats {
+        self.state().stats
+    }
+
+    /// Drops what's cached for `path`.
+    pub fn invalidate(&self, path: &str) {
+        self.state().invalidate(path);
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Reads `count` blocks from `first` in one go. The blocks past the end
+    /// of the file come back empty.
+    fn fetch(
+        &self,
+        path: &str,
+        first: u64,
+        count: usize,
+        fi: &mut fuse_file_info,
+    ) -> Result<Vec<Block>, Errno> {
+        let mut data = vec![0; count * self.block_size];
+        let start = first * self.block_size as u64;
+        let mut filled = 0;
+
+        while filled < data.len() {
+            let off = (start + filled as u64) as off_t;
+            let n = self
+                .inner
+                .read(path, &mut data[filled..], off, Some(&mut *fi))
+                .map_err(|e| errno(&e))?;
+            if n == 0 {
+                break;
+            }
+            filled += n as usize;
+        }
+        data.truncate(filled);
+
+        Ok((0..count)
+            .map(|i| {
+                let from = (i * self.block_size).min(filled);
+                let to = ((i + 1) * self.block_size).min(filled);
+                Block::from(&data[from..to])
+            })
+            .collect())
+    }
+
+    fn block(
+        &self,
+        path: &str,
+        index: u64,
+        sequential: bool,
+        fi: &mut fuse_file_info,
+    ) -> Result<Block, Errno> {
+        let mut state = self.state();
+
+        if let Some(block) = state.lookup(path, index) {
+            state.stats.hits += 1;
+            return Ok(block);
+        }
+        if let Some(fetch) = state.fetching.get(&(path.to_string(), index)).cloned() {
+            state.stats.coalesced += 1;
+            drop(state);
+            return fetch.wait();
+        }
+
+        // Read ahead as far as nothing's cached or already being fetched.
+        let ahead = match sequential {
+            true => self.readahead,
+            false => 0,
+        };
+        let mut count = 1;
+        while count <= ahead {
+            let next = index + count as u64;
+            let key = (path.to_string(), next);
+            let cached = state
+                .files
+                .get(path)
+                .is_some_and(|file| file.blocks.contains_key(&next));
+
+            if cached || state.fetching.contains_key(&key) {
+                break;
+            }
+            count += 1;
+        }
+
+        let mut pending = Pending {
+            state: &self.state,
+            path,
+            index,
+            fetches: (0..count as u64)
+                .map(|i| {
+                    let fetch = Arc::new(Fetch::default());
+                    state
+                        .fetching
+                        .insert((path.to_string(), index + i), fetch.clone());
+                    fetch
+                })
+                .collect(),
+        };
+        let generation = state.generation;
+        state.stats.misses += 1;
+        state.stats.prefetched += count as u64 - 1;
+        drop(state);
+
+        let out = self.fetch(path, index, count, fi);
+
+        let mut state = self.state();
+        for (i, fetch) in mem::take(&mut pending.fetches).iter().enumerate() {
+            let index = index + i as u64;
+            state.fetching.remove(&(path.to_string(), index));
+
+            match &out {
+                Ok(blocks) => {
+                    if state.generation == generation {
+                        let version = state.versions.get(&fi.fh).copied();
+                        state.insert(path, index, blocks[i].clone(), version, self.capacity);
+                    }
+                    fetch.finish(Ok(blocks[i].clone()));
+                }
+                Err(e) => fetch.finish(Err(*e)),
+            }
+        }
+
+        out.map(|blocks| blocks[0].clone())
+    }
+
+    fn cached_read(
+        &self,
+        path: &str,
+        buf: &mut [u8],
+        off: off_t,
+        fi: &mut fuse_file_info,
+    ) -> Result<i32, Errno> {
+        let sequential = {
+            let state = self.state();
+            off == 0 || state.positions.get(&fi.fh) == Some(&off)
+        };
+
+        let block_size = self.block_size as u64;
+        let mut done = 0;
+
+        while done < buf.len() {
+            let pos = off as u64 + done as u64;
+            let within = (pos % block_size) as usize;
+            let block = self.block(path, pos / block_size, sequential, fi)?;
+
+            if within >= block.len() {
+                break;
+            }
+            let n = (block.len() - within).min(buf.len() - done);
+            buf[done..done + n].copy_from_slice(&block[within..within + n]);
+            done += n;
+
+            // A short block is the end of the file.
+            if block.len() < self.block_size {
+                break;
+            }
+        }
+
+        self.state().positions.insert(fi.fh, off + done as off_t);
+        Ok(done as i32)
+    }
+
+    /// Drops the cached blocks of a file that's been opened as `fh`, if it's
+    /// changed since they were read.
+    fn revalidate(&self, path: &str, fh: u64) {
+        let mut stat = stat::default();
+        let version = match self.inner.getattr(path, Some(&mut stat), None) {
+            Ok(_) => Some((
+                stat.st_size,
+                stat.st_mtim.tv_sec as u64,
+                stat.st_mtim.tv_nsec as u32,
+            )),
+            Err(_) => None,
+        };
+
+        let mut state = self.state();
+        let stale = state
+            .files
+            .get(path)
+            .is_some_and(|file| file.version != version);
+        if stale || version.is_none() {
+            state.invalidate(path);
+        }
+        match version {
+            Some(version) => state.versions.insert(fh, version),
+            None => state.versions.remove(&fh),
+        };
+    }
+}
+
+impl<F: FileSystem + Send + Sync> Dispatch for ReadCache<F> {
+    fn init(
+        &self,
+        conn: Option<&mut crate::fuse_conn_info>,
+        config: Option<&mut crate::fuse_config>,
+    ) {
+        self.inner.init(conn, config);
+    }
+
+    fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+        match call {
+            Call::Read(path, buf, off, Some(fi)) => Ok(self.cached_read(path, buf, off, fi)?),
+            Call::Open(path, Some(fi)) => {
+                if fi.flags & libc::O_TRUNC != 0 {
+                    self.invalidate(path);
+                }
+                let out = self.inner.open(path, Some(fi))?;
+                self.revalidate(path, fi.fh);
+                Ok(out)
+            }
+            Call::Release(path, Some(fi)) => {
+                let mut state = self.state();
+                state.positions.remove(&fi.fh);
+                state.versions.remove(&fi.fh);
+                drop(state);
+                self.inner.release(path, Some(fi))
+            }
+            call if call.operation().is_mutating() => {
+                let paths: Vec<String> = call.paths().into_iter().map(String::from).collect();
+                let out = call.forward(&self.inner);
+
+                let mut state = self.state();
+                for path in &paths {
+                    state.invalidate(path);
+                }
+                out
+            }
+            call => call.forward(&self.inner),
+        }
+    }
+}
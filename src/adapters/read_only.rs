@@ -0,0 +1,47 @@
+use crate::{Call, Dispatch, FileSystem};
+use nix::errno::Errno;
+
+/// Exposes a filesystem read-only, failing anything that would change it
+/// with `EROFS`.
+pub struct ReadOnly<F> {
+    inner: F,
+}
+
+impl<F: FileSystem> ReadOnly<F> {
+    pub fn new(inner: F) -> Self {
+        Self { inner }
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+impl<F: FileSystem + Send + Sync> Dispatch for ReadOnly<F> {
+    fn init(
+        &self,
+        conn: Option<&mut crate::fuse_conn_info>,
+        config: Option<&mut crate::fuse_config>,
+    ) {
+        self.inner.init(conn, config);
+    }
+
+    fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+        let writes = match &call {
+            Call::Open(_, Some(fi)) => {
+                fi.flags & libc::O_ACCMODE != libc::O_RDONLY || fi.flags & libc::O_TRUNC != 0
+            }
+            Call::Access(_, mask) => mask & libc::W_OK != 0,
+            call => call.operation().is_mutating(),
+        };
+
+        match writes {
+            true => Err(Errno::EROFS.into()),
+            false => call.forward(&self.inner),
+        }
+    }
+}
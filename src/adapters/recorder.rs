@@ -0,0 +1,1031 @@
+use crate::{
+    dev_t, errno, fuse_config, fuse_conn_info, fuse_file_info, gid_t, mode_t, off_t, uid_t, Call,
+    Dispatch, FileSystem,
+};
+use anyhow::{anyhow, bail};
+use std::{
+    ffi::c_uint,
+    fmt,
+    fs::File,
+    io::{self, BufRead, LineWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// An operation in a recording, with the arguments needed to make it again.
+///
+/// Open files are referred to by the handle the filesystem gave them when
+/// recorded. Reads keep a checksum of the data read rather than the data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Getattr {
+        path: String,
+    },
+    Readlink {
+        path: String,
+    },
+    Mknod {
+        path: String,
+        mode: mode_t,
+        rdev: dev_t,
+    },
+    Mkdir {
+        path: String,
+        mode: mode_t,
+    },
+    Unlink {
+        path: String,
+    },
+    Rmdir {
+        path: String,
+    },
+    Symlink {
+        target: String,
+        path: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+        flags: c_uint,
+    },
+    Link {
+        from: String,
+        to: String,
+    },
+    Chmod {
+        path: String,
+        mode: mode_t,
+    },
+    Chown {
+        path: String,
+        uid: uid_t,
+        gid: gid_t,
+    },
+    /// The access and modification times as seconds and nanoseconds, or
+    /// `None` for now.
+    Utimens {
+        path: String,
+        times: Option<[(i64, i64); 2]>,
+    },
+    Truncate {
+        path: String,
+        size: off_t,
+    },
+    Open {
+        path: String,
+        flags: i32,
+        fh: u64,
+    },
+    Create {
+        path: String,
+        mode: mode_t,
+        flags: i32,
+        fh: u64,
+    },
+    Read {
+        path: String,
+        fh: u64,
+        off: off_t,
+        size: usize,
+        checksum: u64,
+    },
+    Write {
+        path: String,
+        fh: u64,
+        off: off_t,
+        data: Vec<u8>,
+    },
+    Flush {
+        path: String,
+        fh: u64,
+    },
+    Fsync {
+        path: String,
+        fh: u64,
+    },
+    Release {
+        path: String,
+        fh: u64,
+    },
+    Fallocate {
+        path: String,
+        fh: u64,
+        mode: i32,
+        off: off_t,
+        len: off_t,
+    },
+    #[cfg(fuse_3_4)]
+    CopyFileRange {
+        path_in: String,
+        fh_in: u64,
+        off_in: off_t,
+        path_out: String,
+        fh_out: u64,
+        off_out: off_t,
+        size: usize,
+        flags: i32,
+    },
+    Setxattr {
+        path: String,
+        name: String,
+        value: Vec<u8>,
+        flags: i32,
+    },
+    Removexattr {
+        path: String,
+        name: String,
+    },
+    Opendir {
+        path: String,
+        fh: u64,
+    },
+    Readdir {
+        path: String,
+    },
+    Releasedir {
+        path: String,
+        fh: u64,
+    },
+    Fsyncdir {
+        path: String,
+        fh: u64,
+    },
+    Statfs {
+        path: String,
+    },
+    /// How much room was given for the value.
+    Getxattr {
+        path: String,
+        name: String,
+        size: usize,
+    },
+    /// How much room was given for the names.
+    Listxattr {
+        path: String,
+        size: usize,
+    },
+    Access {
+        path: String,
+        mask: i32,
+    },
+    /// The lock's type, where its start is from, its start and its length.
+    Lock {
+        path: String,
+        fh: u64,
+        cmd: i32,
+        kind: i16,
+        whence: i16,
+        start: off_t,
+        len: off_t,
+    },
+    Flock {
+        path: String,
+        fh: u64,
+        operation: i32,
+    },
+    Bmap {
+        path: String,
+        blocksize: usize,
+        index: u64,
+    },
+    /// Without the data, which only the filesystem knows the size of.
+    Ioctl {
+        path: String,
+        fh: u64,
+        cmd: u32,
+        flags: u32,
+    },
+    Poll {
+        path: String,
+        fh: u64,
+    },
+    /// Without the data, which may be in a pipe or file rather than memory.
+    WriteBuf {
+        path: String,
+        fh: u64,
+        off: off_t,
+        size: usize,
+    },
+    ReadBuf {
+        path: String,
+        fh: u64,
+        off: off_t,
+        size: usize,
+    },
+    /// An operation from a newer libfuse than this knows of, which is
+    /// recorded by name but can't be replayed.
+    Other {
+        name: String,
+    },
+}
+
+/// One operation a [`Recorder`] saw, one line of its log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// When the operation started, from when recording began.
+    pub at: Duration,
+    pub took: Duration,
+    pub op: Op,
+    /// What the operation returned, or the negated errno it failed with, as
+    /// libfuse sees it.
+    pub result: i32,
+}
+
+/// FNV-1a, to tell whether two reads returned the same data.
+pub(crate) fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Escapes bytes so they make a single word: `%` followed by two hex digits
+/// for anything unprintable, a space, `%` or `-`, with `-` alone standing
+/// for nothing.
+fn escape(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "-".to_string();
+    }
+
+    let mut escaped = String::with_capacity(data.len());
+    for &b in data {
+        match b {
+            b'!'..=b'~' if b != b'%' && b != b'-' => escaped.push(b as char),
+            b => escaped.push_str(&format!("%{b:02x}")),
+        }
+    }
+    escaped
+}
+
+fn unescape(word: &str) -> anyhow::Result<Vec<u8>> {
+    if word == "-" {
+        return Ok(vec![]);
+    }
+
+    let mut data = Vec::with_capacity(word.len());
+    let mut bytes = word.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next(), bytes.next()];
+                let hex = match hex {
+                    [Some(high), Some(low)] => [high, low],
+                    _ => bail!("Incomplete escape in {word:?}"),
+                };
+                data.push(u8::from_str_radix(std::str::from_utf8(&hex)?, 16)?);
+            }
+            b => data.push(b),
+        }
+    }
+    Ok(data)
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes data as unpadded base64, with `-` standing for nothing, so
+/// binary data takes a third more room rather than three times as much.
+fn encode(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "-".to_string();
+    }
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &b)| bits | ((b as u32) << (16 - 8 * i)));
+        for i in 0..=chunk.len() {
+            encoded.push(BASE64[((bits >> (18 - 6 * i)) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+fn decode(word: &str) -> anyhow::Result<Vec<u8>> {
+    if word == "-" {
+        return Ok(vec![]);
+    }
+
+    let mut data = Vec::with_capacity(word.len() * 3 / 4);
+    for chunk in word.as_bytes().chunks(4) {
+        if chunk.len() == 1 {
+            bail!("Truncated base64 in {word:?}");
+        }
+
+        let mut bits = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let Some(value) = BASE64.iter().position(|&b| b == c) else {
+                bail!("Invalid base64 in {word:?}");
+            };
+            bits |= (value as u32) << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            data.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    Ok(data)
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let p = |path: &str| escape(path.as_bytes());
+        write!(f, "{} {} ", self.at.as_micros(), self.took.as_micros())?;
+
+        match &self.op {
+            Op::Getattr { path } => write!(f, "getattr {}", p(path)),
+            Op::Readlink { path } => write!(f, "readlink {}", p(path)),
+            Op::Mknod { path, mode, rdev } => write!(f, "mknod {} {mode:o} {rdev}", p(path)),
+            Op::Mkdir { path, mode } => write!(f, "mkdir {} {mode:o}", p(path)),
+            Op::Unlink { path } => write!(f, "unlink {}", p(path)),
+            Op::Rmdir { path } => write!(f, "rmdir {}", p(path)),
+            Op::Symlink { target, path } => write!(f, "symlink {} {}", p(target), p(path)),
+            Op::Rename { from, to, flags } => write!(f, "rename {} {} {flags}", p(from), p(to)),
+            Op::Link { from, to } => write!(f, "link {} {}", p(from), p(to)),
+            Op::Chmod { path, mode } => write!(f, "chmod {} {mode:o}", p(path)),
+            Op::Chown { path, uid, gid } => write!(f, "chown {} {uid} {gid}", p(path)),
+            Op::Utimens { path, times } => match times {
+                Some([(asec, ansec), (msec, mnsec)]) => {
+                    write!(f, "utimens {} {asec}.{ansec} {msec}.{mnsec}", p(path))
+                }
+                None => write!(f, "utimens {} - -", p(path)),
+            },
+            Op::Truncate { path, size } => write!(f, "truncate {} {size}", p(path)),
+            Op::Open { path, flags, fh } => write!(f, "open {} {flags:o} {fh}", p(path)),
+            Op::Create {
+                path,
+                mode,
+                flags,
+                fh,
+            } => write!(f, "create {} {mode:o} {flags:o} {fh}", p(path)),
+            Op::Read {
+                path,
+                fh,
+                off,
+                size,
+                checksum,
+            } => write!(f, "read {} {fh} {off} {size} {checksum:x}", p(path)),
+            Op::Write {
+                path,
+                fh,
+                off,
+                data,
+            } => {
+                write!(f, "write {} {fh} {off} {}", p(path), encode(data))
+            }
+            Op::Flush { path, fh } => write!(f, "flush {} {fh}", p(path)),
+            Op::Fsync { path, fh } => write!(f, "fsync {} {fh}", p(path)),
+            Op::Release { path, fh } => write!(f, "release {} {fh}", p(path)),
+            Op::Fallocate {
+                path,
+                fh,
+                mode,
+                off,
+                len,
+            } => write!(f, "fallocate {} {fh} {mode} {off} {len}", p(path)),
+            #[cfg(fuse_3_4)]
+            Op::CopyFileRange {
+                path_in,
+                fh_in,
+                off_in,
+                path_out,
+                fh_out,
+                off_out,
+                size,
+                flags,
+            } => write!(
+                f,
+                "copy_file_range {} {fh_in} {off_in} {} {fh_out} {off_out} {size} {flags}",
+                p(path_in),
+                p(path_out)
+            ),
+            Op::Setxattr {
+                path,
+                name,
+                value,
+                flags,
+            } => write!(
+                f,
+                "setxattr {} {} {} {flags}",
+                p(path),
+                p(name),
+                encode(value)
+            ),
+            Op::Removexattr { path, name } => write!(f, "removexattr {} {}", p(path), p(name)),
+            Op::Opendir { path, fh } => write!(f, "opendir {} {fh}", p(path)),
+            Op::Readdir { path } => write!(f, "readdir {}", p(path)),
+            Op::Releasedir { path, fh } => write!(f, "releasedir {} {fh}", p(path)),
+            Op::Fsyncdir { path, fh } => write!(f, "fsyncdir {} {fh}", p(path)),
+            Op::Statfs { path } => write!(f, "statfs {}", p(path)),
+            Op::Getxattr { path, name, size } => {
+                write!(f, "getxattr {} {} {size}", p(path), p(name))
+            }
+            Op::Listxattr { path, size } => write!(f, "listxattr {} {size}", p(path)),
+            Op::Access { path, mask } => write!(f, "access {} {mask:o}", p(path)),
+            Op::Lock {
+                path,
+                fh,
+                cmd,
+                kind,
+                whence,
+                start,
+                len,
+            } => write!(
+                f,
+                "lock {} {fh} {cmd} {kind} {whence} {start} {len}",
+                p(path)
+            ),
+            Op::Flock {
+                path,
+                fh,
+                operation,
+            } => write!(f, "flock {} {fh} {operation}", p(path)),
+            Op::Bmap {
+                path,
+                blocksize,
+                index,
+            } => write!(f, "bmap {} {blocksize} {index}", p(path)),
+            Op::Ioctl {
+                path,
+                fh,
+                cmd,
+                flags,
+            } => write!(f, "ioctl {} {fh} {cmd} {flags}", p(path)),
+            Op::Poll { path, fh } => write!(f, "poll {} {fh}", p(path)),
+            Op::WriteBuf {
+                path,
+                fh,
+                off,
+                size,
+            } => write!(f, "write_buf {} {fh} {off} {size}", p(path)),
+            Op::ReadBuf {
+                path,
+                fh,
+                off,
+                size,
+            } => write!(f, "read_buf {} {fh} {off} {size}", p(path)),
+            Op::Other { name } => write!(f, "{name}"),
+        }?;
+
+        write!(f, " {}", self.result)
+    }
+}
+
+impl FromStr for Record {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> anyhow::Result<Self> {
+        let words: Vec<&str> = line.split(' ').collect();
+        let [at, took, name, args @ .., result] = &words[..] else {
+            bail!("Too few fields in {line:?}");
+        };
+
+        let word = |i: usize| -> anyhow::Result<&str> {
+            Ok(*args
+                .get(i)
+                .ok_or_else(|| anyhow!("Missing field in {line:?}"))?)
+        };
+        let path =
+            |i: usize| -> anyhow::Result<String> { Ok(String::from_utf8(unescape(word(i)?)?)?) };
+        let octal = |i: usize| -> anyhow::Result<u32> { Ok(u32::from_str_radix(word(i)?, 8)?) };
+        let time = |i: usize| -> anyhow::Result<(i64, i64)> {
+            let (sec, nsec) = word(i)?
+                .split_once('.')
+                .ok_or_else(|| anyhow!("Malformed time in {line:?}"))?;
+            Ok((sec.parse()?, nsec.parse()?))
+        };
+
+        let op = match *name {
+            "getattr" => Op::Getattr { path: path(0)? },
+            "readlink" => Op::Readlink { path: path(0)? },
+            "mknod" => Op::Mknod {
+                path: path(0)?,
+                mode: octal(1)?,
+                rdev: word(2)?.parse()?,
+            },
+            "mkdir" => Op::Mkdir {
+                path: path(0)?,
+                mode: octal(1)?,
+            },
+            "unlink" => Op::Unlink { path: path(0)? },
+            "rmdir" => Op::Rmdir { path: path(0)? },
+            "symlink" => Op::Symlink {
+                target: path(0)?,
+                path: path(1)?,
+            },
+            "rename" => Op::Rename {
+                from: path(0)?,
+                to: path(1)?,
+                flags: word(2)?.parse()?,
+            },
+            "link" => Op::Link {
+                from: path(0)?,
+                to: path(1)?,
+            },
+            "chmod" => Op::Chmod {
+                path: path(0)?,
+                mode: octal(1)?,
+            },
+            "chown" => Op::Chown {
+                path: path(0)?,
+                uid: word(1)?.parse()?,
+                gid: word(2)?.parse()?,
+            },
+            "utimens" => Op::Utimens {
+                path: path(0)?,
+                times: match word(1)? {
+                    "-" => None,
+                    _ => Some([time(1)?, time(2)?]),
+                },
+            },
+            "truncate" => Op::Truncate {
+                path: path(0)?,
+                size: word(1)?.parse()?,
+            },
+            "open" => Op::Open {
+                path: path(0)?,
+                flags: octal(1)? as i32,
+                fh: word(2)?.parse()?,
+            },
+            "create" => Op::Create {
+                path: path(0)?,
+                mode: octal(1)?,
+                flags: octal(2)? as i32,
+                fh: word(3)?.parse()?,
+            },
+            "read" => Op::Read {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+                off: word(2)?.parse()?,
+                size: word(3)?.parse()?,
+                checksum: u64::from_str_radix(word(4)?, 16)?,
+            },
+            "write" => Op::Write {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+                off: word(2)?.parse()?,
+                data: decode(word(3)?)?,
+            },
+            "flush" => Op::Flush {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+            },
+            "fsync" => Op::Fsync {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+            },
+            "release" => Op::Release {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+            },
+            "fallocate" => Op::Fallocate {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+                mode: word(2)?.parse()?,
+                off: word(3)?.parse()?,
+                len: word(4)?.parse()?,
+            },
+            #[cfg(fuse_3_4)]
+            "copy_file_range" => Op::CopyFileRange {
+                path_in: path(0)?,
+                fh_in: word(1)?.parse()?,
+                off_in: word(2)?.parse()?,
+                path_out: path(3)?,
+                fh_out: word(4)?.parse()?,
+                off_out: word(5)?.parse()?,
+                size: word(6)?.parse()?,
+                flags: word(7)?.parse()?,
+            },
+            "setxattr" => Op::Setxattr {
+                path: path(0)?,
+                name: path(1)?,
+                value: decode(word(2)?)?,
+                flags: word(3)?.parse()?,
+            },
+            "removexattr" => Op::Removexattr {
+                path: path(0)?,
+                name: path(1)?,
+            },
+            "opendir" => Op::Opendir {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+            },
+            "readdir" => Op::Readdir { path: path(0)? },
+            "releasedir" => Op::Releasedir {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+            },
+            "fsyncdir" => Op::Fsyncdir {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+            },
+            "statfs" => Op::Statfs { path: path(0)? },
+            "getxattr" => Op::Getxattr {
+                path: path(0)?,
+                name: path(1)?,
+                size: word(2)?.parse()?,
+            },
+            "listxattr" => Op::Listxattr {
+                path: path(0)?,
+                size: word(1)?.parse()?,
+            },
+            "access" => Op::Access {
+                path: path(0)?,
+                mask: octal(1)? as i32,
+            },
+            "lock" => Op::Lock {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+                cmd: word(2)?.parse()?,
+                kind: word(3)?.parse()?,
+                whence: word(4)?.parse()?,
+                start: word(5)?.parse()?,
+                len: word(6)?.parse()?,
+            },
+            "flock" => Op::Flock {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+                operation: word(2)?.parse()?,
+            },
+            "bmap" => Op::Bmap {
+                path: path(0)?,
+                blocksize: word(1)?.parse()?,
+                index: word(2)?.parse()?,
+            },
+            "ioctl" => Op::Ioctl {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+                cmd: word(2)?.parse()?,
+                flags: word(3)?.parse()?,
+            },
+            "poll" => Op::Poll {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+            },
+            "write_buf" => Op::WriteBuf {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+                off: word(2)?.parse()?,
+                size: word(3)?.parse()?,
+            },
+            "read_buf" => Op::ReadBuf {
+                path: path(0)?,
+                fh: word(1)?.parse()?,
+                off: word(2)?.parse()?,
+                size: word(3)?.parse()?,
+            },
+            name => Op::Other {
+                name: name.to_string(),
+            },
+        };
+
+        Ok(Record {
+            at: Duration::from_micros(at.parse()?),
+            took: Duration::from_micros(took.parse()?),
+            op,
+            result: result.parse()?,
+        })
+    }
+}
+
+impl Record {
+    /// Reads every record in a log a [`Recorder`] wrote.
+    pub fn read_all(log: impl BufRead) -> anyhow::Result<Vec<Record>> {
+        log.lines()
+            .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+            .map(|line| line?.parse())
+            .collect()
+    }
+}
+
+/// Logs every operation on a filesystem, with its arguments, result and
+/// timing, one line each, so a workload can be replayed against a
+/// filesystem later with [`replay`](crate::testing::replay::replay).
+///
+/// Operations are logged as they finish, so ones made at the same time may
+/// appear in a different order from the one they started in. Recording
+/// stops at the first operation that can't be logged, so the log is always
+/// a complete prefix of the workload, and the error is kept for
+/// [`Recorder::error`].
+pub struct Recorder<F> {
+    inner: F,
+    log: Mutex<Log>,
+    start: Instant,
+}
+
+struct Log {
+    out: Box<dyn Write + Send>,
+    error: Option<io::Error>,
+}
+
+impl<F: FileSystem> Recorder<F> {
+    /// Records into `log`.
+    pub fn new(inner: F, log: impl Write + Send + 'static) -> Self {
+        Self {
+            inner,
+            log: Mutex::new(Log {
+                out: Box::new(log),
+                error: None,
+            }),
+            start: Instant::now(),
+        }
+    }
+
+    /// Records into a new file at `path`, a line at a time, so the log is
+    /// complete up to the last operation however the filesystem stops.
+    pub fn create(inner: F, path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(inner, LineWriter::new(File::create(path)?)))
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    /// The error that stopped recording, if anything has.
+    pub fn error(&self) -> Option<io::Error> {
+        let log = self.log.lock().unwrap();
+        let e = log.error.as_ref()?;
+        Some(io::Error::new(e.kind(), e.to_string()))
+    }
+
+    fn record(&self, record: Record) {
+        let mut log = self.log.lock().unwrap();
+        if log.error.is_some() {
+            return;
+        }
+        if let Err(e) = writeln!(log.out, "{record}") {
+            log.error = Some(e);
+        }
+    }
+}
+
+/// What's recorded of a call, before it's made. What the filesystem hands
+/// back, like handles and the data read, is filled in after.
+fn op(call: &Call<'_>) -> Op {
+    let fh = |fi: &Option<&mut fuse_file_info>| fi.as_ref().map_or(0, |fi| fi.fh);
+    let flags = |fi: &Option<&mut fuse_file_info>| fi.as_ref().map_or(0, |fi| fi.flags);
+
+    match call {
+        Call::Getattr(path, ..) => Op::Getattr {
+            path: path.to_string(),
+        },
+        Call::Readlink(path, _) => Op::Readlink {
+            path: path.to_string(),
+        },
+        Call::Mknod(path, mode, rdev) => Op::Mknod {
+            path: path.to_string(),
+            mode: *mode,
+            rdev: *rdev,
+        },
+        Call::Mkdir(path, mode) => Op::Mkdir {
+            path: path.to_string(),
+            mode: *mode,
+        },
+        Call::Unlink(path) => Op::Unlink {
+            path: path.to_string(),
+        },
+        Call::Rmdir(path) => Op::Rmdir {
+            path: path.to_string(),
+        },
+        Call::Symlink(target, path) => Op::Symlink {
+            target: target.to_string(),
+            path: path.to_string(),
+        },
+        Call::Rename(from, to, flags) => Op::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+            flags: *flags,
+        },
+        Call::Link(from, to) => Op::Link {
+            from: from.to_string(),
+            to: to.to_string(),
+        },
+        Call::Chmod(path, mode, _) => Op::Chmod {
+            path: path.to_string(),
+            mode: *mode,
+        },
+        Call::Chown(path, uid, gid, _) => Op::Chown {
+            path: path.to_string(),
+            uid: *uid,
+            gid: *gid,
+        },
+        Call::Utimens(path, tv, _) => Op::Utimens {
+            path: path.to_string(),
+            times: tv.map(|times| times.map(|time| (time.tv_sec as _, time.tv_nsec as _))),
+        },
+        Call::Truncate(path, size, _) => Op::Truncate {
+            path: path.to_string(),
+            size: *size,
+        },
+        Call::Open(path, fi) => Op::Open {
+            path: path.to_string(),
+            flags: flags(fi),
+            fh: 0,
+        },
+        Call::Create(path, mode, fi) => Op::Create {
+            path: path.to_string(),
+            mode: *mode,
+            flags: flags(fi),
+            fh: 0,
+        },
+        Call::Read(path, buf, off, fi) => Op::Read {
+            path: path.to_string(),
+            fh: fh(fi),
+            off: *off,
+            size: buf.len(),
+            checksum: 0,
+        },
+        Call::Write(path, buf, off, fi) => Op::Write {
+            path: path.to_string(),
+            fh: fh(fi),
+            off: *off,
+            data: buf.to_vec(),
+        },
+        Call::Flush(path, fi) => Op::Flush {
+            path: path.to_string(),
+            fh: fh(fi),
+        },
+        Call::Fsync(path, _, fi) => Op::Fsync {
+            path: path.to_string(),
+            fh: fh(fi),
+        },
+        Call::Release(path, fi) => Op::Release {
+            path: path.to_string(),
+            fh: fh(fi),
+        },
+        Call::Fallocate(path, mode, off, len, fi) => Op::Fallocate {
+            path: path.to_string(),
+            fh: fh(fi),
+            mode: *mode,
+            off: *off,
+            len: *len,
+        },
+        #[cfg(fuse_3_4)]
+        Call::CopyFileRange(path_in, fi_in, off_in, path_out, fi_out, off_out, size, flags) => {
+            Op::CopyFileRange {
+                path_in: path_in.to_string(),
+                fh_in: fh(fi_in),
+                off_in: *off_in,
+                path_out: path_out.to_string(),
+                fh_out: fh(fi_out),
+                off_out: *off_out,
+                size: *size,
+                flags: *flags,
+            }
+        }
+        Call::Setxattr(path, name, value, flags) => Op::Setxattr {
+            path: path.to_string(),
+            name: name.to_string(),
+            value: value.to_vec(),
+            flags: *flags,
+        },
+        Call::Removexattr(path, name) => Op::Removexattr {
+            path: path.to_string(),
+            name: name.to_string(),
+        },
+        Call::Opendir(path, _) => Op::Opendir {
+            path: path.to_string(),
+            fh: 0,
+        },
+        Call::Readdir(path, ..) => Op::Readdir {
+            path: path.to_string(),
+        },
+        Call::Releasedir(path, fi) => Op::Releasedir {
+            path: path.to_string(),
+            fh: fh(fi),
+        },
+        Call::Fsyncdir(path, _, fi) => Op::Fsyncdir {
+            path: path.to_string(),
+            fh: fh(fi),
+        },
+        Call::Statfs(path, _) => Op::Statfs {
+            path: path.to_string(),
+        },
+        Call::Getxattr(path, name, value) => Op::Getxattr {
+            path: path.to_string(),
+            name: name.to_string(),
+            size: value.len(),
+        },
+        Call::Listxattr(path, list) => Op::Listxattr {
+            path: path.to_string(),
+            size: list.len(),
+        },
+        Call::Access(path, mask) => Op::Access {
+            path: path.to_string(),
+            mask: *mask,
+        },
+        Call::Lock(path, fi, cmd, lock) => {
+            let lock = lock.as_deref().copied().unwrap_or_default();
+            Op::Lock {
+                path: path.to_string(),
+                fh: fh(fi),
+                cmd: *cmd,
+                kind: lock.l_type as _,
+                whence: lock.l_whence as _,
+                start: lock.l_start,
+                len: lock.l_len,
+            }
+        }
+        Call::Flock(path, fi, operation) => Op::Flock {
+            path: path.to_string(),
+            fh: fh(fi),
+            operation: *operation,
+        },
+        Call::Bmap(path, blocksize, index) => Op::Bmap {
+            path: path.to_string(),
+            blocksize: *blocksize,
+            index: index.as_deref().copied().unwrap_or(0),
+        },
+        Call::Ioctl(path, cmd, _, fi, flags, _) => Op::Ioctl {
+            path: path.to_string(),
+            fh: fh(fi),
+            cmd: *cmd as _,
+            flags: *flags,
+        },
+        Call::Poll(path, fi, ..) => Op::Poll {
+            path: path.to_string(),
+            fh: fh(fi),
+        },
+        Call::WriteBuf(path, buf, off, fi) => Op::WriteBuf {
+            path: path.to_string(),
+            fh: fh(fi),
+            off: *off,
+            size: buf
+                .as_deref()
+                .map_or(0, |buf| unsafe { crate::fuse_buf_size(buf) }),
+        },
+        Call::ReadBuf(path, _, size, off, fi) => Op::ReadBuf {
+            path: path.to_string(),
+            fh: fh(fi),
+            off: *off,
+            size: *size,
+        },
+        // Operations that only newer versions of libfuse have.
+        #[allow(unreachable_patterns)]
+        call => Op::Other {
+            name: call.operation().name().to_string(),
+        },
+    }
+}
+
+impl<F: FileSystem + Send + Sync> Dispatch for Recorder<F> {
+    fn init(&self, conn: Option<&mut fuse_conn_info>, config: Option<&mut fuse_config>) {
+        self.inner.init(conn, config);
+    }
+
+    fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+        let mut op = op(&call);
+        let started = Instant::now();
+
+        // Reads and opens are made here, to see what they hand back.
+        let out = match call {
+            Call::Read(path, buf, off, fi) => {
+                let out = self.inner.read(path, buf, off, fi);
+                if let (Op::Read { checksum: sum, .. }, Ok(n)) = (&mut op, &out) {
+                    *sum = checksum(&buf[..(*n).max(0) as usize]);
+                }
+                out
+            }
+            Call::Open(path, mut fi) => {
+                let out = self.inner.open(path, fi.as_deref_mut());
+                if let (Op::Open { fh, .. }, Some(fi)) = (&mut op, fi) {
+                    *fh = fi.fh;
+                }
+                out
+            }
+            Call::Create(path, mode, mut fi) => {
+                let out = self.inner.create(path, mode, fi.as_deref_mut());
+                if let (Op::Create { fh, .. }, Some(fi)) = (&mut op, fi) {
+                    *fh = fi.fh;
+                }
+                out
+            }
+            Call::Opendir(path, mut fi) => {
+                let out = self.inner.opendir(path, fi.as_deref_mut());
+                if let (Op::Opendir { fh, .. }, Some(fi)) = (&mut op, fi) {
+                    *fh = fi.fh;
+                }
+                out
+            }
+            call => call.forward(&self.inner),
+        };
+
+        let result = match &out {
+            Ok(n) => *n,
+            Err(e) => -(errno(e) as i32),
+        };
+        self.record(Record {
+            at: started - self.start,
+            took: started.elapsed(),
+            op,
+            result,
+        });
+
+        out
+    }
+}
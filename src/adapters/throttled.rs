@@ -0,0 +1,313 @@
+use crate::{fuse_config, fuse_conn_info, uid_t, Call, Dispatch, FileSystem, Operation};
+use nix::errno::Errno;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// A group of operations limited together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Class {
+    /// `read`.
+    Read,
+    /// Everything that changes the filesystem, see
+    /// [`Operation::is_mutating`].
+    Write,
+    /// Everything else, like `getattr`, `open` and `readdir`.
+    Metadata,
+    /// Every operation.
+    All,
+}
+
+impl Class {
+    fn of(operation: Operation) -> Self {
+        match operation {
+            Operation::Read => Class::Read,
+            operation if operation.is_mutating() => Class::Write,
+            _ => Class::Metadata,
+        }
+    }
+}
+
+/// What a [`Throttled`] filesystem does with a call over its limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Policy {
+    /// Waits until the call is within them.
+    #[default]
+    Block,
+    /// Fails the call with `EAGAIN`.
+    Fail,
+}
+
+/// Rates, per second, that calls are kept to. Each allows a second's worth
+/// in a burst.
+///
+/// An infinite rate is the same as leaving the limit unset. Anything else
+/// below [`MIN_RATE`], including zero, negative rates and NaN, is raised to
+/// it, since a bucket that never refills would hold calls up forever.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    ops: HashMap<Class, f64>,
+    read_bytes: Option<f64>,
+    write_bytes: Option<f64>,
+}
+
+impl Limits {
+    /// No limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits how many operations in `class` are made per second.
+    pub fn ops(mut self, class: Class, per_second: f64) -> Self {
+        match clamped(per_second) {
+            Some(rate) => self.ops.insert(class, rate),
+            None => self.ops.remove(&class),
+        };
+        self
+    }
+
+    /// Limits how many bytes are read per second, counting what's asked for.
+    pub fn read_bytes(mut self, per_second: f64) -> Self {
+        self.read_bytes = clamped(per_second);
+        self
+    }
+
+    /// Limits how many bytes are written per second.
+    pub fn write_bytes(mut self, per_second: f64) -> Self {
+        self.write_bytes = clamped(per_second);
+        self
+    }
+
+    fn rate(&self, kind: Kind) -> Option<f64> {
+        match kind {
+            Kind::Ops(class) => self.ops.get(&class).copied(),
+            Kind::ReadBytes => self.read_bytes,
+            Kind::WriteBytes => self.write_bytes,
+        }
+    }
+}
+
+/// The slowest rate [`Limits`] keep to, one every thousand seconds.
+pub const MIN_RATE: f64 = 1e-3;
+
+/// A rate buckets can divide by to know how long to wait, or none for no
+/// limit.
+fn clamped(per_second: f64) -> Option<f64> {
+    if per_second == f64::INFINITY {
+        return None;
+    }
+
+    // `max` ignores NaN, so that's raised too.
+    Some(per_second.max(MIN_RATE))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Kind {
+    Ops(Class),
+    ReadBytes,
+    WriteBytes,
+}
+
+/// Whose calls a bucket counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Scope {
+    Total,
+    Uid(uid_t),
+}
+
+struct Bucket {
+    rate: f64,
+    /// Goes below zero when calls have been let through ahead of time, to
+    /// wait once they're made.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.max(1.0),
+            updated: now,
+        }
+    }
+
+    /// A second's worth, or enough for one operation at slower rates.
+    fn capacity(&self) -> f64 {
+        self.rate.max(1.0)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity());
+        self.updated = now;
+    }
+
+    /// How long until `cost` could be taken. Anything costing more than the
+    /// bucket holds only waits for it to be full.
+    fn wait(&self, cost: f64) -> Duration {
+        let needed = cost.min(self.capacity());
+        match self.tokens >= needed {
+            true => Duration::ZERO,
+            false => Duration::from_secs_f64((needed - self.tokens) / self.rate),
+        }
+    }
+}
+
+/// The user making the current call.
+fn caller() -> uid_t {
+    // There's no libfuse context to ask when driven by the mock.
+    let context = match crate::mocked() {
+        true => None,
+        false => unsafe { crate::fuse_get_context().as_ref() },
+    };
+
+    match context {
+        Some(context) => context.uid,
+        None => unsafe { libc::geteuid() },
+    }
+}
+
+/// Keeps calls to a filesystem within rates of operations and bytes per
+/// second, with token buckets, for filesystems in front of backends that
+/// mustn't be overloaded.
+///
+/// Limits apply to each user separately, unless overridden for a user, and
+/// can also apply to everyone together. Calls over them wait or fail with
+/// `EAGAIN`, depending on the [`Policy`].
+///
+/// ```no_run
+/// use fuse_sys::{
+///     adapters::{Class, Limits, Throttled},
+///     passthrough::Passthrough,
+///     prelude::*,
+/// };
+///
+/// Throttled::new(Passthrough::new("/srv/shared"))
+///     .with_limits(Limits::new().ops(Class::All, 100.0))
+///     .with_total_limits(Limits::new().read_bytes(50e6).write_bytes(10e6))
+///     .run(&["throttled", "/mnt"])
+///     .unwrap();
+/// ```
+pub struct Throttled<F> {
+    inner: F,
+    limits: Limits,
+    uid_limits: HashMap<uid_t, Limits>,
+    total_limits: Limits,
+    policy: Policy,
+    buckets: Mutex<HashMap<(Scope, Kind), Bucket>>,
+}
+
+impl<F: FileSystem> Throttled<F> {
+    /// Doesn't limit anything until limits are set.
+    pub fn new(inner: F) -> Self {
+        Self {
+            inner,
+            limits: Limits::new(),
+            uid_limits: HashMap::new(),
+            total_limits: Limits::new(),
+            policy: Policy::default(),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Limits each user's calls.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Limits `uid`'s calls differently from other users'.
+    pub fn with_uid_limits(mut self, uid: uid_t, limits: Limits) -> Self {
+        self.uid_limits.insert(uid, limits);
+        self
+    }
+
+    /// Limits everyone's calls together.
+    pub fn with_total_limits(mut self, limits: Limits) -> Self {
+        self.total_limits = limits;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    /// Takes what a call costs from every bucket it counts against, failing
+    /// or waiting if they don't have enough.
+    fn admit(&self, call: &Call<'_>) -> Result<(), Errno> {
+        let class = Class::of(call.operation());
+        let mut costs = vec![(Kind::Ops(class), 1.0), (Kind::Ops(Class::All), 1.0)];
+        match call {
+            Call::Read(_, buf, ..) => costs.push((Kind::ReadBytes, buf.len() as f64)),
+            Call::Write(_, buf, ..) => costs.push((Kind::WriteBytes, buf.len() as f64)),
+            _ => {}
+        }
+
+        let uid = caller();
+        let scopes = [
+            (
+                Scope::Uid(uid),
+                self.uid_limits.get(&uid).unwrap_or(&self.limits),
+            ),
+            (Scope::Total, &self.total_limits),
+        ];
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut wait = Duration::ZERO;
+        let mut charged = vec![];
+
+        for (scope, limits) in scopes {
+            for &(kind, cost) in &costs {
+                let Some(rate) = limits.rate(kind) else {
+                    continue;
+                };
+                let bucket = buckets
+                    .entry((scope, kind))
+                    .or_insert_with(|| Bucket::new(rate, now));
+
+                bucket.refill(now);
+                wait = wait.max(bucket.wait(cost));
+                charged.push(((scope, kind), cost));
+            }
+        }
+
+        if wait > Duration::ZERO && self.policy == Policy::Fail {
+            return Err(Errno::EAGAIN);
+        }
+
+        // Taking the tokens now holds the call's place, so calls that come
+        // later wait behind it.
+        for (key, cost) in charged {
+            buckets.get_mut(&key).unwrap().tokens -= cost;
+        }
+        drop(buckets);
+
+        thread::sleep(wait);
+        Ok(())
+    }
+}
+
+impl<F: FileSystem + Send + Sync> Dispatch for Throttled<F> {
+    fn init(&self, conn: Option<&mut fuse_conn_info>, config: Option<&mut fuse_config>) {
+        self.inner.init(conn, config);
+    }
+
+    fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+        self.admit(&call)?;
+        call.forward(&self.inner)
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{errno, Call, Dispatch, FileSystem};
+use std::time::Instant;
+use tracing::{debug, debug_span, field, trace};
+
+/// Logs every operation on a filesystem through `tracing`.
+///
+/// Each operation runs in a `fuse` span recording the operation and path,
+/// plus the destination for `rename` and `link`. The result or errno and
+/// the latency are logged at debug level when it finishes, and the full
+/// arguments at trace level when it starts.
+pub struct Traced<F> {
+    inner: F,
+}
+
+impl<F: FileSystem> Traced<F> {
+    pub fn new(inner: F) -> Self {
+        Self { inner }
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+impl<F: FileSystem + Send + Sync> Dispatch for Traced<F> {
+    fn init(
+        &self,
+        conn: Option<&mut crate::fuse_conn_info>,
+        config: Option<&mut crate::fuse_config>,
+    ) {
+        let _span = debug_span!("fuse", op = "init").entered();
+        self.inner.init(conn, config);
+    }
+
+    fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+        let paths = call.paths();
+        let span = debug_span!(
+            "fuse",
+            op = call.operation().name(),
+            path = paths.first().copied().unwrap_or_default(),
+            to = field::Empty,
+        );
+        if let [_, to] = paths[..] {
+            span.record("to", to);
+        }
+        let _span = span.entered();
+
+        trace!(args = ?call, "started");
+        let start = Instant::now();
+        let out = call.forward(&self.inner);
+        let latency = start.elapsed();
+
+        match &out {
+            Ok(out) => debug!(result = out, ?latency, "finished"),
+            Err(e) => debug!(errno = %errno(e), error = %e, ?latency, "failed"),
+        }
+
+        out
+    }
+}
@@ -0,0 +1,363 @@
+use crate::{errno, fuse_file_info, off_t, Call, Dispatch, FileSystem};
+use nix::errno::Errno;
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// An open file's writes that haven't been passed on yet.
+struct Handle {
+    path: String,
+    /// What the inner filesystem gave the open, to write through.
+    fi: fuse_file_info,
+    /// Opens sharing the handle, for filesystems that don't give each open
+    /// a handle of its own.
+    opens: usize,
+    /// Dirty data by where it starts, merged so no two ranges overlap or
+    /// touch.
+    ranges: BTreeMap<u64, Vec<u8>>,
+    bytes: usize,
+}
+
+impl Handle {
+    fn new(path: &str, fi: fuse_file_info) -> Self {
+        Self {
+            path: path.to_string(),
+            fi,
+            opens: 0,
+            ranges: BTreeMap::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Adds dirty data, extending the range it starts in or just after in
+    /// place, so sequential writes don't copy what's already held.
+    fn add(&mut self, off: u64, data: &[u8]) {
+        let end = off + data.len() as u64;
+        let start = match self.ranges.range(..=off).next_back() {
+            Some((&from, range)) if from + range.len() as u64 >= off => from,
+            _ => off,
+        };
+
+        let mut merged = self.ranges.remove(&start).unwrap_or_default();
+        self.bytes -= merged.len();
+        let at = (off - start) as usize;
+        if merged.len() < at + data.len() {
+            merged.resize(at + data.len(), 0);
+        }
+        merged[at..at + data.len()].copy_from_slice(data);
+
+        // Ranges the new data reaches are absorbed, keeping whatever of them
+        // lies past it.
+        let reached: Vec<u64> = self
+            .ranges
+            .range(start..=end)
+            .map(|(from, _)| *from)
+            .collect();
+        for from in reached {
+            let range = self.ranges.remove(&from).unwrap();
+            self.bytes -= range.len();
+            let merged_end = start + merged.len() as u64;
+            if from + range.len() as u64 > merged_end {
+                merged.extend_from_slice(&range[(merged_end - from) as usize..]);
+            }
+        }
+
+        self.bytes += merged.len();
+        self.ranges.insert(start, merged);
+    }
+
+    /// Where the dirty data ends, if there is any.
+    fn end(&self) -> Option<u64> {
+        let (from, range) = self.ranges.last_key_value()?;
+        Some(from + range.len() as u64)
+    }
+
+    /// Copies the dirty data over `buf`, which was read at `off` and filled
+    /// to `n` bytes, returning how many bytes the read now has.
+    fn overlay(&self, buf: &mut [u8], off: u64, n: usize) -> usize {
+        let n = match self.end() {
+            Some(end) if end > off + n as u64 => {
+                let n_now = ((end - off) as usize).min(buf.len());
+                // Anything between the end of the file and the dirty data
+                // is a hole.
+                buf[n..n_now].fill(0);
+                n_now
+            }
+            _ => n,
+        };
+        let read_end = off + n as u64;
+
+        for (&from, range) in self.ranges.range(..read_end) {
+            let to = from + range.len() as u64;
+            if to <= off {
+                continue;
+            }
+
+            let (start, stop) = (from.max(off), to.min(read_end));
+            buf[(start - off) as usize..(stop - off) as usize]
+                .copy_from_slice(&range[(start - from) as usize..(stop - from) as usize]);
+        }
+
+        n
+    }
+}
+
+/// Holds writes back and passes them on in as few, large writes as it can,
+/// for filesystems where each write is costly, like object stores.
+///
+/// Writes are kept for each open file until it's flushed, which happens on
+/// `flush` (so on every `close`), `fsync` and `release`, before anything
+/// else that changes the file, and whenever the file has more dirty data
+/// than the threshold. If passing the writes on fails, the error goes to the
+/// `close` or `fsync` that caused the flush, and the writes are kept to try
+/// again, until the file's released. A `write` that goes over the threshold
+/// has been taken either way, so it succeeds, and a failure is reported by
+/// the next `close` or `fsync`.
+///
+/// Reads through the same open file see its writes. Other open files, and
+/// other processes, see them once they're flushed.
+pub struct WriteBack<F> {
+    inner: F,
+    threshold: usize,
+    handles: Mutex<HashMap<u64, Arc<Mutex<Handle>>>>,
+}
+
+impl<F: FileSystem> WriteBack<F> {
+    /// Holds up to 8 MiB per open file.
+    pub fn new(inner: F) -> Self {
+        Self {
+            inner,
+            threshold: 8 * 1024 * 1024,
+            handles: Mutex::default(),
+        }
+    }
+
+    /// How many dirty bytes an open file may have before it's flushed.
+    pub fn with_threshold(mut self, bytes: usize) -> Self {
+        self.threshold = bytes;
+        self
+    }
+
+    /// How many bytes are waiting to be written, over every open file.
+    pub fn dirty(&self) -> usize {
+        self.all()
+            .iter()
+            .map(|handle| handle.lock().unwrap().bytes)
+            .sum()
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    fn handles(&self) -> MutexGuard<'_, HashMap<u64, Arc<Mutex<Handle>>>> {
+        self.handles.lock().unwrap()
+    }
+
+    fn handle(&self, fi: &fuse_file_info) -> Option<Arc<Mutex<Handle>>> {
+        self.handles().get(&fi.fh).cloned()
+    }
+
+    /// Every handle, taken out of the map so they can be locked without
+    /// holding it.
+    fn all(&self) -> Vec<Arc<Mutex<Handle>>> {
+        self.handles().values().cloned().collect()
+    }
+
+    fn opened(&self, path: &str, fi: &fuse_file_info) {
+        let mut handles = self.handles();
+        let handle = handles
+            .entry(fi.fh)
+            .or_insert_with(|| Arc::new(Mutex::new(Handle::new(path, *fi))));
+        handle.lock().unwrap().opens += 1;
+    }
+
+    /// Passes on a handle's dirty data. Whatever can't be written is kept.
+    fn flush_handle(&self, handle: &mut Handle) -> Result<(), Errno> {
+        let mut fi = handle.fi;
+        let mut ranges = mem::take(&mut handle.ranges).into_iter();
+        handle.bytes = 0;
+
+        while let Some((from, range)) = ranges.next() {
+            let mut written = 0;
+
+            while written < range.len() {
+                let off = (from + written as u64) as off_t;
+                let out = self
+                    .inner
+                    .write(&handle.path, &range[written..], off, Some(&mut fi))
+                    .map_err(|e| errno(&e));
+
+                match out {
+                    Ok(n) if n > 0 => written += n as usize,
+                    // A write that takes nothing would be retried forever.
+                    out => {
+                        handle.add(from + written as u64, &range[written..]);
+                        for (from, range) in ranges {
+                            handle.add(from, &range);
+                        }
+                        return Err(out.err().unwrap_or(Errno::EIO));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self, fi: &fuse_file_info) -> Result<(), Errno> {
+        match self.handle(fi) {
+            Some(handle) => self.flush_handle(&mut handle.lock().unwrap()),
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes every open file at or under `path`, before something else
+    /// changes it.
+    fn flush_path(&self, path: &str) -> Result<(), Errno> {
+        let below = format!("{}/", path.trim_end_matches('/'));
+
+        for handle in self.all() {
+            let mut handle = handle.lock().unwrap();
+            if handle.path == path || handle.path.starts_with(&below) {
+                self.flush_handle(&mut handle)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, path: &str, buf: &[u8], off: off_t, fi: &mut fuse_file_info) -> i32 {
+        let handle = match self.handle(fi) {
+            Some(handle) => handle,
+            None => {
+                self.opened(path, fi);
+                self.handle(fi).unwrap()
+            }
+        };
+        let mut handle = handle.lock().unwrap();
+
+        handle.add(off as u64, buf);
+        if handle.bytes >= self.threshold {
+            // What couldn't be written stays buffered for the next flush to
+            // try again and report.
+            let _ = self.flush_handle(&mut handle);
+        }
+
+        buf.len() as i32
+    }
+}
+
+impl<F: FileSystem + Send + Sync> Dispatch for WriteBack<F> {
+    fn init(
+        &self,
+        conn: Option<&mut crate::fuse_conn_info>,
+        config: Option<&mut crate::fuse_config>,
+    ) {
+        self.inner.init(conn, config);
+    }
+
+    fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+        match call {
+            Call::Open(path, Some(fi)) => {
+                let out = self.inner.open(path, Some(&mut *fi))?;
+                self.opened(path, fi);
+                Ok(out)
+            }
+            Call::Create(path, mode, Some(fi)) => {
+                let out = self.inner.create(path, mode, Some(&mut *fi))?;
+                self.opened(path, fi);
+                Ok(out)
+            }
+            Call::Write(path, buf, off, Some(fi)) => Ok(self.write(path, buf, off, fi)),
+            Call::Read(path, buf, off, Some(fi)) => {
+                let n = self.inner.read(path, buf, off, Some(&mut *fi))?;
+                match self.handle(fi) {
+                    Some(handle) => {
+                        let n = handle.lock().unwrap().overlay(buf, off as u64, n as usize);
+                        Ok(n as i32)
+                    }
+                    None => Ok(n),
+                }
+            }
+            Call::Flush(path, Some(fi)) => {
+                self.flush(fi)?;
+                self.inner.flush(path, Some(fi))
+            }
+            Call::Fsync(path, datasync, Some(fi)) => {
+                self.flush(fi)?;
+                self.inner.fsync(path, datasync, Some(fi))
+            }
+            Call::Release(path, Some(fi)) => {
+                // `close` has returned by now, so what can't be written is
+                // lost once the last open goes, but release still fails.
+                let flushed = self.flush(fi);
+
+                let mut handles = self.handles();
+                if let Some(handle) = handles.get(&fi.fh).cloned() {
+                    let mut handle = handle.lock().unwrap();
+                    handle.opens = handle.opens.saturating_sub(1);
+                    if handle.opens == 0 {
+                        handles.remove(&fi.fh);
+                    }
+                }
+                drop(handles);
+
+                let out = self.inner.release(path, Some(fi))?;
+                flushed?;
+                Ok(out)
+            }
+            // Open files may reach past where the inner filesystem thinks
+            // they end.
+            Call::Getattr(path, Some(stat), fi) => {
+                let by_handle = fi.as_ref().and_then(|fi| self.handle(fi));
+                let out = self.inner.getattr(path, Some(&mut *stat), fi)?;
+
+                let handles = match by_handle {
+                    Some(handle) => vec![handle],
+                    None => self
+                        .all()
+                        .into_iter()
+                        .filter(|handle| handle.lock().unwrap().path == path)
+                        .collect(),
+                };
+                for handle in handles {
+                    if let Some(end) = handle.lock().unwrap().end() {
+                        stat.st_size = stat.st_size.max(end as off_t);
+                    }
+                }
+                Ok(out)
+            }
+            call if call.operation().is_mutating() => {
+                let paths: Vec<String> = call.paths().into_iter().map(String::from).collect();
+                for path in &paths {
+                    self.flush_path(path)?;
+                }
+
+                let renamed = matches!(call, Call::Rename(..));
+                let out = call.forward(&self.inner)?;
+
+                // Open files keep writing to where they've been moved.
+                if let (true, [from, to]) = (renamed, &paths[..]) {
+                    let below = format!("{}/", from.trim_end_matches('/'));
+                    for handle in self.all() {
+                        let mut handle = handle.lock().unwrap();
+                        if handle.path == *from {
+                            handle.path = to.clone();
+                        } else if let Some(rest) = handle.path.strip_prefix(&below) {
+                            handle.path = format!("{}/{rest}", to.trim_end_matches('/'));
+                        }
+                    }
+                }
+                Ok(out)
+            }
+            call => call.forward(&self.inner),
+        }
+    }
+}
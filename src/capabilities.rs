@@ -0,0 +1,118 @@
+//! Safe access to the `FUSE_CAP_*` flags negotiated with the kernel in `init`.
+//!
+//! Flags that only exist in newer versions of libfuse are gated on the
+//! `fuse_3_N` cfgs emitted by the build script.
+
+use crate::fuse_conn_info;
+use std::ops::{BitOr, BitOrAssign};
+
+/// A set of `FUSE_CAP_*` flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const ASYNC_READ: Self = Self(crate::FUSE_CAP_ASYNC_READ);
+    pub const POSIX_LOCKS: Self = Self(crate::FUSE_CAP_POSIX_LOCKS);
+    pub const ATOMIC_O_TRUNC: Self = Self(crate::FUSE_CAP_ATOMIC_O_TRUNC);
+    pub const EXPORT_SUPPORT: Self = Self(crate::FUSE_CAP_EXPORT_SUPPORT);
+    pub const DONT_MASK: Self = Self(crate::FUSE_CAP_DONT_MASK);
+    pub const SPLICE_WRITE: Self = Self(crate::FUSE_CAP_SPLICE_WRITE);
+    pub const SPLICE_MOVE: Self = Self(crate::FUSE_CAP_SPLICE_MOVE);
+    pub const SPLICE_READ: Self = Self(crate::FUSE_CAP_SPLICE_READ);
+    pub const FLOCK_LOCKS: Self = Self(crate::FUSE_CAP_FLOCK_LOCKS);
+    pub const IOCTL_DIR: Self = Self(crate::FUSE_CAP_IOCTL_DIR);
+
+    #[cfg(not(feature = "fuse2"))]
+    pub const AUTO_INVAL_DATA: Self = Self(crate::FUSE_CAP_AUTO_INVAL_DATA);
+    #[cfg(not(feature = "fuse2"))]
+    pub const READDIRPLUS: Self = Self(crate::FUSE_CAP_READDIRPLUS);
+    #[cfg(not(feature = "fuse2"))]
+    pub const READDIRPLUS_AUTO: Self = Self(crate::FUSE_CAP_READDIRPLUS_AUTO);
+    #[cfg(not(feature = "fuse2"))]
+    pub const ASYNC_DIO: Self = Self(crate::FUSE_CAP_ASYNC_DIO);
+    #[cfg(not(feature = "fuse2"))]
+    pub const WRITEBACK_CACHE: Self = Self(crate::FUSE_CAP_WRITEBACK_CACHE);
+    #[cfg(not(feature = "fuse2"))]
+    pub const NO_OPEN_SUPPORT: Self = Self(crate::FUSE_CAP_NO_OPEN_SUPPORT);
+    #[cfg(not(feature = "fuse2"))]
+    pub const PARALLEL_DIROPS: Self = Self(crate::FUSE_CAP_PARALLEL_DIROPS);
+    #[cfg(not(feature = "fuse2"))]
+    pub const POSIX_ACL: Self = Self(crate::FUSE_CAP_POSIX_ACL);
+    #[cfg(not(feature = "fuse2"))]
+    pub const HANDLE_KILLPRIV: Self = Self(crate::FUSE_CAP_HANDLE_KILLPRIV);
+    #[cfg(not(feature = "fuse2"))]
+    pub const CACHE_SYMLINKS: Self = Self(crate::FUSE_CAP_CACHE_SYMLINKS);
+    #[cfg(not(feature = "fuse2"))]
+    pub const NO_OPENDIR_SUPPORT: Self = Self(crate::FUSE_CAP_NO_OPENDIR_SUPPORT);
+    #[cfg(not(feature = "fuse2"))]
+    pub const EXPLICIT_INVAL_DATA: Self = Self(crate::FUSE_CAP_EXPLICIT_INVAL_DATA);
+
+    #[cfg(feature = "fuse2")]
+    pub const BIG_WRITES: Self = Self(crate::FUSE_CAP_BIG_WRITES);
+
+    #[cfg(fuse_3_12)]
+    pub const SETXATTR_EXT: Self = Self(crate::FUSE_CAP_SETXATTR_EXT);
+
+    #[cfg(fuse_3_13)]
+    pub const EXPIRE_ONLY: Self = Self(crate::FUSE_CAP_EXPIRE_ONLY);
+
+    #[cfg(fuse_3_16)]
+    pub const DIRECT_IO_ALLOW_MMAP: Self = Self(crate::FUSE_CAP_DIRECT_IO_ALLOW_MMAP);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Capabilities {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl fuse_conn_info {
+    /// Capabilities supported by both the kernel and libfuse.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities(self.capable)
+    }
+
+    /// Capabilities that will be enabled once `init` returns.
+    pub fn wanted(&self) -> Capabilities {
+        Capabilities(self.want)
+    }
+
+    /// Enables `caps` if all of them are supported, returning whether they were.
+    pub fn request(&mut self, caps: Capabilities) -> bool {
+        if !self.capabilities().contains(caps) {
+            return false;
+        }
+
+        self.want |= caps.0;
+        true
+    }
+
+    /// Disables `caps`, including any libfuse enables by default.
+    pub fn disable(&mut self, caps: Capabilities) {
+        self.want &= !caps.0;
+    }
+}
@@ -0,0 +1,40 @@
+//! A safe wrapper around the `fuse_fill_dir_t` callback handed to `readdir`,
+//! which takes an extra flags argument in libfuse 3.
+
+use crate::{fuse_fill_dir_t, off_t, stat};
+use std::{
+    ffi::{c_void, CString, OsStr},
+    os::unix::ffi::OsStrExt,
+};
+
+pub struct DirFiller<'a> {
+    buf: &'a mut c_void,
+    filler: fuse_fill_dir_t,
+}
+
+impl<'a> DirFiller<'a> {
+    /// Wraps the `buf` and `filler` arguments of `readdir`, returning `None`
+    /// if libfuse didn't hand us a buffer to fill.
+    pub fn new(buf: Option<&'a mut c_void>, filler: fuse_fill_dir_t) -> Option<Self> {
+        filler?;
+        Some(Self { buf: buf?, filler })
+    }
+
+    /// Adds an entry, returning false once the buffer is full.
+    ///
+    /// `off` is the offset of the *next* entry, or 0 to have libfuse
+    /// buffer the whole directory and handle offsets itself.
+    pub fn add(&mut self, name: &OsStr, stat: Option<&stat>, off: off_t) -> bool {
+        let filler = self.filler.unwrap();
+        let name = CString::new(name.as_bytes()).unwrap();
+        let stat = stat.map_or(std::ptr::null(), |stat| stat as *const stat);
+
+        #[cfg(not(feature = "fuse2"))]
+        let status = unsafe { filler(self.buf, name.as_ptr(), stat, off, 0) };
+
+        #[cfg(feature = "fuse2")]
+        let status = unsafe { filler(self.buf, name.as_ptr(), stat, off) };
+
+        status == 0
+    }
+}
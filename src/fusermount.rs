@@ -0,0 +1,39 @@
+//! Where libfuse looks for the setuid `fusermount3` (`fusermount` on libfuse 2)
+//! helper it uses to mount without privileges.
+//!
+//! libfuse tries the directory it was configured with first and then falls
+//! back to `PATH`. That configured directory is baked into statically linked
+//! binaries, so on hosts that keep the helper elsewhere it needs pointing at.
+
+use std::{
+    env,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+/// Newer versions of libfuse read the helper's location from this variable.
+const FUSERMOUNT_PROG: &str = "FUSERMOUNT_PROG";
+
+/// Makes subsequent mounts use the helper at `path`.
+///
+/// Older versions of libfuse only search `PATH`, so the helper's directory
+/// is also put at the front of it. Call this before mounting, and before
+/// spawning any threads that read the environment.
+pub fn set_path(path: impl AsRef<Path>) {
+    let path = path.as_ref();
+    env::set_var(FUSERMOUNT_PROG, path);
+
+    if let Some(dir) = path.parent() {
+        let mut dirs = vec![dir.to_path_buf()];
+        dirs.extend(env::split_paths(&env::var_os("PATH").unwrap_or_default()));
+        env::set_var(
+            "PATH",
+            env::join_paths(dirs).unwrap_or_else(|_| OsString::from(dir)),
+        );
+    }
+}
+
+/// The helper configured with [`set_path`] (or `FUSERMOUNT_PROG`), if any.
+pub fn path() -> Option<PathBuf> {
+    env::var_os(FUSERMOUNT_PROG).map(PathBuf::from)
+}
@@ -2,4 +2,54 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
+// The FileSystem trait gets a method for each operation the detected
+// libfuse declares, so newer ones like copy_file_range (3.4) only exist
+// behind their `fuse_3_N` cfg. Those cfgs also gate capability flags and
+// the loop config API, but nothing adds operations libfuse doesn't have.
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+// libfuse 2 has no readdir flags, but the safe readdir signature always
+// takes them so implementations work against either version.
+#[cfg(feature = "fuse2")]
+pub type fuse_readdir_flags = ::std::os::raw::c_uint;
+
+#[cfg(all(feature = "auto", not(feature = "fuse2")))]
+pub mod adapters;
+pub mod capabilities;
+pub mod dir_filler;
+pub mod fusermount;
+#[cfg(not(feature = "fuse2"))]
+pub mod loop_config;
+#[cfg(not(feature = "fuse2"))]
+pub mod lowlevel;
+#[cfg(all(feature = "auto", not(feature = "fuse2")))]
+pub mod memfs;
+#[cfg(all(feature = "auto", not(feature = "fuse2")))]
+pub mod mount;
+#[cfg(not(feature = "fuse2"))]
+pub mod notify;
+#[cfg(all(feature = "auto", not(feature = "fuse2")))]
+pub mod overlay;
+#[cfg(all(feature = "auto", not(feature = "fuse2")))]
+pub mod passthrough;
+#[cfg(all(feature = "auto", not(feature = "fuse2")))]
+pub mod router;
+#[cfg(all(feature = "testing", not(feature = "fuse2")))]
+pub mod testing;
+
+/// The errno an error will be reported to the kernel as, if it has one.
+/// Anything else becomes `ENOTRECOVERABLE`.
+#[cfg(all(any(feature = "auto", feature = "async"), not(feature = "fuse2")))]
+pub(crate) fn errno(e: &anyhow::Error) -> nix::errno::Errno {
+    use nix::errno::Errno;
+
+    if let Some(err) = e.downcast_ref::<std::io::Error>() {
+        if let Some(os) = err.raw_os_error() {
+            return Errno::from_i32(os);
+        }
+    } else if let Some(&err) = e.downcast_ref::<Errno>() {
+        return err;
+    }
+
+    Errno::ENOTRECOVERABLE
+}
@@ -0,0 +1,90 @@
+//! Configuration for libfuse's multi-threaded event loop.
+//!
+//! libfuse 3.12 made `struct fuse_loop_config` opaque and added a maximum
+//! thread count; before that it's a plain struct we fill in ourselves.
+
+use crate::fuse_loop_config;
+
+pub struct LoopConfig {
+    #[cfg(fuse_3_12)]
+    raw: *mut fuse_loop_config,
+    #[cfg(not(fuse_3_12))]
+    raw: fuse_loop_config,
+}
+
+impl LoopConfig {
+    #[cfg(fuse_3_12)]
+    pub fn new() -> Self {
+        let raw = unsafe { crate::fuse_loop_cfg_create() };
+        assert!(!raw.is_null(), "Could not allocate fuse_loop_config");
+        Self { raw }
+    }
+
+    #[cfg(not(fuse_3_12))]
+    pub fn new() -> Self {
+        Self {
+            raw: fuse_loop_config {
+                clone_fd: 0,
+                max_idle_threads: 10,
+            },
+        }
+    }
+
+    /// Whether each worker thread should open its own `/dev/fuse` file descriptor.
+    #[cfg(fuse_3_12)]
+    pub fn clone_fd(self, clone_fd: bool) -> Self {
+        unsafe { crate::fuse_loop_cfg_set_clone_fd(self.raw, clone_fd as _) };
+        self
+    }
+
+    /// Whether each worker thread should open its own `/dev/fuse` file descriptor.
+    #[cfg(not(fuse_3_12))]
+    pub fn clone_fd(mut self, clone_fd: bool) -> Self {
+        self.raw.clone_fd = clone_fd as _;
+        self
+    }
+
+    /// How many idle worker threads to keep around.
+    #[cfg(fuse_3_12)]
+    pub fn max_idle_threads(self, threads: u32) -> Self {
+        unsafe { crate::fuse_loop_cfg_set_idle_threads(self.raw, threads) };
+        self
+    }
+
+    /// How many idle worker threads to keep around.
+    #[cfg(not(fuse_3_12))]
+    pub fn max_idle_threads(mut self, threads: u32) -> Self {
+        self.raw.max_idle_threads = threads;
+        self
+    }
+
+    /// The most worker threads libfuse will spawn at once.
+    #[cfg(fuse_3_12)]
+    pub fn max_threads(self, threads: u32) -> Self {
+        unsafe { crate::fuse_loop_cfg_set_max_threads(self.raw, threads) };
+        self
+    }
+
+    #[cfg(fuse_3_12)]
+    pub fn as_mut_ptr(&mut self) -> *mut fuse_loop_config {
+        self.raw
+    }
+
+    #[cfg(not(fuse_3_12))]
+    pub fn as_mut_ptr(&mut self) -> *mut fuse_loop_config {
+        &mut self.raw
+    }
+}
+
+impl Default for LoopConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(fuse_3_12)]
+impl Drop for LoopConfig {
+    fn drop(&mut self) {
+        unsafe { crate::fuse_loop_cfg_destroy(self.raw) };
+    }
+}
@@ -0,0 +1,734 @@
+//! An `async` counterpart to [`LowLevelFileSystem`], for filesystems backed
+//! by async clients.
+//!
+//! [`TokioAdapter`] spawns each request onto a tokio runtime and answers it
+//! when the future completes, so libfuse's worker threads go straight back
+//! to reading requests instead of blocking on the backend.
+
+use super::*;
+use crate::{dev_t, errno, statvfs};
+use std::{
+    ffi::OsString,
+    future::{ready, Future},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::runtime::Handle;
+
+/// A directory entry, as returned by the operations that create or look one up.
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    /// `attr.st_ino` is the entry's inode number.
+    pub attr: stat,
+    /// How long the kernel may cache the entry and its attributes.
+    pub ttl: Duration,
+    pub generation: u64,
+}
+
+/// A file's attributes and how long the kernel may cache them.
+#[derive(Clone, Copy, Debug)]
+pub struct Attr {
+    pub attr: stat,
+    pub ttl: Duration,
+}
+
+/// One entry of a directory listing.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub ino: Ino,
+    /// The offset of the entry after this one.
+    pub offset: off_t,
+    /// Only the file type bits are used.
+    pub mode: mode_t,
+    pub name: OsString,
+}
+
+type Result<T> = anyhow::Result<T>;
+
+fn unimplemented<T: Send>() -> impl Future<Output = Result<T>> + Send {
+    ready(Err(Errno::ENOSYS.into()))
+}
+
+/// An inode-based filesystem whose operations return futures. Arguments are
+/// owned, and open files are identified by the handle returned from `open`,
+/// `opendir` or `create`.
+#[allow(unused_variables)]
+pub trait AsyncFileSystem: Send + Sync + 'static {
+    fn init(&self, conn: &mut fuse_conn_info) {}
+
+    fn destroy(&self) {}
+
+    fn lookup(
+        &self,
+        req: Request,
+        parent: Ino,
+        name: OsString,
+    ) -> impl Future<Output = Result<Entry>> + Send {
+        unimplemented()
+    }
+
+    fn forget(&self, req: Request, ino: Ino, nlookup: u64) -> impl Future<Output = ()> + Send {
+        ready(())
+    }
+
+    fn getattr(
+        &self,
+        req: Request,
+        ino: Ino,
+        fh: Option<u64>,
+    ) -> impl Future<Output = Result<Attr>> + Send {
+        unimplemented()
+    }
+
+    /// `to_set` is a mask of the `FUSE_SET_ATTR_*` fields of `attr` to change.
+    fn setattr(
+        &self,
+        req: Request,
+        ino: Ino,
+        attr: stat,
+        to_set: i32,
+        fh: Option<u64>,
+    ) -> impl Future<Output = Result<Attr>> + Send {
+        unimplemented()
+    }
+
+    fn readlink(&self, req: Request, ino: Ino) -> impl Future<Output = Result<OsString>> + Send {
+        unimplemented()
+    }
+
+    fn mknod(
+        &self,
+        req: Request,
+        parent: Ino,
+        name: OsString,
+        mode: mode_t,
+        rdev: dev_t,
+    ) -> impl Future<Output = Result<Entry>> + Send {
+        unimplemented()
+    }
+
+    fn mkdir(
+        &self,
+        req: Request,
+        parent: Ino,
+        name: OsString,
+        mode: mode_t,
+    ) -> impl Future<Output = Result<Entry>> + Send {
+        unimplemented()
+    }
+
+    fn unlink(
+        &self,
+        req: Request,
+        parent: Ino,
+        name: OsString,
+    ) -> impl Future<Output = Result<()>> + Send {
+        unimplemented()
+    }
+
+    fn rmdir(
+        &self,
+        req: Request,
+        parent: Ino,
+        name: OsString,
+    ) -> impl Future<Output = Result<()>> + Send {
+        unimplemented()
+    }
+
+    fn symlink(
+        &self,
+        req: Request,
+        link: OsString,
+        parent: Ino,
+        name: OsString,
+    ) -> impl Future<Output = Result<Entry>> + Send {
+        unimplemented()
+    }
+
+    fn rename(
+        &self,
+        req: Request,
+        parent: Ino,
+        name: OsString,
+        newparent: Ino,
+        newname: OsString,
+        flags: u32,
+    ) -> impl Future<Output = Result<()>> + Send {
+        unimplemented()
+    }
+
+    fn link(
+        &self,
+        req: Request,
+        ino: Ino,
+        newparent: Ino,
+        newname: OsString,
+    ) -> impl Future<Output = Result<Entry>> + Send {
+        unimplemented()
+    }
+
+    /// Returns the handle later operations on the file will get.
+    fn open(&self, req: Request, ino: Ino, flags: i32) -> impl Future<Output = Result<u64>> + Send {
+        ready(Ok(0))
+    }
+
+    fn read(
+        &self,
+        req: Request,
+        ino: Ino,
+        fh: u64,
+        size: usize,
+        off: off_t,
+    ) -> impl Future<Output = Result<Vec<u8>>> + Send {
+        unimplemented()
+    }
+
+    fn write(
+        &self,
+        req: Request,
+        ino: Ino,
+        fh: u64,
+        data: Vec<u8>,
+        off: off_t,
+    ) -> impl Future<Output = Result<usize>> + Send {
+        unimplemented()
+    }
+
+    fn flush(&self, req: Request, ino: Ino, fh: u64) -> impl Future<Output = Result<()>> + Send {
+        unimplemented()
+    }
+
+    fn release(&self, req: Request, ino: Ino, fh: u64) -> impl Future<Output = Result<()>> + Send {
+        ready(Ok(()))
+    }
+
+    fn fsync(
+        &self,
+        req: Request,
+        ino: Ino,
+        fh: u64,
+        datasync: bool,
+    ) -> impl Future<Output = Result<()>> + Send {
+        unimplemented()
+    }
+
+    fn opendir(
+        &self,
+        req: Request,
+        ino: Ino,
+        flags: i32,
+    ) -> impl Future<Output = Result<u64>> + Send {
+        ready(Ok(0))
+    }
+
+    /// Returns the entries following `off`. Entries that don't fit in the
+    /// kernel's buffer are dropped, and asked for again from their offset.
+    fn readdir(
+        &self,
+        req: Request,
+        ino: Ino,
+        fh: u64,
+        off: off_t,
+    ) -> impl Future<Output = Result<Vec<DirEntry>>> + Send {
+        unimplemented()
+    }
+
+    fn releasedir(
+        &self,
+        req: Request,
+        ino: Ino,
+        fh: u64,
+    ) -> impl Future<Output = Result<()>> + Send {
+        ready(Ok(()))
+    }
+
+    fn fsyncdir(
+        &self,
+        req: Request,
+        ino: Ino,
+        fh: u64,
+        datasync: bool,
+    ) -> impl Future<Output = Result<()>> + Send {
+        unimplemented()
+    }
+
+    fn statfs(&self, req: Request, ino: Ino) -> impl Future<Output = Result<statvfs>> + Send {
+        ready(Ok(statvfs {
+            f_namemax: 255,
+            f_bsize: 512,
+            ..Default::default()
+        }))
+    }
+
+    fn setxattr(
+        &self,
+        req: Request,
+        ino: Ino,
+        name: OsString,
+        value: Vec<u8>,
+        flags: i32,
+    ) -> impl Future<Output = Result<()>> + Send {
+        unimplemented()
+    }
+
+    fn getxattr(
+        &self,
+        req: Request,
+        ino: Ino,
+        name: OsString,
+    ) -> impl Future<Output = Result<Vec<u8>>> + Send {
+        unimplemented()
+    }
+
+    /// Returns the attribute names, each terminated by a NUL byte.
+    fn listxattr(&self, req: Request, ino: Ino) -> impl Future<Output = Result<Vec<u8>>> + Send {
+        unimplemented()
+    }
+
+    fn removexattr(
+        &self,
+        req: Request,
+        ino: Ino,
+        name: OsString,
+    ) -> impl Future<Output = Result<()>> + Send {
+        unimplemented()
+    }
+
+    fn access(&self, req: Request, ino: Ino, mask: i32) -> impl Future<Output = Result<()>> + Send {
+        unimplemented()
+    }
+
+    /// Returns the new entry and the handle later operations on it will get.
+    fn create(
+        &self,
+        req: Request,
+        parent: Ino,
+        name: OsString,
+        mode: mode_t,
+        flags: i32,
+    ) -> impl Future<Output = Result<(Entry, u64)>> + Send {
+        unimplemented()
+    }
+}
+
+/// Serves an [`AsyncFileSystem`] as a [`LowLevelFileSystem`] by spawning
+/// every request onto a tokio runtime.
+pub struct TokioAdapter<F> {
+    fs: Arc<F>,
+    runtime: Handle,
+}
+
+impl<F: AsyncFileSystem> TokioAdapter<F> {
+    pub fn new(fs: F, runtime: Handle) -> Self {
+        Self {
+            fs: Arc::new(fs),
+            runtime,
+        }
+    }
+
+    /// Spawns onto the runtime we're currently inside of.
+    pub fn current(fs: F) -> Self {
+        Self::new(fs, Handle::current())
+    }
+
+    fn spawn<T, Fut>(
+        &self,
+        op: impl FnOnce(Arc<F>) -> Fut,
+        done: impl FnOnce(std::result::Result<T, Errno>) + Send + 'static,
+    ) where
+        Fut: Future<Output = Result<T>> + Send + 'static,
+    {
+        let fut = op(self.fs.clone());
+        self.runtime
+            .spawn(async move { done(fut.await.map_err(|e| errno(&e))) });
+    }
+}
+
+fn empty(reply: ReplyEmpty) -> impl FnOnce(std::result::Result<(), Errno>) + Send {
+    move |result| match result {
+        Ok(()) => reply.ok(),
+        Err(e) => reply.error(e),
+    }
+}
+
+fn entry(reply: ReplyEntry) -> impl FnOnce(std::result::Result<Entry, Errno>) + Send {
+    move |result| match result {
+        Ok(entry) => reply.entry(entry.ttl, &entry.attr, entry.generation),
+        Err(e) => reply.error(e),
+    }
+}
+
+fn attr(reply: ReplyAttr) -> impl FnOnce(std::result::Result<Attr, Errno>) + Send {
+    move |result| match result {
+        Ok(attr) => reply.attr(attr.ttl, &attr.attr),
+        Err(e) => reply.error(e),
+    }
+}
+
+fn opened(reply: ReplyOpen) -> impl FnOnce(std::result::Result<u64, Errno>) + Send {
+    move |result| match result {
+        Ok(fh) => reply.opened(fh),
+        Err(e) => reply.error(e),
+    }
+}
+
+fn xattr(
+    reply: ReplyXattr,
+    size: usize,
+) -> impl FnOnce(std::result::Result<Vec<u8>, Errno>) + Send {
+    move |result| match result {
+        Ok(data) if size == 0 => reply.size(data.len()),
+        Ok(data) if data.len() > size => reply.error(Errno::ERANGE),
+        Ok(data) => reply.data(&data),
+        Err(e) => reply.error(e),
+    }
+}
+
+impl<F: AsyncFileSystem> LowLevelFileSystem for TokioAdapter<F> {
+    fn init(&self, conn: &mut fuse_conn_info) {
+        self.fs.init(conn);
+    }
+
+    fn destroy(&self) {
+        self.fs.destroy();
+    }
+
+    fn lookup(&self, req: &Request, parent: Ino, name: &OsStr, reply: ReplyEntry) {
+        let (req, name) = (*req, name.to_owned());
+        self.spawn(
+            move |fs| async move { fs.lookup(req, parent, name).await },
+            entry(reply),
+        );
+    }
+
+    fn forget(&self, req: &Request, ino: Ino, nlookup: u64, reply: ReplyNone) {
+        let req = *req;
+        let fs = self.fs.clone();
+        self.runtime.spawn(async move {
+            fs.forget(req, ino, nlookup).await;
+            reply.none();
+        });
+    }
+
+    fn getattr(&self, req: &Request, ino: Ino, fi: Option<&fuse_file_info>, reply: ReplyAttr) {
+        let (req, fh) = (*req, fi.map(|fi| fi.fh));
+        self.spawn(
+            move |fs| async move { fs.getattr(req, ino, fh).await },
+            attr(reply),
+        );
+    }
+
+    fn setattr(
+        &self,
+        req: &Request,
+        ino: Ino,
+        attr_: &stat,
+        to_set: i32,
+        fi: Option<&fuse_file_info>,
+        reply: ReplyAttr,
+    ) {
+        let (req, attr_, fh) = (*req, *attr_, fi.map(|fi| fi.fh));
+        self.spawn(
+            move |fs| async move { fs.setattr(req, ino, attr_, to_set, fh).await },
+            attr(reply),
+        );
+    }
+
+    fn readlink(&self, req: &Request, ino: Ino, reply: ReplyReadlink) {
+        let req = *req;
+        self.spawn(
+            move |fs| async move { fs.readlink(req, ino).await },
+            move |result| match result {
+                Ok(link) => reply.link(&link),
+                Err(e) => reply.error(e),
+            },
+        );
+    }
+
+    fn mknod(
+        &self,
+        req: &Request,
+        parent: Ino,
+        name: &OsStr,
+        mode: mode_t,
+        rdev: dev_t,
+        reply: ReplyEntry,
+    ) {
+        let (req, name) = (*req, name.to_owned());
+        self.spawn(
+            move |fs| async move { fs.mknod(req, parent, name, mode, rdev).await },
+            entry(reply),
+        );
+    }
+
+    fn mkdir(&self, req: &Request, parent: Ino, name: &OsStr, mode: mode_t, reply: ReplyEntry) {
+        let (req, name) = (*req, name.to_owned());
+        self.spawn(
+            move |fs| async move { fs.mkdir(req, parent, name, mode).await },
+            entry(reply),
+        );
+    }
+
+    fn unlink(&self, req: &Request, parent: Ino, name: &OsStr, reply: ReplyEmpty) {
+        let (req, name) = (*req, name.to_owned());
+        self.spawn(
+            move |fs| async move { fs.unlink(req, parent, name).await },
+            empty(reply),
+        );
+    }
+
+    fn rmdir(&self, req: &Request, parent: Ino, name: &OsStr, reply: ReplyEmpty) {
+        let (req, name) = (*req, name.to_owned());
+        self.spawn(
+            move |fs| async move { fs.rmdir(req, parent, name).await },
+            empty(reply),
+        );
+    }
+
+    fn symlink(&self, req: &Request, link: &OsStr, parent: Ino, name: &OsStr, reply: ReplyEntry) {
+        let (req, link, name) = (*req, link.to_owned(), name.to_owned());
+        self.spawn(
+            move |fs| async move { fs.symlink(req, link, parent, name).await },
+            entry(reply),
+        );
+    }
+
+    fn rename(
+        &self,
+        req: &Request,
+        parent: Ino,
+        name: &OsStr,
+        newparent: Ino,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let (req, name, newname) = (*req, name.to_owned(), newname.to_owned());
+        self.spawn(
+            move |fs| async move {
+                fs.rename(req, parent, name, newparent, newname, flags)
+                    .await
+            },
+            empty(reply),
+        );
+    }
+
+    fn link(&self, req: &Request, ino: Ino, newparent: Ino, newname: &OsStr, reply: ReplyEntry) {
+        let (req, newname) = (*req, newname.to_owned());
+        self.spawn(
+            move |fs| async move { fs.link(req, ino, newparent, newname).await },
+            entry(reply),
+        );
+    }
+
+    fn open(&self, req: &Request, ino: Ino, fi: &fuse_file_info, reply: ReplyOpen) {
+        let (req, flags) = (*req, fi.flags);
+        self.spawn(
+            move |fs| async move { fs.open(req, ino, flags).await },
+            opened(reply),
+        );
+    }
+
+    fn read(
+        &self,
+        req: &Request,
+        ino: Ino,
+        size: usize,
+        off: off_t,
+        fi: &fuse_file_info,
+        reply: ReplyData,
+    ) {
+        let (req, fh) = (*req, fi.fh);
+        self.spawn(
+            move |fs| async move { fs.read(req, ino, fh, size, off).await },
+            move |result| match result {
+                Ok(data) => reply.data(&data[..data.len().min(size)]),
+                Err(e) => reply.error(e),
+            },
+        );
+    }
+
+    fn write(
+        &self,
+        req: &Request,
+        ino: Ino,
+        data: &[u8],
+        off: off_t,
+        fi: &fuse_file_info,
+        reply: ReplyWrite,
+    ) {
+        let (req, fh, data) = (*req, fi.fh, data.to_vec());
+        self.spawn(
+            move |fs| async move { fs.write(req, ino, fh, data, off).await },
+            move |result| match result {
+                Ok(count) => reply.written(count),
+                Err(e) => reply.error(e),
+            },
+        );
+    }
+
+    fn flush(&self, req: &Request, ino: Ino, fi: &fuse_file_info, reply: ReplyEmpty) {
+        let (req, fh) = (*req, fi.fh);
+        self.spawn(
+            move |fs| async move { fs.flush(req, ino, fh).await },
+            empty(reply),
+        );
+    }
+
+    fn release(&self, req: &Request, ino: Ino, fi: &fuse_file_info, reply: ReplyEmpty) {
+        let (req, fh) = (*req, fi.fh);
+        self.spawn(
+            move |fs| async move { fs.release(req, ino, fh).await },
+            empty(reply),
+        );
+    }
+
+    fn fsync(
+        &self,
+        req: &Request,
+        ino: Ino,
+        datasync: bool,
+        fi: &fuse_file_info,
+        reply: ReplyEmpty,
+    ) {
+        let (req, fh) = (*req, fi.fh);
+        self.spawn(
+            move |fs| async move { fs.fsync(req, ino, fh, datasync).await },
+            empty(reply),
+        );
+    }
+
+    fn opendir(&self, req: &Request, ino: Ino, fi: &fuse_file_info, reply: ReplyOpen) {
+        let (req, flags) = (*req, fi.flags);
+        self.spawn(
+            move |fs| async move { fs.opendir(req, ino, flags).await },
+            opened(reply),
+        );
+    }
+
+    fn readdir(
+        &self,
+        req: &Request,
+        ino: Ino,
+        off: off_t,
+        fi: &fuse_file_info,
+        mut reply: ReplyDirectory,
+    ) {
+        let (req, fh) = (*req, fi.fh);
+        self.spawn(
+            move |fs| async move { fs.readdir(req, ino, fh, off).await },
+            move |result| match result {
+                Ok(entries) => {
+                    for entry in entries {
+                        if !reply.add(entry.ino, entry.offset, entry.mode, &entry.name) {
+                            break;
+                        }
+                    }
+                    reply.ok();
+                }
+                Err(e) => reply.error(e),
+            },
+        );
+    }
+
+    fn releasedir(&self, req: &Request, ino: Ino, fi: &fuse_file_info, reply: ReplyEmpty) {
+        let (req, fh) = (*req, fi.fh);
+        self.spawn(
+            move |fs| async move { fs.releasedir(req, ino, fh).await },
+            empty(reply),
+        );
+    }
+
+    fn fsyncdir(
+        &self,
+        req: &Request,
+        ino: Ino,
+        datasync: bool,
+        fi: &fuse_file_info,
+        reply: ReplyEmpty,
+    ) {
+        let (req, fh) = (*req, fi.fh);
+        self.spawn(
+            move |fs| async move { fs.fsyncdir(req, ino, fh, datasync).await },
+            empty(reply),
+        );
+    }
+
+    fn statfs(&self, req: &Request, ino: Ino, reply: ReplyStatfs) {
+        let req = *req;
+        self.spawn(
+            move |fs| async move { fs.statfs(req, ino).await },
+            move |result| match result {
+                Ok(stat) => reply.statfs(&stat),
+                Err(e) => reply.error(e),
+            },
+        );
+    }
+
+    fn setxattr(
+        &self,
+        req: &Request,
+        ino: Ino,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        let (req, name, value) = (*req, name.to_owned(), value.to_vec());
+        self.spawn(
+            move |fs| async move { fs.setxattr(req, ino, name, value, flags).await },
+            empty(reply),
+        );
+    }
+
+    fn getxattr(&self, req: &Request, ino: Ino, name: &OsStr, size: usize, reply: ReplyXattr) {
+        let (req, name) = (*req, name.to_owned());
+        self.spawn(
+            move |fs| async move { fs.getxattr(req, ino, name).await },
+            xattr(reply, size),
+        );
+    }
+
+    fn listxattr(&self, req: &Request, ino: Ino, size: usize, reply: ReplyXattr) {
+        let req = *req;
+        self.spawn(
+            move |fs| async move { fs.listxattr(req, ino).await },
+            xattr(reply, size),
+        );
+    }
+
+    fn removexattr(&self, req: &Request, ino: Ino, name: &OsStr, reply: ReplyEmpty) {
+        let (req, name) = (*req, name.to_owned());
+        self.spawn(
+            move |fs| async move { fs.removexattr(req, ino, name).await },
+            empty(reply),
+        );
+    }
+
+    fn access(&self, req: &Request, ino: Ino, mask: i32, reply: ReplyEmpty) {
+        let req = *req;
+        self.spawn(
+            move |fs| async move { fs.access(req, ino, mask).await },
+            empty(reply),
+        );
+    }
+
+    fn create(
+        &self,
+        req: &Request,
+        parent: Ino,
+        name: &OsStr,
+        mode: mode_t,
+        fi: &fuse_file_info,
+        reply: ReplyCreate,
+    ) {
+        let (req, name, flags) = (*req, name.to_owned(), fi.flags);
+        self.spawn(
+            move |fs| async move { fs.create(req, parent, name, mode, flags).await },
+            move |result| match result {
+                Ok((entry, fh)) => reply.created(entry.ttl, &entry.attr, entry.generation, fh),
+                Err(e) => reply.error(e),
+            },
+        );
+    }
+}
@@ -0,0 +1,273 @@
+//! A safe interface to libfuse's low-level API, where operations address
+//! files by inode number rather than by path.
+//!
+//! Unlike the high-level API, nothing is returned from the operations.
+//! Each one is handed a reply object instead, which answers the kernel when
+//! used. Replies can be moved to other threads and answered after the
+//! operation returns, so slow backends don't have to tie up libfuse's
+//! workers; dropping one unanswered fails the request with `EIO`.
+
+#[cfg(feature = "async")]
+pub mod asynchronous;
+mod ops;
+mod reply;
+mod session;
+
+#[cfg(feature = "async")]
+pub use asynchronous::{AsyncFileSystem, TokioAdapter};
+pub use reply::*;
+pub use session::Session;
+
+use crate::{fuse_conn_info, fuse_file_info, fuse_req_t, gid_t, mode_t, off_t, pid_t, stat, uid_t};
+use nix::errno::Errno;
+use std::ffi::OsStr;
+
+pub type Ino = crate::fuse_ino_t;
+
+/// The inode number of the filesystem's root directory.
+pub const ROOT_INO: Ino = crate::FUSE_ROOT_ID as Ino;
+
+/// Who issued a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub uid: uid_t,
+    pub gid: gid_t,
+    pub pid: pid_t,
+    pub umask: mode_t,
+}
+
+impl Request {
+    pub(crate) unsafe fn from_raw(req: fuse_req_t) -> Self {
+        let ctx = &*crate::fuse_req_ctx(req);
+        Self {
+            uid: ctx.uid,
+            gid: ctx.gid,
+            pid: ctx.pid,
+            umask: ctx.umask,
+        }
+    }
+}
+
+/// An inode-based filesystem. Operations that aren't implemented behave the
+/// same way libfuse treats missing operations.
+#[allow(unused_variables)]
+pub trait LowLevelFileSystem: Send + Sync {
+    fn init(&self, conn: &mut fuse_conn_info) {}
+
+    fn destroy(&self) {}
+
+    fn lookup(&self, req: &Request, parent: Ino, name: &OsStr, reply: ReplyEntry) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn forget(&self, req: &Request, ino: Ino, nlookup: u64, reply: ReplyNone) {
+        reply.none();
+    }
+
+    fn getattr(&self, req: &Request, ino: Ino, fi: Option<&fuse_file_info>, reply: ReplyAttr) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    /// `to_set` is a mask of the `FUSE_SET_ATTR_*` fields of `attr` to change.
+    fn setattr(
+        &self,
+        req: &Request,
+        ino: Ino,
+        attr: &stat,
+        to_set: i32,
+        fi: Option<&fuse_file_info>,
+        reply: ReplyAttr,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn readlink(&self, req: &Request, ino: Ino, reply: ReplyReadlink) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn mknod(
+        &self,
+        req: &Request,
+        parent: Ino,
+        name: &OsStr,
+        mode: mode_t,
+        rdev: crate::dev_t,
+        reply: ReplyEntry,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn mkdir(&self, req: &Request, parent: Ino, name: &OsStr, mode: mode_t, reply: ReplyEntry) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn unlink(&self, req: &Request, parent: Ino, name: &OsStr, reply: ReplyEmpty) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn rmdir(&self, req: &Request, parent: Ino, name: &OsStr, reply: ReplyEmpty) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn symlink(&self, req: &Request, link: &OsStr, parent: Ino, name: &OsStr, reply: ReplyEntry) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn rename(
+        &self,
+        req: &Request,
+        parent: Ino,
+        name: &OsStr,
+        newparent: Ino,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn link(&self, req: &Request, ino: Ino, newparent: Ino, newname: &OsStr, reply: ReplyEntry) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn open(&self, req: &Request, ino: Ino, fi: &fuse_file_info, reply: ReplyOpen) {
+        reply.opened(0);
+    }
+
+    fn read(
+        &self,
+        req: &Request,
+        ino: Ino,
+        size: usize,
+        off: off_t,
+        fi: &fuse_file_info,
+        reply: ReplyData,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn write(
+        &self,
+        req: &Request,
+        ino: Ino,
+        data: &[u8],
+        off: off_t,
+        fi: &fuse_file_info,
+        reply: ReplyWrite,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn flush(&self, req: &Request, ino: Ino, fi: &fuse_file_info, reply: ReplyEmpty) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn release(&self, req: &Request, ino: Ino, fi: &fuse_file_info, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    fn fsync(
+        &self,
+        req: &Request,
+        ino: Ino,
+        datasync: bool,
+        fi: &fuse_file_info,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn opendir(&self, req: &Request, ino: Ino, fi: &fuse_file_info, reply: ReplyOpen) {
+        reply.opened(0);
+    }
+
+    fn readdir(
+        &self,
+        req: &Request,
+        ino: Ino,
+        off: off_t,
+        fi: &fuse_file_info,
+        reply: ReplyDirectory,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn releasedir(&self, req: &Request, ino: Ino, fi: &fuse_file_info, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    fn fsyncdir(
+        &self,
+        req: &Request,
+        ino: Ino,
+        datasync: bool,
+        fi: &fuse_file_info,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn statfs(&self, req: &Request, ino: Ino, reply: ReplyStatfs) {
+        reply.statfs(&crate::statvfs {
+            f_namemax: 255,
+            f_bsize: 512,
+            ..Default::default()
+        });
+    }
+
+    fn setxattr(
+        &self,
+        req: &Request,
+        ino: Ino,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    /// A `size` of 0 asks for the size of the value rather than the value.
+    fn getxattr(&self, req: &Request, ino: Ino, name: &OsStr, size: usize, reply: ReplyXattr) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    /// A `size` of 0 asks for the size of the list rather than the list.
+    fn listxattr(&self, req: &Request, ino: Ino, size: usize, reply: ReplyXattr) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn removexattr(&self, req: &Request, ino: Ino, name: &OsStr, reply: ReplyEmpty) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn access(&self, req: &Request, ino: Ino, mask: i32, reply: ReplyEmpty) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn create(
+        &self,
+        req: &Request,
+        parent: Ino,
+        name: &OsStr,
+        mode: mode_t,
+        fi: &fuse_file_info,
+        reply: ReplyCreate,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    /// Receives the data asked for by [`Notifier::retrieve`](crate::notify::Notifier::retrieve).
+    fn retrieve_reply(
+        &self,
+        req: &Request,
+        cookie: u64,
+        ino: Ino,
+        off: off_t,
+        data: &[u8],
+        reply: ReplyNone,
+    ) {
+        reply.none();
+    }
+}
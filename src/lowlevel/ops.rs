@@ -0,0 +1,461 @@
+//! The `extern "C"` callbacks handed to libfuse, which turn the raw
+//! arguments into safe ones and dispatch to a [`LowLevelFileSystem`].
+
+use super::{session::SessionData, *};
+use crate::{dev_t, fuse_buf, fuse_bufvec, fuse_ino_t, fuse_lowlevel_ops};
+use std::{
+    ffi::{c_void, CStr},
+    os::{raw::c_char, unix::ffi::OsStrExt},
+};
+
+unsafe fn fs<'a, F>(req: fuse_req_t) -> &'a F {
+    session_fs(crate::fuse_req_userdata(req))
+}
+
+unsafe fn session_fs<'a, F>(userdata: *mut c_void) -> &'a F {
+    &(userdata as *const SessionData<F>)
+        .as_ref()
+        .expect("Mangled session userdata")
+        .fs
+}
+
+unsafe fn name<'a>(name: *const c_char) -> &'a OsStr {
+    OsStr::from_bytes(CStr::from_ptr(name).to_bytes())
+}
+
+unsafe extern "C" fn init<F: LowLevelFileSystem>(userdata: *mut c_void, conn: *mut fuse_conn_info) {
+    session_fs::<F>(userdata).init(&mut *conn);
+}
+
+unsafe extern "C" fn destroy<F: LowLevelFileSystem>(userdata: *mut c_void) {
+    session_fs::<F>(userdata).destroy();
+}
+
+unsafe extern "C" fn lookup<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    parent: fuse_ino_t,
+    name_: *const c_char,
+) {
+    fs::<F>(req).lookup(
+        &Request::from_raw(req),
+        parent,
+        name(name_),
+        ReplyEntry::new(req),
+    );
+}
+
+unsafe extern "C" fn forget<F: LowLevelFileSystem>(req: fuse_req_t, ino: fuse_ino_t, nlookup: u64) {
+    fs::<F>(req).forget(&Request::from_raw(req), ino, nlookup, ReplyNone::new(req));
+}
+
+unsafe extern "C" fn getattr<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    fi: *mut fuse_file_info,
+) {
+    fs::<F>(req).getattr(
+        &Request::from_raw(req),
+        ino,
+        fi.as_ref(),
+        ReplyAttr::new(req),
+    );
+}
+
+unsafe extern "C" fn setattr<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    attr: *mut stat,
+    to_set: i32,
+    fi: *mut fuse_file_info,
+) {
+    fs::<F>(req).setattr(
+        &Request::from_raw(req),
+        ino,
+        &*attr,
+        to_set,
+        fi.as_ref(),
+        ReplyAttr::new(req),
+    );
+}
+
+unsafe extern "C" fn readlink<F: LowLevelFileSystem>(req: fuse_req_t, ino: fuse_ino_t) {
+    fs::<F>(req).readlink(&Request::from_raw(req), ino, ReplyReadlink::new(req));
+}
+
+unsafe extern "C" fn mknod<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    parent: fuse_ino_t,
+    name_: *const c_char,
+    mode: mode_t,
+    rdev: dev_t,
+) {
+    fs::<F>(req).mknod(
+        &Request::from_raw(req),
+        parent,
+        name(name_),
+        mode,
+        rdev,
+        ReplyEntry::new(req),
+    );
+}
+
+unsafe extern "C" fn mkdir<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    parent: fuse_ino_t,
+    name_: *const c_char,
+    mode: mode_t,
+) {
+    fs::<F>(req).mkdir(
+        &Request::from_raw(req),
+        parent,
+        name(name_),
+        mode,
+        ReplyEntry::new(req),
+    );
+}
+
+unsafe extern "C" fn unlink<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    parent: fuse_ino_t,
+    name_: *const c_char,
+) {
+    fs::<F>(req).unlink(
+        &Request::from_raw(req),
+        parent,
+        name(name_),
+        ReplyEmpty::new(req),
+    );
+}
+
+unsafe extern "C" fn rmdir<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    parent: fuse_ino_t,
+    name_: *const c_char,
+) {
+    fs::<F>(req).rmdir(
+        &Request::from_raw(req),
+        parent,
+        name(name_),
+        ReplyEmpty::new(req),
+    );
+}
+
+unsafe extern "C" fn symlink<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    link: *const c_char,
+    parent: fuse_ino_t,
+    name_: *const c_char,
+) {
+    fs::<F>(req).symlink(
+        &Request::from_raw(req),
+        name(link),
+        parent,
+        name(name_),
+        ReplyEntry::new(req),
+    );
+}
+
+unsafe extern "C" fn rename<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    parent: fuse_ino_t,
+    name_: *const c_char,
+    newparent: fuse_ino_t,
+    newname: *const c_char,
+    flags: u32,
+) {
+    fs::<F>(req).rename(
+        &Request::from_raw(req),
+        parent,
+        name(name_),
+        newparent,
+        name(newname),
+        flags,
+        ReplyEmpty::new(req),
+    );
+}
+
+unsafe extern "C" fn link<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    newparent: fuse_ino_t,
+    newname: *const c_char,
+) {
+    fs::<F>(req).link(
+        &Request::from_raw(req),
+        ino,
+        newparent,
+        name(newname),
+        ReplyEntry::new(req),
+    );
+}
+
+unsafe extern "C" fn open<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    fi: *mut fuse_file_info,
+) {
+    fs::<F>(req).open(&Request::from_raw(req), ino, &*fi, ReplyOpen::new(req, *fi));
+}
+
+unsafe extern "C" fn read<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    size: usize,
+    off: off_t,
+    fi: *mut fuse_file_info,
+) {
+    fs::<F>(req).read(
+        &Request::from_raw(req),
+        ino,
+        size,
+        off,
+        &*fi,
+        ReplyData::new(req),
+    );
+}
+
+unsafe extern "C" fn write<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    buf: *const c_char,
+    size: usize,
+    off: off_t,
+    fi: *mut fuse_file_info,
+) {
+    fs::<F>(req).write(
+        &Request::from_raw(req),
+        ino,
+        std::slice::from_raw_parts(buf as *const u8, size),
+        off,
+        &*fi,
+        ReplyWrite::new(req),
+    );
+}
+
+unsafe extern "C" fn flush<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    fi: *mut fuse_file_info,
+) {
+    fs::<F>(req).flush(&Request::from_raw(req), ino, &*fi, ReplyEmpty::new(req));
+}
+
+unsafe extern "C" fn release<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    fi: *mut fuse_file_info,
+) {
+    fs::<F>(req).release(&Request::from_raw(req), ino, &*fi, ReplyEmpty::new(req));
+}
+
+unsafe extern "C" fn fsync<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    datasync: i32,
+    fi: *mut fuse_file_info,
+) {
+    fs::<F>(req).fsync(
+        &Request::from_raw(req),
+        ino,
+        datasync != 0,
+        &*fi,
+        ReplyEmpty::new(req),
+    );
+}
+
+unsafe extern "C" fn opendir<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    fi: *mut fuse_file_info,
+) {
+    fs::<F>(req).opendir(&Request::from_raw(req), ino, &*fi, ReplyOpen::new(req, *fi));
+}
+
+unsafe extern "C" fn readdir<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    size: usize,
+    off: off_t,
+    fi: *mut fuse_file_info,
+) {
+    fs::<F>(req).readdir(
+        &Request::from_raw(req),
+        ino,
+        off,
+        &*fi,
+        ReplyDirectory::with_size(req, size),
+    );
+}
+
+unsafe extern "C" fn releasedir<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    fi: *mut fuse_file_info,
+) {
+    fs::<F>(req).releasedir(&Request::from_raw(req), ino, &*fi, ReplyEmpty::new(req));
+}
+
+unsafe extern "C" fn fsyncdir<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    datasync: i32,
+    fi: *mut fuse_file_info,
+) {
+    fs::<F>(req).fsyncdir(
+        &Request::from_raw(req),
+        ino,
+        datasync != 0,
+        &*fi,
+        ReplyEmpty::new(req),
+    );
+}
+
+unsafe extern "C" fn statfs<F: LowLevelFileSystem>(req: fuse_req_t, ino: fuse_ino_t) {
+    fs::<F>(req).statfs(&Request::from_raw(req), ino, ReplyStatfs::new(req));
+}
+
+unsafe extern "C" fn setxattr<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    name_: *const c_char,
+    value: *const c_char,
+    size: usize,
+    flags: i32,
+) {
+    fs::<F>(req).setxattr(
+        &Request::from_raw(req),
+        ino,
+        name(name_),
+        std::slice::from_raw_parts(value as *const u8, size),
+        flags,
+        ReplyEmpty::new(req),
+    );
+}
+
+unsafe extern "C" fn getxattr<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    name_: *const c_char,
+    size: usize,
+) {
+    fs::<F>(req).getxattr(
+        &Request::from_raw(req),
+        ino,
+        name(name_),
+        size,
+        ReplyXattr::new(req),
+    );
+}
+
+unsafe extern "C" fn listxattr<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    size: usize,
+) {
+    fs::<F>(req).listxattr(&Request::from_raw(req), ino, size, ReplyXattr::new(req));
+}
+
+unsafe extern "C" fn removexattr<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    ino: fuse_ino_t,
+    name_: *const c_char,
+) {
+    fs::<F>(req).removexattr(
+        &Request::from_raw(req),
+        ino,
+        name(name_),
+        ReplyEmpty::new(req),
+    );
+}
+
+unsafe extern "C" fn access<F: LowLevelFileSystem>(req: fuse_req_t, ino: fuse_ino_t, mask: i32) {
+    fs::<F>(req).access(&Request::from_raw(req), ino, mask, ReplyEmpty::new(req));
+}
+
+unsafe extern "C" fn create<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    parent: fuse_ino_t,
+    name_: *const c_char,
+    mode: mode_t,
+    fi: *mut fuse_file_info,
+) {
+    fs::<F>(req).create(
+        &Request::from_raw(req),
+        parent,
+        name(name_),
+        mode,
+        &*fi,
+        ReplyCreate::new(req, *fi),
+    );
+}
+
+unsafe extern "C" fn retrieve_reply<F: LowLevelFileSystem>(
+    req: fuse_req_t,
+    cookie: *mut c_void,
+    ino: fuse_ino_t,
+    off: off_t,
+    bufv: *mut fuse_bufvec,
+) {
+    // The data may be spread over several buffers, or not be in memory at
+    // all, so copy it out into one.
+    let mut data = vec![0u8; crate::fuse_buf_size(bufv)];
+    let mut dst = fuse_bufvec {
+        count: 1,
+        buf: [fuse_buf {
+            size: data.len(),
+            mem: data.as_mut_ptr() as *mut c_void,
+            fd: -1,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let copied = crate::fuse_buf_copy(&mut dst, bufv, 0);
+    data.truncate(copied.max(0) as usize);
+
+    fs::<F>(req).retrieve_reply(
+        &Request::from_raw(req),
+        cookie as u64,
+        ino,
+        off,
+        &data,
+        ReplyNone::new(req),
+    );
+}
+
+pub(crate) fn operations<F: LowLevelFileSystem>() -> fuse_lowlevel_ops {
+    fuse_lowlevel_ops {
+        init: Some(init::<F>),
+        destroy: Some(destroy::<F>),
+        lookup: Some(lookup::<F>),
+        forget: Some(forget::<F>),
+        getattr: Some(getattr::<F>),
+        setattr: Some(setattr::<F>),
+        readlink: Some(readlink::<F>),
+        mknod: Some(mknod::<F>),
+        mkdir: Some(mkdir::<F>),
+        unlink: Some(unlink::<F>),
+        rmdir: Some(rmdir::<F>),
+        symlink: Some(symlink::<F>),
+        rename: Some(rename::<F>),
+        link: Some(link::<F>),
+        open: Some(open::<F>),
+        read: Some(read::<F>),
+        write: Some(write::<F>),
+        flush: Some(flush::<F>),
+        release: Some(release::<F>),
+        fsync: Some(fsync::<F>),
+        opendir: Some(opendir::<F>),
+        readdir: Some(readdir::<F>),
+        releasedir: Some(releasedir::<F>),
+        fsyncdir: Some(fsyncdir::<F>),
+        statfs: Some(statfs::<F>),
+        setxattr: Some(setxattr::<F>),
+        getxattr: Some(getxattr::<F>),
+        listxattr: Some(listxattr::<F>),
+        removexattr: Some(removexattr::<F>),
+        access: Some(access::<F>),
+        create: Some(create::<F>),
+        retrieve_reply: Some(retrieve_reply::<F>),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,385 @@
+//! Wrappers around the `fuse_reply_*` functions. Each reply consumes the
+//! object, so a request can't be answered twice.
+//!
+//! Replies own their request and are `Send`, so they can be moved to another
+//! thread and answered after the operation has returned. One that's dropped
+//! without answering fails its request with `EIO`, rather than leaving the
+//! caller hanging. The session waits for every reply to be answered before
+//! it's destroyed.
+//!
+//! A reply libfuse fails to send is kept by the session, which returns it
+//! once its loop ends.
+
+use super::Ino;
+use crate::{fuse_entry_param, fuse_file_info, fuse_req_t, mode_t, off_t, stat, statvfs};
+use nix::errno::Errno;
+use std::{
+    ffi::{CString, OsStr},
+    mem::ManuallyDrop,
+    os::unix::ffi::OsStrExt,
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+/// Counts the replies a session has handed out and not yet answered, so the
+/// session can wait for them before it's destroyed.
+#[derive(Default)]
+pub(crate) struct Pending {
+    count: Mutex<usize>,
+    answered: Condvar,
+    /// The first reply libfuse failed to send.
+    failed: Mutex<Option<Errno>>,
+}
+
+impl Pending {
+    fn add(&self) {
+        *self.count.lock().unwrap() += 1;
+    }
+
+    fn remove(&self) {
+        let mut count = self.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.answered.notify_all();
+        }
+    }
+
+    /// Keeps what `fuse_reply_*` returned, if it failed. `ENOENT` only means
+    /// the request was interrupted, and nobody's waiting for the answer.
+    fn answered(&self, res: i32) {
+        if res < 0 && res != -libc::ENOENT {
+            self.failed
+                .lock()
+                .unwrap()
+                .get_or_insert(Errno::from_i32(-res));
+        }
+    }
+
+    /// Takes the first reply that couldn't be sent.
+    pub(crate) fn take_failed(&self) -> Option<Errno> {
+        self.failed.lock().unwrap().take()
+    }
+
+    /// Blocks until every reply has been answered or dropped.
+    pub(crate) fn wait(&self) {
+        let count = self.count.lock().unwrap();
+        drop(self.answered.wait_while(count, |count| *count > 0).unwrap());
+    }
+}
+
+/// The part every reply has in common: the request being answered.
+pub(crate) struct Reply {
+    req: fuse_req_t,
+    pending: Arc<Pending>,
+    /// Whether the request is answered with `fuse_reply_none`, which is
+    /// also what dropping it does.
+    none: bool,
+}
+
+// libfuse lets a request be answered from any thread, and all we ever do
+// with it is answer it.
+unsafe impl Send for Reply {}
+
+impl Reply {
+    pub(crate) fn new(req: fuse_req_t) -> Self {
+        Self::with_none(req, false)
+    }
+
+    fn with_none(req: fuse_req_t, none: bool) -> Self {
+        // The session's userdata starts with its pending replies.
+        let pending = unsafe { &*(crate::fuse_req_userdata(req) as *const Arc<Pending>) }.clone();
+        pending.add();
+        Self { req, pending, none }
+    }
+
+    /// Answers the request with `reply`, disarming the answer on drop.
+    fn answer(self, reply: impl FnOnce(fuse_req_t) -> i32) {
+        let this = ManuallyDrop::new(self);
+        let res = reply(this.req);
+        // Safe to move out, as `this` is never touched again.
+        let pending = unsafe { std::ptr::read(&this.pending) };
+        pending.answered(res);
+        pending.remove();
+    }
+
+    pub fn error(self, errno: Errno) {
+        self.answer(|req| unsafe { crate::fuse_reply_err(req, errno as i32) });
+    }
+
+    fn ok(self) {
+        self.answer(|req| unsafe { crate::fuse_reply_err(req, 0) });
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        let res = unsafe {
+            match self.none {
+                true => {
+                    crate::fuse_reply_none(self.req);
+                    0
+                }
+                false => crate::fuse_reply_err(self.req, Errno::EIO as i32),
+            }
+        };
+        self.pending.answered(res);
+        self.pending.remove();
+    }
+}
+
+fn entry_param(ttl: Duration, attr: &stat, generation: u64) -> fuse_entry_param {
+    fuse_entry_param {
+        ino: attr.st_ino as Ino,
+        generation,
+        attr: *attr,
+        attr_timeout: ttl.as_secs_f64(),
+        entry_timeout: ttl.as_secs_f64(),
+    }
+}
+
+macro_rules! reply_type {
+    ($(#[$meta:meta])* $name:ident { $($field:ident: $ty:ty),* }) => {
+        $(#[$meta])*
+        pub struct $name {
+            reply: Reply,
+            $($field: $ty,)*
+        }
+
+        impl $name {
+            pub(crate) fn new(req: fuse_req_t, $($field: $ty),*) -> Self {
+                Self {
+                    reply: Reply::new(req),
+                    $($field,)*
+                }
+            }
+
+            pub fn error(self, errno: Errno) {
+                self.reply.error(errno);
+            }
+        }
+    };
+}
+
+reply_type!(
+    /// Answers operations that only succeed or fail.
+    ReplyEmpty {}
+);
+
+impl ReplyEmpty {
+    pub fn ok(self) {
+        self.reply.ok();
+    }
+}
+
+/// Answers `forget` and `retrieve_reply`, which the kernel doesn't wait on.
+/// There's nothing to fail them with, so dropping one answers it the same
+/// way.
+pub struct ReplyNone {
+    reply: Reply,
+}
+
+impl ReplyNone {
+    pub(crate) fn new(req: fuse_req_t) -> Self {
+        Self {
+            reply: Reply::with_none(req, true),
+        }
+    }
+
+    pub fn none(self) {
+        self.reply.answer(|req| unsafe {
+            crate::fuse_reply_none(req);
+            0
+        });
+    }
+}
+
+reply_type!(
+    /// Answers operations that create or look up a directory entry.
+    ReplyEntry {}
+);
+
+impl ReplyEntry {
+    /// Replies with the entry's attributes, which the kernel may cache for `ttl`.
+    /// `attr.st_ino` is the entry's inode number.
+    pub fn entry(self, ttl: Duration, attr: &stat, generation: u64) {
+        let entry = entry_param(ttl, attr, generation);
+        self.reply
+            .answer(|req| unsafe { crate::fuse_reply_entry(req, &entry) });
+    }
+
+    /// Replies that the entry doesn't exist, letting the kernel cache that for `ttl`.
+    pub fn negative(self, ttl: Duration) {
+        let entry = fuse_entry_param {
+            entry_timeout: ttl.as_secs_f64(),
+            ..Default::default()
+        };
+        self.reply
+            .answer(|req| unsafe { crate::fuse_reply_entry(req, &entry) });
+    }
+}
+
+reply_type!(
+    /// Answers `getattr` and `setattr`.
+    ReplyAttr {}
+);
+
+impl ReplyAttr {
+    pub fn attr(self, ttl: Duration, attr: &stat) {
+        self.reply
+            .answer(|req| unsafe { crate::fuse_reply_attr(req, attr, ttl.as_secs_f64()) });
+    }
+}
+
+reply_type!(
+    /// Answers `readlink`.
+    ReplyReadlink {}
+);
+
+impl ReplyReadlink {
+    pub fn link(self, link: &OsStr) {
+        let link = CString::new(link.as_bytes()).unwrap();
+        self.reply
+            .answer(|req| unsafe { crate::fuse_reply_readlink(req, link.as_ptr()) });
+    }
+}
+
+reply_type!(
+    /// Answers `open` and `opendir`.
+    ReplyOpen { fi: fuse_file_info }
+);
+
+impl ReplyOpen {
+    /// The file info handed to `open`, for setting flags like `direct_io`
+    /// before replying.
+    pub fn info_mut(&mut self) -> &mut fuse_file_info {
+        &mut self.fi
+    }
+
+    /// Replies with the file handle later operations on this file will get.
+    pub fn opened(mut self, fh: u64) {
+        self.fi.fh = fh;
+        self.reply
+            .answer(|req| unsafe { crate::fuse_reply_open(req, &self.fi) });
+    }
+}
+
+reply_type!(
+    /// Answers `create`.
+    ReplyCreate { fi: fuse_file_info }
+);
+
+impl ReplyCreate {
+    pub fn info_mut(&mut self) -> &mut fuse_file_info {
+        &mut self.fi
+    }
+
+    pub fn created(mut self, ttl: Duration, attr: &stat, generation: u64, fh: u64) {
+        let entry = entry_param(ttl, attr, generation);
+        self.fi.fh = fh;
+        self.reply
+            .answer(|req| unsafe { crate::fuse_reply_create(req, &entry, &self.fi) });
+    }
+}
+
+reply_type!(
+    /// Answers `read`.
+    ReplyData {}
+);
+
+impl ReplyData {
+    pub fn data(self, data: &[u8]) {
+        self.reply.answer(|req| unsafe {
+            crate::fuse_reply_buf(req, data.as_ptr() as *const _, data.len())
+        });
+    }
+}
+
+reply_type!(
+    /// Answers `write`.
+    ReplyWrite {}
+);
+
+impl ReplyWrite {
+    pub fn written(self, count: usize) {
+        self.reply
+            .answer(|req| unsafe { crate::fuse_reply_write(req, count) });
+    }
+}
+
+reply_type!(
+    /// Answers `statfs`.
+    ReplyStatfs {}
+);
+
+impl ReplyStatfs {
+    pub fn statfs(self, stat: &statvfs) {
+        self.reply
+            .answer(|req| unsafe { crate::fuse_reply_statfs(req, stat) });
+    }
+}
+
+reply_type!(
+    /// Answers `getxattr` and `listxattr`.
+    ReplyXattr {}
+);
+
+impl ReplyXattr {
+    /// Replies to a request with a size of 0 with how big the buffer needs to be.
+    pub fn size(self, size: usize) {
+        self.reply
+            .answer(|req| unsafe { crate::fuse_reply_xattr(req, size) });
+    }
+
+    pub fn data(self, data: &[u8]) {
+        self.reply.answer(|req| unsafe {
+            crate::fuse_reply_buf(req, data.as_ptr() as *const _, data.len())
+        });
+    }
+}
+
+reply_type!(
+    /// Answers `readdir`, collecting entries until the kernel's buffer is full.
+    ReplyDirectory { buf: Vec<u8>, used: usize }
+);
+
+impl ReplyDirectory {
+    pub(crate) fn with_size(req: fuse_req_t, size: usize) -> Self {
+        Self::new(req, vec![0; size], 0)
+    }
+
+    /// Adds an entry, returning false once the buffer is full. `off` is the
+    /// offset of the *next* entry, and `mode` only needs the file type bits.
+    pub fn add(&mut self, ino: Ino, off: off_t, mode: mode_t, name: &OsStr) -> bool {
+        let name = CString::new(name.as_bytes()).unwrap();
+        let stat = stat {
+            st_ino: ino as _,
+            st_mode: mode,
+            ..Default::default()
+        };
+
+        let remaining = self.buf.len() - self.used;
+        let size = unsafe {
+            crate::fuse_add_direntry(
+                self.reply.req,
+                self.buf[self.used..].as_mut_ptr() as *mut _,
+                remaining,
+                name.as_ptr(),
+                &stat,
+                off,
+            )
+        };
+
+        if size > remaining {
+            return false;
+        }
+
+        self.used += size;
+        true
+    }
+
+    pub fn ok(self) {
+        self.reply.answer(|req| unsafe {
+            crate::fuse_reply_buf(req, self.buf.as_ptr() as *const _, self.used)
+        });
+    }
+}
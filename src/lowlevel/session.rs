@@ -0,0 +1,157 @@
+use super::{ops, reply::Pending, LowLevelFileSystem};
+use crate::{fuse_args, fuse_session, loop_config::LoopConfig, notify::Notifier};
+use std::{
+    ffi::{c_void, CString},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::Arc,
+};
+
+/// What libfuse holds as the session's userdata. Replies find the pending
+/// count at the start of it without knowing the filesystem's type.
+#[repr(C)]
+pub(crate) struct SessionData<F> {
+    pub(crate) pending: Arc<Pending>,
+    pub(crate) fs: F,
+}
+
+/// A low-level filesystem session, which owns the filesystem for as long as
+/// libfuse might call into it.
+pub struct Session<F> {
+    raw: *mut fuse_session,
+    mounted: bool,
+    notifier: Notifier,
+    // Boxed so the pointer libfuse holds as its userdata stays put.
+    data: Box<SessionData<F>>,
+}
+
+impl<F: LowLevelFileSystem> Session<F> {
+    /// Creates a session from fuse command line options (without the
+    /// mountpoint), e.g. `&["myfs", "-o", "allow_other"]`.
+    pub fn new(fs: F, fuse_args: &[&str]) -> io::Result<Self> {
+        let data = Box::new(SessionData {
+            pending: Arc::default(),
+            fs,
+        });
+        let operations = ops::operations::<F>();
+
+        let args_owned: Vec<_> = fuse_args
+            .iter()
+            .map(|s| CString::new(*s).unwrap())
+            .collect();
+        let mut argv: Vec<_> = args_owned.iter().map(|cs| cs.as_ptr()).collect();
+        let mut args = fuse_args {
+            argc: argv.len() as i32,
+            argv: argv.as_mut_ptr() as *mut *mut std::os::raw::c_char,
+            allocated: 0,
+        };
+
+        let raw = unsafe {
+            crate::fuse_session_new(
+                &mut args,
+                &operations,
+                std::mem::size_of_val(&operations),
+                &*data as *const SessionData<F> as *mut c_void,
+            )
+        };
+
+        // libfuse may have replaced argv with a copy of its own.
+        unsafe { crate::fuse_opt_free_args(&mut args) };
+
+        if raw.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Could not create fuse session",
+            ));
+        }
+
+        Ok(Self {
+            raw,
+            mounted: false,
+            notifier: Notifier::new(raw, std::ptr::null_mut()),
+            data,
+        })
+    }
+
+    pub fn mount(&mut self, mountpoint: impl AsRef<Path>) -> io::Result<()> {
+        let mountpoint = CString::new(mountpoint.as_ref().as_os_str().as_bytes()).unwrap();
+
+        match unsafe { crate::fuse_session_mount(self.raw, mountpoint.as_ptr()) } {
+            0 => {
+                self.mounted = true;
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Could not mount fuse session",
+            )),
+        }
+    }
+
+    /// Serves requests on the current thread until the filesystem is
+    /// unmounted or the process is signalled. Fails with the first reply
+    /// libfuse couldn't send, if nothing else went wrong.
+    pub fn run(&mut self) -> io::Result<()> {
+        self.with_signal_handlers(|raw| unsafe { crate::fuse_session_loop(raw) })
+    }
+
+    /// Serves requests on a pool of worker threads until the filesystem is
+    /// unmounted or the process is signalled. Fails like [`Session::run`].
+    pub fn run_mt(&mut self, mut config: LoopConfig) -> io::Result<()> {
+        self.with_signal_handlers(|raw| unsafe {
+            crate::fuse_session_loop_mt(raw, config.as_mut_ptr())
+        })
+    }
+
+    fn with_signal_handlers(
+        &mut self,
+        run: impl FnOnce(*mut fuse_session) -> i32,
+    ) -> io::Result<()> {
+        if unsafe { crate::fuse_set_signal_handlers(self.raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let out = run(self.raw);
+        unsafe { crate::fuse_remove_signal_handlers(self.raw) };
+
+        match out {
+            e if e < 0 => Err(io::Error::from_raw_os_error(-e)),
+            // Positive results mean we were stopped by a signal.
+            _ => match self.data.pending.take_failed() {
+                Some(errno) => Err(errno.into()),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// A handle for invalidating the kernel's caches while the session runs.
+    pub fn notifier(&self) -> Notifier {
+        self.notifier.clone()
+    }
+
+    /// Makes the event loop return after the request it's handling.
+    pub fn exit(&self) {
+        unsafe { crate::fuse_session_exit(self.raw) };
+    }
+}
+
+impl<F> Drop for Session<F> {
+    fn drop(&mut self) {
+        self.notifier.close();
+
+        unsafe {
+            if self.mounted {
+                crate::fuse_session_unmount(self.raw);
+            }
+        }
+
+        // Replies still out on other threads answer through the session, so
+        // it has to outlive them.
+        self.data.pending.wait();
+
+        unsafe {
+            crate::fuse_session_destroy(self.raw);
+        }
+    }
+}
@@ -0,0 +1,947 @@
+//! A filesystem kept entirely in memory, like tmpfs, for tests and scratch
+//! mounts.
+
+use crate::{
+    dev_t, dir_filler::DirFiller, fuse_config, fuse_conn_info, fuse_file_info, fuse_fill_dir_t,
+    fuse_readdir_flags, gid_t, mode_t, off_t, stat, statvfs, timespec, uid_t, FileSystem,
+};
+use anyhow::Result;
+use nix::errno::Errno;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{c_void, OsStr},
+    os::raw::c_uint,
+    sync::{Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+type Ino = u64;
+
+const ROOT_INO: Ino = 1;
+const BLOCK_SIZE: u64 = 4096;
+const NAME_MAX: usize = 255;
+
+enum Contents {
+    File(Vec<u8>),
+    Directory {
+        parent: Ino,
+        entries: BTreeMap<String, Ino>,
+    },
+    Symlink(String),
+    /// Device nodes, fifos and sockets, which only have attributes.
+    Special,
+}
+
+struct Inode {
+    mode: mode_t,
+    uid: uid_t,
+    gid: gid_t,
+    rdev: dev_t,
+    nlink: u64,
+    /// Open handles, which keep the inode alive after its last link goes.
+    open: u64,
+    atime: timespec,
+    mtime: timespec,
+    ctime: timespec,
+    xattrs: BTreeMap<String, Vec<u8>>,
+    contents: Contents,
+}
+
+impl Inode {
+    fn new(mode: mode_t, (uid, gid): (uid_t, gid_t), contents: Contents) -> Self {
+        let now = now();
+
+        Self {
+            mode,
+            uid,
+            gid,
+            rdev: 0,
+            nlink: 1,
+            open: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            xattrs: BTreeMap::new(),
+            contents,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match &self.contents {
+            Contents::File(data) => data.len() as u64,
+            Contents::Directory { entries, .. } => entries.len() as u64,
+            Contents::Symlink(target) => target.len() as u64,
+            Contents::Special => 0,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.contents, Contents::Directory { .. })
+    }
+
+    fn stat(&self, ino: Ino) -> stat {
+        let size = self.size();
+
+        stat {
+            st_ino: ino as _,
+            st_mode: self.mode,
+            st_nlink: self.nlink as _,
+            st_uid: self.uid,
+            st_gid: self.gid,
+            st_rdev: self.rdev,
+            st_size: size as _,
+            st_blksize: BLOCK_SIZE as _,
+            st_blocks: size.div_ceil(512) as _,
+            st_atim: self.atime,
+            st_mtim: self.mtime,
+            st_ctim: self.ctime,
+            ..Default::default()
+        }
+    }
+
+    /// Whether `uid`/`gid` may access the inode as `access(2)`'s `mask` asks.
+    fn permits(&self, uid: uid_t, gid: gid_t, mask: i32) -> bool {
+        let mask = (mask & 0o7) as mode_t;
+
+        if uid == 0 {
+            // Root only needs some execute bit to execute.
+            return mask & 0o1 == 0 || self.is_dir() || self.mode & 0o111 != 0;
+        }
+
+        let bits = if uid == self.uid {
+            self.mode >> 6
+        } else if gid == self.gid {
+            self.mode >> 3
+        } else {
+            self.mode
+        };
+
+        bits & mask == mask
+    }
+}
+
+fn now() -> timespec {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    timespec {
+        tv_sec: now.as_secs() as _,
+        tv_nsec: now.subsec_nanos() as _,
+    }
+}
+
+/// Who's making the request, and the umask to create files with.
+fn caller() -> (uid_t, gid_t, mode_t) {
+    // There's no libfuse context to ask when driven by the mock.
+    let context = match crate::mocked() {
+        true => None,
+        false => unsafe { crate::fuse_get_context().as_ref() },
+    };
+
+    match context {
+        Some(context) => (context.uid, context.gid, context.umask),
+        None => unsafe { (libc::geteuid(), libc::getegid(), 0) },
+    }
+}
+
+/// Splits `path` into its parent directory and final component.
+fn split(path: &str) -> Result<(&str, &str), Errno> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+
+    match name {
+        "" | "." | ".." => Err(Errno::EINVAL),
+        name if name.len() > NAME_MAX => Err(Errno::ENAMETOOLONG),
+        name => Ok((parent, name)),
+    }
+}
+
+struct Tree {
+    inodes: HashMap<Ino, Inode>,
+    next_ino: Ino,
+    /// Bytes of file data held, which is what the quota limits.
+    used: u64,
+}
+
+impl Tree {
+    fn inode(&self, ino: Ino) -> Result<&Inode, Errno> {
+        self.inodes.get(&ino).ok_or(Errno::ENOENT)
+    }
+
+    fn inode_mut(&mut self, ino: Ino) -> Result<&mut Inode, Errno> {
+        self.inodes.get_mut(&ino).ok_or(Errno::ENOENT)
+    }
+
+    fn entries(&self, ino: Ino) -> Result<&BTreeMap<String, Ino>, Errno> {
+        match &self.inode(ino)?.contents {
+            Contents::Directory { entries, .. } => Ok(entries),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn entries_mut(&mut self, ino: Ino) -> Result<&mut BTreeMap<String, Ino>, Errno> {
+        match &mut self.inode_mut(ino)?.contents {
+            Contents::Directory { entries, .. } => Ok(entries),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn lookup(&self, path: &str) -> Result<Ino, Errno> {
+        path.split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+            .try_fold(ROOT_INO, |ino, name| match name {
+                ".." => match self.inode(ino)?.contents {
+                    Contents::Directory { parent, .. } => Ok(parent),
+                    _ => Err(Errno::ENOTDIR),
+                },
+                name => self.entries(ino)?.get(name).copied().ok_or(Errno::ENOENT),
+            })
+    }
+
+    /// The inode an open file refers to, or failing that the one at `path`.
+    fn resolve(&self, path: &str, fi: &Option<&mut fuse_file_info>) -> Result<Ino, Errno> {
+        match fi {
+            Some(fi) if fi.fh != 0 => Ok(fi.fh),
+            _ => self.lookup(path),
+        }
+    }
+
+    fn touch(&mut self, ino: Ino) {
+        if let Ok(inode) = self.inode_mut(ino) {
+            let now = now();
+            inode.mtime = now;
+            inode.ctime = now;
+        }
+    }
+
+    /// Adds a new inode as `name` in `parent`.
+    fn create(&mut self, parent: Ino, name: &str, inode: Inode) -> Result<Ino, Errno> {
+        if self.entries(parent)?.contains_key(name) {
+            return Err(Errno::EEXIST);
+        }
+
+        let ino = self.next_ino;
+        self.next_ino += 1;
+
+        if inode.is_dir() {
+            self.inode_mut(parent)?.nlink += 1;
+        }
+        self.inodes.insert(ino, inode);
+        self.entries_mut(parent)?.insert(name.to_string(), ino);
+        self.touch(parent);
+
+        Ok(ino)
+    }
+
+    /// Drops a link to `ino`, freeing it once nothing refers to it.
+    fn unlink(&mut self, ino: Ino) {
+        let inode = match self.inodes.get_mut(&ino) {
+            Some(inode) => inode,
+            None => return,
+        };
+
+        // A directory's own "." link goes with its last name.
+        inode.nlink = match inode.contents {
+            Contents::Directory { .. } => 0,
+            _ => inode.nlink.saturating_sub(1),
+        };
+        inode.ctime = now();
+
+        self.release(ino);
+    }
+
+    fn release(&mut self, ino: Ino) {
+        if matches!(self.inodes.get(&ino), Some(inode) if inode.nlink == 0 && inode.open == 0) {
+            let inode = self.inodes.remove(&ino).unwrap();
+            if let Contents::File(data) = inode.contents {
+                self.used -= data.len() as u64;
+            }
+        }
+    }
+
+    /// Takes the entry `name` out of `parent`, updating link counts.
+    fn remove_entry(&mut self, parent: Ino, name: &str) -> Result<Ino, Errno> {
+        let ino = self
+            .entries_mut(parent)?
+            .remove(name)
+            .ok_or(Errno::ENOENT)?;
+
+        if self.inode(ino)?.is_dir() {
+            self.inode_mut(parent)?.nlink -= 1;
+        }
+        self.touch(parent);
+
+        Ok(ino)
+    }
+
+    fn resize(&mut self, ino: Ino, size: u64, quota: Option<u64>) -> Result<(), Errno> {
+        let used = self.used;
+        let inode = self.inode_mut(ino)?;
+
+        let data = match &mut inode.contents {
+            Contents::File(data) => data,
+            Contents::Directory { .. } => return Err(Errno::EISDIR),
+            _ => return Err(Errno::EINVAL),
+        };
+
+        let used = used - data.len() as u64 + size;
+        if matches!(quota, Some(quota) if used > quota) && size > data.len() as u64 {
+            return Err(Errno::ENOSPC);
+        }
+
+        data.resize(size as usize, 0);
+        let now = now();
+        inode.mtime = now;
+        inode.ctime = now;
+        self.used = used;
+
+        Ok(())
+    }
+}
+
+/// A thread-safe in-memory filesystem with directories, regular files,
+/// symlinks, hard links, device nodes and extended attributes.
+///
+/// Permissions are stored and reported, and checked by `access`. Mount with
+/// `-o default_permissions` to have the kernel enforce them everywhere else.
+pub struct MemFs {
+    tree: Mutex<Tree>,
+    quota: Option<u64>,
+}
+
+impl Default for MemFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemFs {
+    /// An empty filesystem, with a root directory owned by the current user.
+    pub fn new() -> Self {
+        let ids = unsafe { (libc::geteuid(), libc::getegid()) };
+        let mut root = Inode::new(
+            libc::S_IFDIR | 0o755,
+            ids,
+            Contents::Directory {
+                parent: ROOT_INO,
+                entries: BTreeMap::new(),
+            },
+        );
+        root.nlink = 2;
+
+        Self {
+            tree: Mutex::new(Tree {
+                inodes: HashMap::from([(ROOT_INO, root)]),
+                next_ino: ROOT_INO + 1,
+                used: 0,
+            }),
+            quota: None,
+        }
+    }
+
+    /// Limits how many bytes of file data can be stored, beyond which writes
+    /// fail with `ENOSPC`.
+    pub fn with_quota(mut self, bytes: u64) -> Self {
+        self.quota = Some(bytes);
+        self
+    }
+
+    /// How many bytes of file data are stored.
+    pub fn used(&self) -> u64 {
+        self.tree().used
+    }
+
+    fn tree(&self) -> MutexGuard<'_, Tree> {
+        self.tree.lock().unwrap()
+    }
+
+    /// Makes a node owned by the caller, entirely under one lock so it's
+    /// never seen half made. `contents` is handed the parent's inode.
+    fn create_node(
+        &self,
+        path: &str,
+        mode: mode_t,
+        nlink: u64,
+        rdev: dev_t,
+        contents: impl FnOnce(Ino) -> Contents,
+    ) -> Result<Ino, Errno> {
+        let (parent, name) = split(path)?;
+        let (uid, gid, umask) = caller();
+        let mode = match mode & libc::S_IFMT {
+            libc::S_IFLNK => mode,
+            _ => mode & !umask,
+        };
+
+        let mut tree = self.tree();
+        let parent = tree.lookup(parent)?;
+        let inode = Inode {
+            nlink,
+            rdev,
+            ..Inode::new(mode, (uid, gid), contents(parent))
+        };
+        tree.create(parent, name, inode)
+    }
+}
+
+impl FileSystem for MemFs {
+    fn init(&self, _conn: Option<&mut fuse_conn_info>, config: Option<&mut fuse_config>) {
+        if let Some(config) = config {
+            config.use_ino = 1;
+        }
+    }
+
+    fn getattr(
+        &self,
+        path: &str,
+        stat: Option<&mut stat>,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        let tree = self.tree();
+        let ino = tree.resolve(path, &fi)?;
+        *stat.unwrap() = tree.inode(ino)?.stat(ino);
+        Ok(0)
+    }
+
+    fn access(&self, path: &str, mask: i32) -> Result<i32> {
+        let (uid, gid, _) = caller();
+        let tree = self.tree();
+        let inode = tree.inode(tree.lookup(path)?)?;
+
+        match inode.permits(uid, gid, mask) {
+            true => Ok(0),
+            false => Err(Errno::EACCES.into()),
+        }
+    }
+
+    fn readlink(&self, path: &str, buf: &mut [u8]) -> Result<i32> {
+        let tree = self.tree();
+        let target = match &tree.inode(tree.lookup(path)?)?.contents {
+            Contents::Symlink(target) => target.as_bytes(),
+            _ => return Err(Errno::EINVAL.into()),
+        };
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let length = target.len().min(buf.len() - 1);
+        buf[..length].copy_from_slice(&target[..length]);
+        buf[length] = 0;
+
+        Ok(0)
+    }
+
+    fn mknod(&self, path: &str, mode: mode_t, rdev: dev_t) -> Result<i32> {
+        let contents = match mode & libc::S_IFMT {
+            libc::S_IFREG => Contents::File(vec![]),
+            libc::S_IFCHR | libc::S_IFBLK | libc::S_IFIFO | libc::S_IFSOCK => Contents::Special,
+            _ => return Err(Errno::EINVAL.into()),
+        };
+
+        self.create_node(path, mode, 1, rdev, |_| contents)?;
+        Ok(0)
+    }
+
+    fn mkdir(&self, path: &str, mode: mode_t) -> Result<i32> {
+        self.create_node(path, libc::S_IFDIR | (mode & 0o7777), 2, 0, |parent| {
+            Contents::Directory {
+                parent,
+                entries: BTreeMap::new(),
+            }
+        })?;
+        Ok(0)
+    }
+
+    fn unlink(&self, path: &str) -> Result<i32> {
+        let (parent, name) = split(path)?;
+        let mut tree = self.tree();
+        let parent = tree.lookup(parent)?;
+
+        let ino = *tree.entries(parent)?.get(name).ok_or(Errno::ENOENT)?;
+        if tree.inode(ino)?.is_dir() {
+            return Err(Errno::EISDIR.into());
+        }
+
+        tree.remove_entry(parent, name)?;
+        tree.unlink(ino);
+        Ok(0)
+    }
+
+    fn rmdir(&self, path: &str) -> Result<i32> {
+        let (parent, name) = split(path)?;
+        let mut tree = self.tree();
+        let parent = tree.lookup(parent)?;
+
+        let ino = *tree.entries(parent)?.get(name).ok_or(Errno::ENOENT)?;
+        if !tree.entries(ino)?.is_empty() {
+            return Err(Errno::ENOTEMPTY.into());
+        }
+
+        tree.remove_entry(parent, name)?;
+        tree.unlink(ino);
+        Ok(0)
+    }
+
+    fn symlink(&self, target: &str, path: &str) -> Result<i32> {
+        self.create_node(path, libc::S_IFLNK | 0o777, 1, 0, |_| {
+            Contents::Symlink(target.to_string())
+        })?;
+        Ok(0)
+    }
+
+    fn rename(&self, from: &str, to: &str, flags: c_uint) -> Result<i32> {
+        let ((from_parent, from_name), (to_parent, to_name)) = (split(from)?, split(to)?);
+        let mut tree = self.tree();
+        let (from_parent, to_parent) = (tree.lookup(from_parent)?, tree.lookup(to_parent)?);
+
+        let ino = *tree
+            .entries(from_parent)?
+            .get(from_name)
+            .ok_or(Errno::ENOENT)?;
+        let existing = tree.entries(to_parent)?.get(to_name).copied();
+        let is_dir = tree.inode(ino)?.is_dir();
+
+        // A directory can't be moved inside itself.
+        if is_dir && to.starts_with(&format!("{}/", from.trim_end_matches('/'))) {
+            return Err(Errno::EINVAL.into());
+        }
+
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            let other = existing.ok_or(Errno::ENOENT)?;
+            tree.entries_mut(from_parent)?
+                .insert(from_name.to_string(), other);
+            tree.entries_mut(to_parent)?
+                .insert(to_name.to_string(), ino);
+
+            for (ino, parent) in [(ino, to_parent), (other, from_parent)] {
+                if let Contents::Directory { parent: p, .. } = &mut tree.inode_mut(ino)?.contents {
+                    *p = parent;
+                }
+            }
+            if from_parent != to_parent {
+                let dirs = tree.inode(other)?.is_dir() as u64;
+                let is_dir = is_dir as u64;
+                tree.inode_mut(from_parent)?.nlink += dirs;
+                tree.inode_mut(from_parent)?.nlink -= is_dir;
+                tree.inode_mut(to_parent)?.nlink += is_dir;
+                tree.inode_mut(to_parent)?.nlink -= dirs;
+            }
+
+            tree.touch(from_parent);
+            tree.touch(to_parent);
+            return Ok(0);
+        }
+
+        if let Some(existing) = existing {
+            if flags & libc::RENAME_NOREPLACE != 0 {
+                return Err(Errno::EEXIST.into());
+            }
+            if existing == ino {
+                return Ok(0);
+            }
+
+            match (is_dir, tree.inode(existing)?.is_dir()) {
+                (true, true) if !tree.entries(existing)?.is_empty() => {
+                    return Err(Errno::ENOTEMPTY.into())
+                }
+                (true, false) => return Err(Errno::ENOTDIR.into()),
+                (false, true) => return Err(Errno::EISDIR.into()),
+                _ => {}
+            }
+
+            tree.remove_entry(to_parent, to_name)?;
+            tree.unlink(existing);
+        }
+
+        tree.remove_entry(from_parent, from_name)?;
+        tree.entries_mut(to_parent)?
+            .insert(to_name.to_string(), ino);
+        if is_dir {
+            tree.inode_mut(to_parent)?.nlink += 1;
+        }
+        tree.touch(to_parent);
+
+        let inode = tree.inode_mut(ino)?;
+        inode.ctime = now();
+        if let Contents::Directory { parent, .. } = &mut inode.contents {
+            *parent = to_parent;
+        }
+
+        Ok(0)
+    }
+
+    fn link(&self, from: &str, to: &str) -> Result<i32> {
+        let (parent, name) = split(to)?;
+        let mut tree = self.tree();
+        let ino = tree.lookup(from)?;
+        let parent = tree.lookup(parent)?;
+
+        if tree.inode(ino)?.is_dir() {
+            return Err(Errno::EPERM.into());
+        }
+        if tree.entries(parent)?.contains_key(name) {
+            return Err(Errno::EEXIST.into());
+        }
+
+        tree.entries_mut(parent)?.insert(name.to_string(), ino);
+        tree.touch(parent);
+
+        let inode = tree.inode_mut(ino)?;
+        inode.nlink += 1;
+        inode.ctime = now();
+        Ok(0)
+    }
+
+    fn chmod(&self, path: &str, mode: mode_t, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let mut tree = self.tree();
+        let ino = tree.resolve(path, &fi)?;
+        let inode = tree.inode_mut(ino)?;
+
+        inode.mode = (inode.mode & libc::S_IFMT) | (mode & !libc::S_IFMT);
+        inode.ctime = now();
+        Ok(0)
+    }
+
+    fn chown(
+        &self,
+        path: &str,
+        uid: uid_t,
+        gid: gid_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        let mut tree = self.tree();
+        let ino = tree.resolve(path, &fi)?;
+        let inode = tree.inode_mut(ino)?;
+
+        // -1 leaves the id as it is.
+        if uid != uid_t::MAX {
+            inode.uid = uid;
+        }
+        if gid != gid_t::MAX {
+            inode.gid = gid;
+        }
+        inode.ctime = now();
+        Ok(0)
+    }
+
+    fn truncate(&self, path: &str, size: off_t, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let mut tree = self.tree();
+        let ino = tree.resolve(path, &fi)?;
+        tree.resize(ino, size as u64, self.quota)?;
+        Ok(0)
+    }
+
+    fn utimens(
+        &self,
+        path: &str,
+        tv: Option<&[timespec; 2]>,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        let now = now();
+        let times = tv.copied().unwrap_or([now; 2]);
+
+        let mut tree = self.tree();
+        let ino = tree.resolve(path, &fi)?;
+        let inode = tree.inode_mut(ino)?;
+
+        for (time, new) in [(&mut inode.atime, times[0]), (&mut inode.mtime, times[1])] {
+            match new.tv_nsec {
+                libc::UTIME_OMIT => {}
+                libc::UTIME_NOW => *time = now,
+                _ => *time = new,
+            }
+        }
+        inode.ctime = now;
+
+        Ok(0)
+    }
+
+    fn open(&self, path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let fi = fi.unwrap();
+        let mut tree = self.tree();
+        let ino = tree.lookup(path)?;
+
+        if tree.inode(ino)?.is_dir() && fi.flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(Errno::EISDIR.into());
+        }
+        if fi.flags & libc::O_TRUNC != 0 {
+            tree.resize(ino, 0, self.quota)?;
+        }
+
+        tree.inode_mut(ino)?.open += 1;
+        fi.fh = ino;
+        Ok(0)
+    }
+
+    fn create(&self, path: &str, mode: mode_t, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let fi = fi.unwrap();
+        let created =
+            self.create_node(path, libc::S_IFREG | mode, 1, 0, |_| Contents::File(vec![]));
+        let ino = match created {
+            Ok(ino) => ino,
+            Err(Errno::EEXIST) if fi.flags & libc::O_EXCL == 0 => {
+                return self.open(path, Some(fi));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        self.tree().inode_mut(ino)?.open += 1;
+        fi.fh = ino;
+        Ok(0)
+    }
+
+    fn read(
+        &self,
+        path: &str,
+        buf: &mut [u8],
+        off: off_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        let mut tree = self.tree();
+        let ino = tree.resolve(path, &fi)?;
+        let inode = tree.inode_mut(ino)?;
+
+        let data = match &inode.contents {
+            Contents::File(data) => data,
+            Contents::Directory { .. } => return Err(Errno::EISDIR.into()),
+            _ => return Err(Errno::EINVAL.into()),
+        };
+
+        let start = (off as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        inode.atime = now();
+
+        Ok(n as i32)
+    }
+
+    fn write(
+        &self,
+        path: &str,
+        buf: &[u8],
+        off: off_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        let mut tree = self.tree();
+        let ino = tree.resolve(path, &fi)?;
+
+        let end = off as u64 + buf.len() as u64;
+        if end > tree.inode(ino)?.size() {
+            tree.resize(ino, end, self.quota)?;
+        }
+
+        let inode = tree.inode_mut(ino)?;
+        if let Contents::File(data) = &mut inode.contents {
+            data[off as usize..end as usize].copy_from_slice(buf);
+        }
+        let now = now();
+        inode.mtime = now;
+        inode.ctime = now;
+
+        Ok(buf.len() as i32)
+    }
+
+    fn statfs(&self, _path: &str, stat: Option<&mut statvfs>) -> Result<i32> {
+        let tree = self.tree();
+        let blocks = self
+            .quota
+            .map_or(u64::MAX / BLOCK_SIZE, |quota| quota / BLOCK_SIZE);
+        let free = blocks.saturating_sub(tree.used.div_ceil(BLOCK_SIZE));
+
+        *stat.unwrap() = statvfs {
+            f_bsize: BLOCK_SIZE as _,
+            f_frsize: BLOCK_SIZE as _,
+            f_blocks: blocks as _,
+            f_bfree: free as _,
+            f_bavail: free as _,
+            f_files: tree.inodes.len() as _,
+            f_ffree: (u32::MAX as usize - tree.inodes.len()) as _,
+            f_favail: (u32::MAX as usize - tree.inodes.len()) as _,
+            f_namemax: NAME_MAX as _,
+            ..Default::default()
+        };
+
+        Ok(0)
+    }
+
+    fn release(&self, _path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let ino = fi.unwrap().fh;
+        let mut tree = self.tree();
+
+        if let Ok(inode) = tree.inode_mut(ino) {
+            inode.open -= 1;
+        }
+        tree.release(ino);
+
+        Ok(0)
+    }
+
+    fn fsync(&self, _path: &str, _datasync: i32, _fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        Ok(0)
+    }
+
+    fn fallocate(
+        &self,
+        path: &str,
+        mode: i32,
+        off: off_t,
+        len: off_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        if mode != 0 {
+            return Err(Errno::EOPNOTSUPP.into());
+        }
+
+        let mut tree = self.tree();
+        let ino = tree.resolve(path, &fi)?;
+        let end = (off + len) as u64;
+
+        if end > tree.inode(ino)?.size() {
+            tree.resize(ino, end, self.quota)?;
+        }
+
+        Ok(0)
+    }
+
+    #[cfg(fuse_3_4)]
+    fn copy_file_range(
+        &self,
+        path_in: &str,
+        fi_in: Option<&mut fuse_file_info>,
+        off_in: off_t,
+        path_out: &str,
+        fi_out: Option<&mut fuse_file_info>,
+        off_out: off_t,
+        size: usize,
+        _flags: i32,
+    ) -> Result<i32> {
+        let available = {
+            let tree = self.tree();
+            let ino = tree.resolve(path_in, &fi_in)?;
+            tree.inode(ino)?.size().saturating_sub(off_in as u64)
+        };
+
+        let mut data = vec![0; size.min(available as usize).min(i32::MAX as usize)];
+        let n = self.read(path_in, &mut data, off_in, fi_in)?;
+        self.write(path_out, &data[..n as usize], off_out, fi_out)
+    }
+
+    fn setxattr(&self, path: &str, name: &str, value: &[u8], flags: i32) -> Result<i32> {
+        let mut tree = self.tree();
+        let ino = tree.lookup(path)?;
+        let inode = tree.inode_mut(ino)?;
+        let exists = inode.xattrs.contains_key(name);
+
+        if flags & libc::XATTR_CREATE != 0 && exists {
+            return Err(Errno::EEXIST.into());
+        }
+        if flags & libc::XATTR_REPLACE != 0 && !exists {
+            return Err(Errno::ENODATA.into());
+        }
+
+        inode.xattrs.insert(name.to_string(), value.to_vec());
+        inode.ctime = now();
+        Ok(0)
+    }
+
+    fn getxattr(&self, path: &str, name: &str, value: &mut [u8]) -> Result<i32> {
+        let tree = self.tree();
+        let inode = tree.inode(tree.lookup(path)?)?;
+        let data = inode.xattrs.get(name).ok_or(Errno::ENODATA)?;
+
+        copy_xattr(data, value)
+    }
+
+    fn listxattr(&self, path: &str, list: &mut [u8]) -> Result<i32> {
+        let tree = self.tree();
+        let inode = tree.inode(tree.lookup(path)?)?;
+
+        let mut names = vec![];
+        for name in inode.xattrs.keys() {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+
+        copy_xattr(&names, list)
+    }
+
+    fn removexattr(&self, path: &str, name: &str) -> Result<i32> {
+        let mut tree = self.tree();
+        let ino = tree.lookup(path)?;
+        let inode = tree.inode_mut(ino)?;
+
+        inode.xattrs.remove(name).ok_or(Errno::ENODATA)?;
+        inode.ctime = now();
+        Ok(0)
+    }
+
+    fn opendir(&self, path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let tree = self.tree();
+        let ino = tree.lookup(path)?;
+        tree.entries(ino)?;
+
+        fi.unwrap().fh = ino;
+        Ok(0)
+    }
+
+    fn readdir(
+        &self,
+        path: &str,
+        buf: Option<&mut c_void>,
+        filler: fuse_fill_dir_t,
+        off: off_t,
+        fi: Option<&mut fuse_file_info>,
+        _flags: fuse_readdir_flags,
+    ) -> Result<i32> {
+        let mut filler = match DirFiller::new(buf, filler) {
+            Some(filler) => filler,
+            None => return Ok(0),
+        };
+
+        let tree = self.tree();
+        let ino = tree.resolve(path, &fi)?;
+        let parent = match tree.inode(ino)?.contents {
+            Contents::Directory { parent, .. } => parent,
+            _ => return Err(Errno::ENOTDIR.into()),
+        };
+
+        let entries = [(".", ino), ("..", parent)].into_iter().chain(
+            tree.entries(ino)?
+                .iter()
+                .map(|(name, &ino)| (name.as_str(), ino)),
+        );
+
+        for (i, (name, ino)) in entries.enumerate().skip(off as usize) {
+            let stat = stat {
+                st_ino: ino as _,
+                st_mode: tree.inode(ino).map_or(libc::S_IFDIR, |inode| inode.mode),
+                ..Default::default()
+            };
+
+            if !filler.add(OsStr::new(name), Some(&stat), i as off_t + 1) {
+                break;
+            }
+        }
+
+        Ok(0)
+    }
+
+    fn releasedir(&self, _path: &str, _fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        Ok(0)
+    }
+}
+
+/// Answers `getxattr` and `listxattr`, which are asked for the size first
+/// when handed an empty buffer.
+fn copy_xattr(data: &[u8], buf: &mut [u8]) -> Result<i32> {
+    if buf.is_empty() {
+        return Ok(data.len() as i32);
+    }
+    if buf.len() < data.len() {
+        return Err(Errno::ERANGE.into());
+    }
+
+    buf[..data.len()].copy_from_slice(data);
+    Ok(data.len() as i32)
+}
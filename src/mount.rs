@@ -0,0 +1,130 @@
+//! A handle on a mounted high-level filesystem, for when
+//! [`FuseMain::run`](crate::FuseMain::run) handing the whole process over to
+//! libfuse isn't enough.
+
+use crate::{fuse, fuse_args, loop_config::LoopConfig, notify::Notifier, FileSystemRaw, UserData};
+use std::{
+    ffi::{c_void, CString},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+/// A mounted filesystem, which owns the filesystem for as long as libfuse
+/// might call into it. Dropping it unmounts.
+pub struct Mount<F> {
+    raw: *mut fuse,
+    unthreaded: bool,
+    notifier: Notifier,
+    // Boxed so the pointers libfuse holds stay put.
+    _user_data: Box<UserData<F>>,
+    _fs: Box<F>,
+}
+
+// libfuse only calls into the filesystem from the thread running the loop
+// (or its workers, for filesystems that are `Sync`).
+unsafe impl<F: Send> Send for Mount<F> {}
+
+impl<F> Mount<F> {
+    /// Mounts `fs` at `mountpoint`. `fuse_args` are fuse options without the
+    /// mountpoint or the command line flags, e.g. `&["myfs", "-o", "allow_other"]`.
+    pub fn new<const UNTHREADED: bool>(
+        fs: F,
+        mountpoint: impl AsRef<Path>,
+        fuse_args: &[&str],
+    ) -> io::Result<Self>
+    where
+        F: FileSystemRaw<UNTHREADED> + 'static,
+    {
+        let mut fs = Box::new(fs);
+        let mut user_data = Box::new(UserData::new(F::operations(), &mut *fs as *mut F));
+
+        let args_owned: Vec<_> = fuse_args
+            .iter()
+            .map(|s| CString::new(*s).unwrap())
+            .collect();
+        let mut argv: Vec<_> = args_owned.iter().map(|cs| cs.as_ptr()).collect();
+        let mut args = fuse_args {
+            argc: argv.len() as i32,
+            argv: argv.as_mut_ptr() as *mut *mut std::os::raw::c_char,
+            allocated: 0,
+        };
+
+        let raw = unsafe {
+            crate::fuse_new(
+                &mut args,
+                &user_data.ops,
+                std::mem::size_of_val(&user_data.ops),
+                &mut *user_data as *mut UserData<F> as *mut c_void,
+            )
+        };
+
+        // libfuse may have replaced argv with a copy of its own.
+        unsafe { crate::fuse_opt_free_args(&mut args) };
+
+        if raw.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Could not create fuse filesystem",
+            ));
+        }
+
+        let mountpoint = CString::new(mountpoint.as_ref().as_os_str().as_bytes()).unwrap();
+
+        if unsafe { crate::fuse_mount(raw, mountpoint.as_ptr()) } != 0 {
+            unsafe { crate::fuse_destroy(raw) };
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Could not mount fuse filesystem",
+            ));
+        }
+
+        Ok(Self {
+            raw,
+            unthreaded: UNTHREADED,
+            notifier: Notifier::new(unsafe { crate::fuse_get_session(raw) }, raw),
+            _user_data: user_data,
+            _fs: fs,
+        })
+    }
+
+    /// Serves requests until the filesystem is unmounted or [`Mount::exit`]
+    /// is called, on a pool of worker threads unless the filesystem is an
+    /// [`UnthreadedFileSystem`](crate::UnthreadedFileSystem).
+    pub fn run(&mut self) -> io::Result<()> {
+        let out = if self.unthreaded {
+            unsafe { crate::fuse_loop(self.raw) }
+        } else {
+            let mut config = LoopConfig::default();
+            unsafe { crate::fuse_loop_mt(self.raw, config.as_mut_ptr()) }
+        };
+
+        match out {
+            0 => Ok(()),
+            e if e < 0 => Err(io::Error::from_raw_os_error(-e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// A handle for invalidating the kernel's caches while the filesystem
+    /// is mounted.
+    pub fn notifier(&self) -> Notifier {
+        self.notifier.clone()
+    }
+
+    /// Makes the event loop return after the request it's handling.
+    pub fn exit(&self) {
+        unsafe { crate::fuse_exit(self.raw) };
+    }
+}
+
+impl<F> Drop for Mount<F> {
+    fn drop(&mut self) {
+        self.notifier.close();
+
+        unsafe {
+            crate::fuse_unmount(self.raw);
+            crate::fuse_destroy(self.raw);
+        }
+    }
+}
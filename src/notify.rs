@@ -0,0 +1,153 @@
+//! Telling the kernel its caches are stale, for filesystems whose contents
+//! change behind its back.
+
+use crate::{fuse, fuse_buf, fuse_bufvec, fuse_session, lowlevel::Ino, off_t};
+use std::{
+    ffi::{c_void, CString, OsStr},
+    io,
+    os::unix::ffi::OsStrExt,
+    sync::{Arc, RwLock},
+};
+
+struct Handles {
+    session: *mut fuse_session,
+    fuse: *mut fuse,
+}
+
+// libfuse's notification functions are safe to call from any thread, and
+// the handles are only used while the mount that owns them is alive.
+unsafe impl Send for Handles {}
+unsafe impl Sync for Handles {}
+
+/// A cloneable handle for invalidating the kernel's caches from any thread,
+/// obtained from the [`Session`](crate::lowlevel::Session) or
+/// [`Mount`](crate::mount::Mount) serving the filesystem.
+///
+/// Once the filesystem is unmounted every notification fails with `ENOTCONN`.
+/// Invalidating something the kernel doesn't have cached fails with `ENOENT`.
+#[derive(Clone)]
+pub struct Notifier {
+    handles: Arc<RwLock<Option<Handles>>>,
+}
+
+fn result(out: i32) -> io::Result<()> {
+    match out {
+        0 => Ok(()),
+        e => Err(io::Error::from_raw_os_error(-e)),
+    }
+}
+
+impl Notifier {
+    pub(crate) fn new(session: *mut fuse_session, fuse: *mut fuse) -> Self {
+        Self {
+            handles: Arc::new(RwLock::new(Some(Handles { session, fuse }))),
+        }
+    }
+
+    /// Called by the owner before tearing the session down. Waits for any
+    /// notifications in flight.
+    pub(crate) fn close(&self) {
+        *self.handles.write().unwrap() = None;
+    }
+
+    fn with<T>(&self, notify: impl FnOnce(&Handles) -> io::Result<T>) -> io::Result<T> {
+        match &*self.handles.read().unwrap() {
+            Some(handles) => notify(handles),
+            None => Err(io::Error::from_raw_os_error(libc::ENOTCONN)),
+        }
+    }
+
+    /// Drops the cached attributes of `ino`, along with its cached data in
+    /// `off..off + len`. A negative `off` only drops the attributes, and a
+    /// `len` of 0 means to the end of the file.
+    pub fn inval_inode(&self, ino: Ino, off: off_t, len: off_t) -> io::Result<()> {
+        self.with(|handles| {
+            result(unsafe {
+                crate::fuse_lowlevel_notify_inval_inode(handles.session, ino, off, len)
+            })
+        })
+    }
+
+    /// Drops the cached lookup of `name` in `parent`.
+    pub fn inval_entry(&self, parent: Ino, name: &OsStr) -> io::Result<()> {
+        self.with(|handles| {
+            result(unsafe {
+                crate::fuse_lowlevel_notify_inval_entry(
+                    handles.session,
+                    parent,
+                    name.as_bytes().as_ptr() as *const _,
+                    name.len(),
+                )
+            })
+        })
+    }
+
+    /// Tells the kernel `name` in `parent` (which was `child`) was deleted,
+    /// which unlike [`Notifier::inval_entry`] also updates open directory
+    /// listings and watchers.
+    pub fn delete(&self, parent: Ino, child: Ino, name: &OsStr) -> io::Result<()> {
+        self.with(|handles| {
+            result(unsafe {
+                crate::fuse_lowlevel_notify_delete(
+                    handles.session,
+                    parent,
+                    child,
+                    name.as_bytes().as_ptr() as *const _,
+                    name.len(),
+                )
+            })
+        })
+    }
+
+    /// Writes `data` into the kernel's cache of `ino` at `off`.
+    pub fn store(&self, ino: Ino, off: off_t, data: &[u8]) -> io::Result<()> {
+        let mut bufv = fuse_bufvec {
+            count: 1,
+            idx: 0,
+            off: 0,
+            buf: [fuse_buf {
+                size: data.len(),
+                mem: data.as_ptr() as *mut c_void,
+                fd: -1,
+                ..Default::default()
+            }],
+        };
+
+        self.with(|handles| {
+            result(unsafe {
+                crate::fuse_lowlevel_notify_store(handles.session, ino, off, &mut bufv, 0)
+            })
+        })
+    }
+
+    /// Asks the kernel for `size` bytes of its cache of `ino` at `off`. They
+    /// arrive through
+    /// [`LowLevelFileSystem::retrieve_reply`](crate::lowlevel::LowLevelFileSystem::retrieve_reply)
+    /// along with `cookie`.
+    pub fn retrieve(&self, ino: Ino, size: usize, off: off_t, cookie: u64) -> io::Result<()> {
+        self.with(|handles| {
+            result(unsafe {
+                crate::fuse_lowlevel_notify_retrieve(
+                    handles.session,
+                    ino,
+                    size,
+                    off,
+                    cookie as *mut c_void,
+                )
+            })
+        })
+    }
+
+    /// Drops the cached lookups and attributes for `path`. Only available for
+    /// filesystems mounted through the high-level API.
+    pub fn inval_path(&self, path: &str) -> io::Result<()> {
+        self.with(|handles| {
+            if handles.fuse.is_null() {
+                return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+            }
+
+            let path = CString::new(path).unwrap();
+            result(unsafe { crate::fuse_invalidate_path(handles.fuse, path.as_ptr()) })
+        })
+    }
+}
@@ -0,0 +1,923 @@
+//! A union of filesystems, like overlayfs: a writable upper layer over
+//! read-only lower layers.
+//!
+//! Files are copied up to the upper layer the first time they're changed.
+//! Deleting something the lower layers hold leaves a `.wh.<name>` whiteout
+//! file next to where it was, and a directory made where one was deleted is
+//! marked opaque with a `.wh..wh..opq` file, so that nothing below shows
+//! through. Both are hidden from the merged view.
+
+use crate::{
+    dev_t, dir_filler::DirFiller, errno, fuse_config, fuse_conn_info, fuse_file_info,
+    fuse_fill_dir_flags, fuse_fill_dir_t, fuse_readdir_flags, gid_t, mode_t, off_t, stat, statvfs,
+    timespec, uid_t, FileSystem,
+};
+use anyhow::Result;
+use nix::errno::Errno;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::{c_void, CStr, OsStr, OsString},
+    os::{
+        raw::{c_char, c_uint},
+        unix::ffi::OsStrExt,
+    },
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE: &str = ".wh..wh..opq";
+
+/// How much is read from a lower layer at a time when copying a file up.
+const COPY_CHUNK: usize = 128 * 1024;
+
+/// Layers are numbered from the top, so the upper layer is 0 and the first
+/// lower layer 1.
+const UPPER: usize = 0;
+
+/// Evaluates `$body` with `$fs` bound to layer `$layer`. The upper and
+/// lower layers have different types, so this can't be a method.
+macro_rules! on_layer {
+    ($self:expr, $layer:expr, |$fs:ident| $body:expr) => {
+        match $layer {
+            UPPER => {
+                let $fs = &$self.upper;
+                $body
+            }
+            lower => {
+                let $fs = &$self.lowers[lower - 1];
+                $body
+            }
+        }
+    };
+}
+
+fn call(out: Result<i32>) -> Result<i32, Errno> {
+    out.map_err(|e| errno(&e))
+}
+
+/// Like [`call`], but for operations a layer can do without.
+fn optional(out: Result<i32>) -> Result<(), Errno> {
+    match call(out) {
+        Ok(_) | Err(Errno::ENOSYS) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn is_dir(stat: &stat) -> bool {
+    stat.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+fn is_reserved(name: &[u8]) -> bool {
+    name.starts_with(WHITEOUT_PREFIX.as_bytes())
+}
+
+fn join(dir: &str, name: &str) -> String {
+    format!("{}/{name}", dir.trim_end_matches('/'))
+}
+
+/// Splits `path` into its parent directory and final component, refusing
+/// names that would be mistaken for whiteouts.
+fn split(path: &str) -> Result<(&str, &str), Errno> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+
+    match name {
+        "" | "." | ".." => Err(Errno::EINVAL),
+        name if is_reserved(name.as_bytes()) => Err(Errno::EPERM),
+        name => Ok((if parent.is_empty() { "/" } else { parent }, name)),
+    }
+}
+
+/// Where the whiteout hiding `path` goes.
+fn whiteout(path: &str) -> Result<String, Errno> {
+    let (parent, name) = split(path)?;
+    Ok(join(parent, &format!("{WHITEOUT_PREFIX}{name}")))
+}
+
+unsafe extern "C" fn collect_entries(
+    buf: *mut c_void,
+    name: *const c_char,
+    stat: *const stat,
+    _off: off_t,
+    _flags: fuse_fill_dir_flags,
+) -> i32 {
+    let entries = &mut *(buf as *mut Vec<(OsString, mode_t)>);
+    let name = OsStr::from_bytes(CStr::from_ptr(name).to_bytes()).to_owned();
+    entries.push((name, stat.as_ref().map_or(0, |stat| stat.st_mode)));
+    0
+}
+
+/// What the layers hold at a path.
+struct Entry {
+    /// Every layer with something at the path that isn't hidden by a
+    /// whiteout, from the top down, and what it has there.
+    layers: Vec<(usize, stat)>,
+    /// The layers whose directories make up the merged one, if the top of
+    /// `layers` is a directory.
+    merged: Vec<usize>,
+}
+
+impl Entry {
+    fn top(&self) -> (usize, &stat) {
+        let (layer, stat) = &self.layers[0];
+        (*layer, stat)
+    }
+
+    fn is_dir(&self) -> bool {
+        is_dir(self.top().1)
+    }
+
+    /// Whether removing the path from the upper layer would leave something
+    /// showing through, so it needs a whiteout.
+    fn in_lower(&self) -> bool {
+        self.layers.iter().any(|&(layer, _)| layer != UPPER)
+    }
+}
+
+enum Handle {
+    File {
+        layer: usize,
+        fi: fuse_file_info,
+    },
+    /// The merged listing, taken at `opendir`.
+    Dir(Vec<(OsString, mode_t)>),
+}
+
+/// Layers a writable `upper` filesystem over read-only `lowers`, which can
+/// be any filesystems built on [`FileSystem`].
+///
+/// The lower layers are only ever read from, so they can be shared with
+/// other mounts or wrapped in [`ReadOnly`](crate::adapters::ReadOnly).
+/// Renaming a directory the lower layers hold fails with `EXDEV`, as it
+/// does on overlayfs without `redirect_dir`, which `mv` handles by copying.
+pub struct Overlay<U, L> {
+    upper: U,
+    lowers: Vec<L>,
+    handles: Mutex<HashMap<u64, Handle>>,
+    next_handle: AtomicU64,
+    /// Held while copying up, so two writers don't both copy the same file.
+    copying: Mutex<()>,
+}
+
+impl<U: FileSystem, L: FileSystem> Overlay<U, L> {
+    /// Layers `upper` over `lowers`, which are listed from the top down.
+    pub fn new(upper: U, lowers: impl IntoIterator<Item = L>) -> Self {
+        Self {
+            upper,
+            lowers: lowers.into_iter().collect(),
+            handles: Mutex::new(HashMap::new()),
+            next_handle: AtomicU64::new(1),
+            copying: Mutex::new(()),
+        }
+    }
+
+    pub fn upper(&self) -> &U {
+        &self.upper
+    }
+
+    pub fn lowers(&self) -> &[L] {
+        &self.lowers
+    }
+
+    pub fn into_layers(self) -> (U, Vec<L>) {
+        (self.upper, self.lowers)
+    }
+
+    fn stat(&self, layer: usize, path: &str) -> Result<stat, Errno> {
+        let mut stat = stat::default();
+        call(on_layer!(self, layer, |fs| fs.getattr(
+            path,
+            Some(&mut stat),
+            None
+        )))?;
+        Ok(stat)
+    }
+
+    fn exists(&self, layer: usize, path: &str) -> bool {
+        self.stat(layer, path).is_ok()
+    }
+
+    /// The entries of the directory at `path` in a single layer.
+    fn list(&self, layer: usize, path: &str) -> Result<Vec<(OsString, mode_t)>, Errno> {
+        let mut fi = fuse_file_info::default();
+        let mut entries: Vec<(OsString, mode_t)> = vec![];
+        let buf = &mut entries as *mut Vec<(OsString, mode_t)> as *mut c_void;
+
+        optional(on_layer!(self, layer, |fs| fs.opendir(path, Some(&mut fi))))?;
+        let read = call(on_layer!(self, layer, |fs| fs.readdir(
+            path,
+            Some(unsafe { &mut *buf }),
+            Some(collect_entries),
+            0,
+            Some(&mut fi),
+            0,
+        )));
+        optional(on_layer!(self, layer, |fs| fs.releasedir(path, Some(&mut fi))))?;
+        read?;
+
+        Ok(entries)
+    }
+
+    /// The layers making up the directory at `path`, stopping at the first
+    /// one that isn't a directory or is opaque.
+    fn merged(&self, path: &str, layers: &[(usize, stat)]) -> Vec<usize> {
+        let mut merged = vec![];
+
+        for (layer, stat) in layers {
+            if !is_dir(stat) {
+                break;
+            }
+            merged.push(*layer);
+            if self.exists(*layer, &join(path, OPAQUE)) {
+                break;
+            }
+        }
+
+        merged
+    }
+
+    fn resolve(&self, path: &str) -> Result<Entry, Errno> {
+        let layers: Vec<_> = (0..=self.lowers.len())
+            .filter_map(|layer| Some((layer, self.stat(layer, "/").ok()?)))
+            .collect();
+        let mut entry = Entry {
+            merged: self.merged("/", &layers),
+            layers,
+        };
+        let mut current = String::from("/");
+
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if entry.merged.is_empty() {
+                return Err(Errno::ENOTDIR);
+            }
+            if is_reserved(name.as_bytes()) {
+                return Err(Errno::ENOENT);
+            }
+
+            let child = join(&current, name);
+            let mut layers = vec![];
+
+            for &layer in &entry.merged {
+                match self.stat(layer, &child) {
+                    Ok(stat) => layers.push((layer, stat)),
+                    Err(Errno::ENOENT | Errno::ENOTDIR) => {}
+                    Err(e) => return Err(e),
+                }
+                if self.exists(layer, &join(&current, &format!("{WHITEOUT_PREFIX}{name}"))) {
+                    break;
+                }
+            }
+
+            if layers.is_empty() {
+                return Err(Errno::ENOENT);
+            }
+
+            entry = Entry {
+                merged: self.merged(&child, &layers),
+                layers,
+            };
+            current = child;
+        }
+
+        Ok(entry)
+    }
+
+    /// The merged listing of a directory, without `.` and `..`.
+    fn read_merged(&self, path: &str, merged: &[usize]) -> Result<Vec<(OsString, mode_t)>, Errno> {
+        let mut entries = BTreeMap::new();
+        let mut hidden = HashSet::new();
+
+        for &layer in merged {
+            let mut whiteouts = vec![];
+
+            for (name, mode) in self.list(layer, path)? {
+                let bytes = name.as_bytes();
+
+                if bytes == b"." || bytes == b".." || hidden.contains(&name) {
+                    continue;
+                }
+                if is_reserved(bytes) {
+                    if bytes != OPAQUE.as_bytes() {
+                        whiteouts
+                            .push(OsStr::from_bytes(&bytes[WHITEOUT_PREFIX.len()..]).to_owned());
+                    }
+                    continue;
+                }
+
+                entries.entry(name).or_insert(mode);
+            }
+
+            // A whiteout only hides what's below it.
+            hidden.extend(whiteouts);
+        }
+
+        Ok(entries.into_iter().collect())
+    }
+
+    fn readlink_in(&self, layer: usize, path: &str) -> Result<String, Errno> {
+        let mut buf = vec![0; libc::PATH_MAX as usize];
+        call(on_layer!(self, layer, |fs| fs.readlink(path, &mut buf)))?;
+
+        let length = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        Ok(String::from_utf8_lossy(&buf[..length]).into_owned())
+    }
+
+    /// Makes sure `path` is in the upper layer, copying it and any missing
+    /// parent directories up from below.
+    fn copy_up(&self, path: &str) -> Result<Entry, Errno> {
+        let _copying = self.copying.lock().unwrap();
+        self.copy_up_locked(path)
+    }
+
+    fn copy_up_locked(&self, path: &str) -> Result<Entry, Errno> {
+        let entry = self.resolve(path)?;
+        let (layer, stat) = entry.top();
+        if layer == UPPER {
+            return Ok(entry);
+        }
+
+        self.copy_up_locked(split(path)?.0)?;
+
+        match stat.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => {
+                call(self.upper.mkdir(path, stat.st_mode & 0o7777))?;
+            }
+            libc::S_IFLNK => {
+                call(self.upper.symlink(&self.readlink_in(layer, path)?, path))?;
+            }
+            libc::S_IFREG => self.copy_file(layer, path, stat.st_mode)?,
+            _ => {
+                call(self.upper.mknod(path, stat.st_mode, stat.st_rdev))?;
+            }
+        }
+
+        self.copy_metadata(layer, path, stat);
+        self.resolve(path)
+    }
+
+    fn copy_file(&self, layer: usize, path: &str, mode: mode_t) -> Result<(), Errno> {
+        let mut dst = fuse_file_info {
+            flags: libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+            ..Default::default()
+        };
+
+        match call(self.upper.create(path, mode & 0o7777, Some(&mut dst))) {
+            Err(Errno::ENOSYS) => {
+                call(self.upper.mknod(path, libc::S_IFREG | (mode & 0o7777), 0))?;
+                call(self.upper.open(path, Some(&mut dst)))?;
+            }
+            out => {
+                out?;
+            }
+        }
+
+        let mut src = fuse_file_info {
+            flags: libc::O_RDONLY,
+            ..Default::default()
+        };
+        let copied =
+            call(on_layer!(self, layer, |fs| fs.open(path, Some(&mut src)))).and_then(|_| {
+                let copied = self.copy_data(layer, path, &mut src, &mut dst);
+                let _ = on_layer!(self, layer, |fs| fs.release(path, Some(&mut src)));
+                copied
+            });
+
+        let _ = self.upper.release(path, Some(&mut dst));
+
+        // A partial copy would hide the rest of the lower file.
+        if copied.is_err() {
+            let _ = self.upper.unlink(path);
+        }
+        copied
+    }
+
+    fn copy_data(
+        &self,
+        layer: usize,
+        path: &str,
+        src: &mut fuse_file_info,
+        dst: &mut fuse_file_info,
+    ) -> Result<(), Errno> {
+        let mut buf = vec![0; COPY_CHUNK];
+        let mut off = 0;
+
+        loop {
+            let n = call(on_layer!(self, layer, |fs| fs.read(
+                path,
+                &mut buf,
+                off,
+                Some(&mut *src)
+            )))? as usize;
+            if n == 0 {
+                return Ok(());
+            }
+
+            let mut written = 0;
+            while written < n {
+                let chunk = &buf[written..n];
+                let at = off + written as off_t;
+                written += call(self.upper.write(path, chunk, at, Some(&mut *dst)))? as usize;
+            }
+            off += n as off_t;
+        }
+    }
+
+    /// Carries over what it can of the original's attributes. Anything that
+    /// fails, like `chown` when not root, is left as created.
+    fn copy_metadata(&self, layer: usize, path: &str, stat: &stat) {
+        // These would follow the link on some filesystems.
+        if stat.st_mode & libc::S_IFMT == libc::S_IFLNK {
+            return;
+        }
+
+        let _ = self.upper.chmod(path, stat.st_mode & 0o7777, None);
+        let _ = self.upper.chown(path, stat.st_uid, stat.st_gid, None);
+
+        let times = [stat.st_atim, stat.st_mtim];
+        let _ = self.upper.utimens(path, Some(&times), None);
+
+        let mut names = vec![0; 64 * 1024];
+        let length = match call(on_layer!(self, layer, |fs| fs.listxattr(path, &mut names))) {
+            Ok(length) => length as usize,
+            Err(_) => return,
+        };
+
+        let mut value = vec![0; 64 * 1024];
+        for name in names[..length]
+            .split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+        {
+            let name = String::from_utf8_lossy(name);
+            if let Ok(n) = call(on_layer!(self, layer, |fs| fs.getxattr(path, &name, &mut value))) {
+                let _ = self.upper.setxattr(path, &name, &value[..n as usize], 0);
+            }
+        }
+    }
+
+    /// Gets ready to make something at `path` in the upper layer, returning
+    /// whether a whiteout had to be removed to do it.
+    fn prepare(&self, path: &str) -> Result<bool, Errno> {
+        let (parent, _) = split(path)?;
+
+        match self.resolve(path) {
+            Ok(_) => return Err(Errno::EEXIST),
+            Err(Errno::ENOENT) => {}
+            Err(e) => return Err(e),
+        }
+        if !self.copy_up(parent)?.is_dir() {
+            return Err(Errno::ENOTDIR);
+        }
+
+        let whiteout = whiteout(path)?;
+        let whited_out = self.exists(UPPER, &whiteout);
+        if whited_out {
+            call(self.upper.unlink(&whiteout))?;
+        }
+
+        Ok(whited_out)
+    }
+
+    fn hide(&self, path: &str) -> Result<(), Errno> {
+        call(self.upper.mknod(&whiteout(path)?, libc::S_IFREG | 0o600, 0))?;
+        Ok(())
+    }
+
+    fn make_opaque(&self, path: &str) -> Result<(), Errno> {
+        match call(
+            self.upper
+                .mknod(&join(path, OPAQUE), libc::S_IFREG | 0o600, 0),
+        ) {
+            Ok(_) | Err(Errno::EEXIST) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes the whiteouts from an upper directory whose merged view is
+    /// empty, so it can be removed or replaced.
+    fn clear_whiteouts(&self, path: &str) -> Result<(), Errno> {
+        for (name, _) in self.list(UPPER, path)? {
+            if is_reserved(name.as_bytes()) {
+                call(self.upper.unlink(&join(path, &name.to_string_lossy())))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, path: &str, dir: bool) -> Result<i32, Errno> {
+        let (parent, _) = split(path)?;
+        let entry = self.resolve(path)?;
+
+        match (dir, entry.is_dir()) {
+            (false, true) => return Err(Errno::EISDIR),
+            (true, false) => return Err(Errno::ENOTDIR),
+            _ => {}
+        }
+        if dir && !self.read_merged(path, &entry.merged)?.is_empty() {
+            return Err(Errno::ENOTEMPTY);
+        }
+
+        if entry.top().0 == UPPER {
+            if dir {
+                self.clear_whiteouts(path)?;
+                call(self.upper.rmdir(path))?;
+            } else {
+                call(self.upper.unlink(path))?;
+            }
+        }
+        if entry.in_lower() {
+            self.copy_up(parent)?;
+            self.hide(path)?;
+        }
+
+        Ok(0)
+    }
+
+    fn add_handle(&self, fi: &mut fuse_file_info, handle: Handle) {
+        let fh = self.next_handle.fetch_add(1, Ordering::Relaxed);
+
+        if let Handle::File { fi: inner, .. } = &handle {
+            *fi = fuse_file_info { fh, ..*inner };
+        } else {
+            fi.fh = fh;
+        }
+        self.handles.lock().unwrap().insert(fh, handle);
+    }
+
+    /// The layer an open file is in, and the file info that layer gave it.
+    fn file(&self, fi: &Option<&mut fuse_file_info>) -> Option<(usize, fuse_file_info)> {
+        match self.handles.lock().unwrap().get(&fi.as_ref()?.fh)? {
+            Handle::File { layer, fi } => Some((*layer, *fi)),
+            Handle::Dir(_) => None,
+        }
+    }
+
+    /// Copies `path` up to change it, along with the upper layer's file info
+    /// if it's open there.
+    fn upper_file(
+        &self,
+        path: &str,
+        fi: &Option<&mut fuse_file_info>,
+    ) -> Result<Option<fuse_file_info>, Errno> {
+        self.copy_up(path)?;
+
+        Ok(match self.file(fi) {
+            Some((UPPER, fi)) => Some(fi),
+            _ => None,
+        })
+    }
+}
+
+impl<U: FileSystem, L: FileSystem> FileSystem for Overlay<U, L> {
+    fn init(&self, mut conn: Option<&mut fuse_conn_info>, mut config: Option<&mut fuse_config>) {
+        self.upper.init(conn.as_deref_mut(), config.as_deref_mut());
+        for lower in &self.lowers {
+            lower.init(conn.as_deref_mut(), config.as_deref_mut());
+        }
+
+        // The layers' inode numbers would collide.
+        if let Some(config) = config {
+            config.use_ino = 0;
+        }
+    }
+
+    fn getattr(
+        &self,
+        path: &str,
+        stat: Option<&mut stat>,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        if let Some((layer, mut inner)) = self.file(&fi) {
+            return on_layer!(self, layer, |fs| fs.getattr(path, stat, Some(&mut inner)));
+        }
+
+        *stat.unwrap() = *self.resolve(path)?.top().1;
+        Ok(0)
+    }
+
+    fn access(&self, path: &str, mask: i32) -> Result<i32> {
+        let layer = self.resolve(path)?.top().0;
+
+        // Writing to a lower file copies it up first.
+        match layer {
+            UPPER => self.upper.access(path, mask),
+            _ => on_layer!(self, layer, |fs| fs.access(path, mask & !libc::W_OK)),
+        }
+    }
+
+    fn readlink(&self, path: &str, buf: &mut [u8]) -> Result<i32> {
+        let layer = self.resolve(path)?.top().0;
+        on_layer!(self, layer, |fs| fs.readlink(path, buf))
+    }
+
+    fn mknod(&self, path: &str, mode: mode_t, rdev: dev_t) -> Result<i32> {
+        self.prepare(path)?;
+        self.upper.mknod(path, mode, rdev)
+    }
+
+    fn mkdir(&self, path: &str, mode: mode_t) -> Result<i32> {
+        let whited_out = self.prepare(path)?;
+        self.upper.mkdir(path, mode)?;
+
+        if whited_out {
+            self.make_opaque(path)?;
+        }
+        Ok(0)
+    }
+
+    fn unlink(&self, path: &str) -> Result<i32> {
+        Ok(self.remove(path, false)?)
+    }
+
+    fn rmdir(&self, path: &str) -> Result<i32> {
+        Ok(self.remove(path, true)?)
+    }
+
+    fn symlink(&self, target: &str, path: &str) -> Result<i32> {
+        self.prepare(path)?;
+        self.upper.symlink(target, path)
+    }
+
+    fn rename(&self, from: &str, to: &str, flags: c_uint) -> Result<i32> {
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            return Err(Errno::EINVAL.into());
+        }
+
+        let source = self.resolve(from)?;
+        let (to_parent, _) = split(to)?;
+
+        if source.is_dir() && source.in_lower() {
+            return Err(Errno::EXDEV.into());
+        }
+        if from == to {
+            return Ok(0);
+        }
+
+        let opaque = match self.resolve(to) {
+            Ok(target) => {
+                if flags & libc::RENAME_NOREPLACE != 0 {
+                    return Err(Errno::EEXIST.into());
+                }
+                match (source.is_dir(), target.is_dir()) {
+                    (true, false) => return Err(Errno::ENOTDIR.into()),
+                    (false, true) => return Err(Errno::EISDIR.into()),
+                    (true, true) if !self.read_merged(to, &target.merged)?.is_empty() => {
+                        return Err(Errno::ENOTEMPTY.into())
+                    }
+                    _ => {}
+                }
+
+                if target.is_dir() && target.top().0 == UPPER {
+                    self.clear_whiteouts(to)?;
+                }
+                self.copy_up(to_parent)?;
+
+                // The lower directory being replaced mustn't show through.
+                target.is_dir() && target.in_lower()
+            }
+            Err(Errno::ENOENT) => self.prepare(to)?,
+            Err(e) => return Err(e.into()),
+        };
+
+        self.copy_up(from)?;
+        self.upper.rename(from, to, flags)?;
+
+        if source.is_dir() && opaque {
+            self.make_opaque(to)?;
+        }
+        if source.in_lower() {
+            self.hide(from)?;
+        }
+
+        Ok(0)
+    }
+
+    fn link(&self, from: &str, to: &str) -> Result<i32> {
+        if self.copy_up(from)?.is_dir() {
+            return Err(Errno::EPERM.into());
+        }
+
+        self.prepare(to)?;
+        self.upper.link(from, to)
+    }
+
+    fn chmod(&self, path: &str, mode: mode_t, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let mut inner = self.upper_file(path, &fi)?;
+        self.upper.chmod(path, mode, inner.as_mut())
+    }
+
+    fn chown(
+        &self,
+        path: &str,
+        uid: uid_t,
+        gid: gid_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        let mut inner = self.upper_file(path, &fi)?;
+        self.upper.chown(path, uid, gid, inner.as_mut())
+    }
+
+    fn truncate(&self, path: &str, size: off_t, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let mut inner = self.upper_file(path, &fi)?;
+        self.upper.truncate(path, size, inner.as_mut())
+    }
+
+    fn utimens(
+        &self,
+        path: &str,
+        tv: Option<&[timespec; 2]>,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        let mut inner = self.upper_file(path, &fi)?;
+        self.upper.utimens(path, tv, inner.as_mut())
+    }
+
+    fn open(&self, path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let fi = fi.unwrap();
+        let writes = fi.flags & libc::O_ACCMODE != libc::O_RDONLY || fi.flags & libc::O_TRUNC != 0;
+
+        let layer = match writes {
+            true => self.copy_up(path)?.top().0,
+            false => self.resolve(path)?.top().0,
+        };
+
+        let mut inner = *fi;
+        on_layer!(self, layer, |fs| fs.open(path, Some(&mut inner)))?;
+        self.add_handle(fi, Handle::File { layer, fi: inner });
+        Ok(0)
+    }
+
+    fn create(&self, path: &str, mode: mode_t, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let fi = fi.unwrap();
+
+        match self.resolve(path) {
+            Ok(_) if fi.flags & libc::O_EXCL != 0 => return Err(Errno::EEXIST.into()),
+            Ok(_) => return self.open(path, Some(fi)),
+            Err(Errno::ENOENT) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        self.prepare(path)?;
+
+        let mut inner = *fi;
+        self.upper.create(path, mode, Some(&mut inner))?;
+        self.add_handle(
+            fi,
+            Handle::File {
+                layer: UPPER,
+                fi: inner,
+            },
+        );
+        Ok(0)
+    }
+
+    fn read(
+        &self,
+        path: &str,
+        buf: &mut [u8],
+        off: off_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        let (layer, mut inner) = self.file(&fi).ok_or(Errno::EBADF)?;
+        on_layer!(self, layer, |fs| fs.read(path, buf, off, Some(&mut inner)))
+    }
+
+    fn write(
+        &self,
+        path: &str,
+        buf: &[u8],
+        off: off_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        match self.file(&fi) {
+            Some((UPPER, mut inner)) => self.upper.write(path, buf, off, Some(&mut inner)),
+            _ => Err(Errno::EBADF.into()),
+        }
+    }
+
+    fn statfs(&self, path: &str, stat: Option<&mut statvfs>) -> Result<i32> {
+        self.upper.statfs(path, stat)
+    }
+
+    fn flush(&self, path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let (layer, mut inner) = self.file(&fi).ok_or(Errno::EBADF)?;
+        on_layer!(self, layer, |fs| fs.flush(path, Some(&mut inner)))
+    }
+
+    fn release(&self, path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let fh = fi.ok_or(Errno::EBADF)?.fh;
+
+        match self.handles.lock().unwrap().remove(&fh) {
+            Some(Handle::File { layer, mut fi }) => {
+                on_layer!(self, layer, |fs| fs.release(path, Some(&mut fi)))
+            }
+            _ => Err(Errno::EBADF.into()),
+        }
+    }
+
+    fn fsync(&self, path: &str, datasync: i32, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let (layer, mut inner) = self.file(&fi).ok_or(Errno::EBADF)?;
+        on_layer!(self, layer, |fs| fs.fsync(path, datasync, Some(&mut inner)))
+    }
+
+    fn fallocate(
+        &self,
+        path: &str,
+        mode: i32,
+        off: off_t,
+        len: off_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        let mut inner = self.upper_file(path, &fi)?;
+        self.upper.fallocate(path, mode, off, len, inner.as_mut())
+    }
+
+    fn setxattr(&self, path: &str, name: &str, value: &[u8], flags: i32) -> Result<i32> {
+        self.copy_up(path)?;
+        self.upper.setxattr(path, name, value, flags)
+    }
+
+    fn getxattr(&self, path: &str, name: &str, value: &mut [u8]) -> Result<i32> {
+        let layer = self.resolve(path)?.top().0;
+        on_layer!(self, layer, |fs| fs.getxattr(path, name, value))
+    }
+
+    fn listxattr(&self, path: &str, list: &mut [u8]) -> Result<i32> {
+        let layer = self.resolve(path)?.top().0;
+        on_layer!(self, layer, |fs| fs.listxattr(path, list))
+    }
+
+    fn removexattr(&self, path: &str, name: &str) -> Result<i32> {
+        self.copy_up(path)?;
+        self.upper.removexattr(path, name)
+    }
+
+    fn opendir(&self, path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let entry = self.resolve(path)?;
+        if !entry.is_dir() {
+            return Err(Errno::ENOTDIR.into());
+        }
+
+        let entries = self.read_merged(path, &entry.merged)?;
+        self.add_handle(fi.unwrap(), Handle::Dir(entries));
+        Ok(0)
+    }
+
+    fn readdir(
+        &self,
+        path: &str,
+        buf: Option<&mut c_void>,
+        filler: fuse_fill_dir_t,
+        off: off_t,
+        fi: Option<&mut fuse_file_info>,
+        _flags: fuse_readdir_flags,
+    ) -> Result<i32> {
+        let mut filler = match DirFiller::new(buf, filler) {
+            Some(filler) => filler,
+            None => return Ok(0),
+        };
+
+        let opened = fi.and_then(|fi| match self.handles.lock().unwrap().get(&fi.fh) {
+            Some(Handle::Dir(entries)) => Some(entries.clone()),
+            _ => None,
+        });
+        let entries = match opened {
+            Some(entries) => entries,
+            None => {
+                let entry = self.resolve(path)?;
+                if !entry.is_dir() {
+                    return Err(Errno::ENOTDIR.into());
+                }
+                self.read_merged(path, &entry.merged)?
+            }
+        };
+
+        let dots = [(".", libc::S_IFDIR), ("..", libc::S_IFDIR)]
+            .map(|(name, mode)| (OsStr::new(name), mode));
+        let entries = dots
+            .into_iter()
+            .chain(entries.iter().map(|(name, mode)| (name.as_os_str(), *mode)));
+
+        for (i, (name, mode)) in entries.enumerate().skip(off as usize) {
+            let stat = stat {
+                st_mode: mode,
+                ..Default::default()
+            };
+
+            if !filler.add(name, Some(&stat), i as off_t + 1) {
+                break;
+            }
+        }
+
+        Ok(0)
+    }
+
+    fn releasedir(&self, _path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        if let Some(fi) = fi {
+            self.handles.lock().unwrap().remove(&fi.fh);
+        }
+        Ok(0)
+    }
+}
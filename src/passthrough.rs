@@ -0,0 +1,436 @@
+//! A filesystem mirroring a directory on another filesystem, for wrapping
+//! or extending instead of starting from scratch.
+
+use crate::{
+    dir_filler::DirFiller, fuse_config, fuse_conn_info, fuse_file_info, fuse_fill_dir_t,
+    fuse_readdir_flags, gid_t, mode_t, off_t, stat, statvfs, timespec, uid_t, FileSystem,
+};
+use anyhow::Result;
+use std::{
+    ffi::{c_void, CStr, CString, OsStr},
+    io,
+    os::{
+        raw::c_uint,
+        unix::ffi::{OsStrExt, OsStringExt},
+    },
+    path::{Path, PathBuf},
+};
+
+/// Mirrors the directory `root`. Open files and directories are backed by
+/// file descriptors and `DIR` streams, so they keep working after being
+/// unlinked or renamed.
+pub struct Passthrough {
+    root: PathBuf,
+}
+
+/// Turns a libc return value into a result, taking the error from `errno`.
+fn cvt<T: Default + PartialOrd>(out: T) -> io::Result<T> {
+    if out < T::default() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(out)
+    }
+}
+
+/// The file handle stored in `fh`, if the kernel passed one.
+fn fd(fi: &Option<&mut fuse_file_info>) -> Option<i32> {
+    fi.as_ref().map(|fi| fi.fh as i32)
+}
+
+/// An open directory, along with where in it the last `readdir` stopped.
+struct DirHandle {
+    dir: *mut libc::DIR,
+    entry: *mut libc::dirent,
+    offset: off_t,
+}
+
+impl Passthrough {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The directory being mirrored.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where `path` in the filesystem lives in the mirrored directory.
+    pub fn source(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    fn c_source(&self, path: &str) -> CString {
+        CString::new(self.source(path).into_os_string().into_vec()).unwrap()
+    }
+}
+
+impl FileSystem for Passthrough {
+    fn init(&self, _conn: Option<&mut fuse_conn_info>, config: Option<&mut fuse_config>) {
+        if let Some(config) = config {
+            config.use_ino = 1;
+        }
+    }
+
+    fn getattr(
+        &self,
+        path: &str,
+        stat: Option<&mut stat>,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        let stat = stat.unwrap() as *mut stat as *mut libc::stat;
+
+        match fd(&fi) {
+            Some(fd) => cvt(unsafe { libc::fstat(fd, stat) })?,
+            None => cvt(unsafe { libc::lstat(self.c_source(path).as_ptr(), stat) })?,
+        };
+
+        Ok(0)
+    }
+
+    fn access(&self, path: &str, mask: i32) -> Result<i32> {
+        cvt(unsafe { libc::access(self.c_source(path).as_ptr(), mask) })?;
+        Ok(0)
+    }
+
+    fn readlink(&self, path: &str, buf: &mut [u8]) -> Result<i32> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = cvt(unsafe {
+            libc::readlink(
+                self.c_source(path).as_ptr(),
+                buf.as_mut_ptr() as *mut _,
+                buf.len() - 1,
+            )
+        })?;
+        buf[n as usize] = 0;
+
+        Ok(0)
+    }
+
+    fn mknod(&self, path: &str, mode: mode_t, rdev: crate::dev_t) -> Result<i32> {
+        let source = self.c_source(path);
+
+        match mode & libc::S_IFMT {
+            libc::S_IFIFO => cvt(unsafe { libc::mkfifo(source.as_ptr(), mode) })?,
+            _ => cvt(unsafe { libc::mknod(source.as_ptr(), mode, rdev) })?,
+        };
+
+        Ok(0)
+    }
+
+    fn mkdir(&self, path: &str, mode: mode_t) -> Result<i32> {
+        cvt(unsafe { libc::mkdir(self.c_source(path).as_ptr(), mode) })?;
+        Ok(0)
+    }
+
+    fn unlink(&self, path: &str) -> Result<i32> {
+        cvt(unsafe { libc::unlink(self.c_source(path).as_ptr()) })?;
+        Ok(0)
+    }
+
+    fn rmdir(&self, path: &str) -> Result<i32> {
+        cvt(unsafe { libc::rmdir(self.c_source(path).as_ptr()) })?;
+        Ok(0)
+    }
+
+    fn symlink(&self, target: &str, path: &str) -> Result<i32> {
+        let target = CString::new(target).unwrap();
+        cvt(unsafe { libc::symlink(target.as_ptr(), self.c_source(path).as_ptr()) })?;
+        Ok(0)
+    }
+
+    fn rename(&self, from: &str, to: &str, flags: c_uint) -> Result<i32> {
+        let (from, to) = (self.c_source(from), self.c_source(to));
+
+        cvt(unsafe {
+            libc::renameat2(
+                libc::AT_FDCWD,
+                from.as_ptr(),
+                libc::AT_FDCWD,
+                to.as_ptr(),
+                flags,
+            )
+        })?;
+
+        Ok(0)
+    }
+
+    fn link(&self, from: &str, to: &str) -> Result<i32> {
+        cvt(unsafe { libc::link(self.c_source(from).as_ptr(), self.c_source(to).as_ptr()) })?;
+        Ok(0)
+    }
+
+    fn chmod(&self, path: &str, mode: mode_t, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        match fd(&fi) {
+            Some(fd) => cvt(unsafe { libc::fchmod(fd, mode) })?,
+            None => cvt(unsafe { libc::chmod(self.c_source(path).as_ptr(), mode) })?,
+        };
+
+        Ok(0)
+    }
+
+    fn chown(
+        &self,
+        path: &str,
+        uid: uid_t,
+        gid: gid_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        match fd(&fi) {
+            Some(fd) => cvt(unsafe { libc::fchown(fd, uid, gid) })?,
+            None => cvt(unsafe { libc::lchown(self.c_source(path).as_ptr(), uid, gid) })?,
+        };
+
+        Ok(0)
+    }
+
+    fn truncate(&self, path: &str, size: off_t, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        match fd(&fi) {
+            Some(fd) => cvt(unsafe { libc::ftruncate(fd, size) })?,
+            None => cvt(unsafe { libc::truncate(self.c_source(path).as_ptr(), size) })?,
+        };
+
+        Ok(0)
+    }
+
+    fn utimens(
+        &self,
+        path: &str,
+        tv: Option<&[timespec; 2]>,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        let times = tv.map_or(std::ptr::null(), |tv| tv.as_ptr() as *const libc::timespec);
+
+        match fd(&fi) {
+            Some(fd) => cvt(unsafe { libc::futimens(fd, times) })?,
+            None => cvt(unsafe {
+                libc::utimensat(
+                    libc::AT_FDCWD,
+                    self.c_source(path).as_ptr(),
+                    times,
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            })?,
+        };
+
+        Ok(0)
+    }
+
+    fn open(&self, path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let fi = fi.unwrap();
+        fi.fh = cvt(unsafe { libc::open(self.c_source(path).as_ptr(), fi.flags) })? as u64;
+        Ok(0)
+    }
+
+    fn create(&self, path: &str, mode: mode_t, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let fi = fi.unwrap();
+        fi.fh = cvt(unsafe {
+            libc::open(self.c_source(path).as_ptr(), fi.flags | libc::O_CREAT, mode)
+        })? as u64;
+        Ok(0)
+    }
+
+    fn read(
+        &self,
+        _path: &str,
+        buf: &mut [u8],
+        off: off_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        let fd = fd(&fi).unwrap();
+        let n = cvt(unsafe { libc::pread(fd, buf.as_mut_ptr() as *mut _, buf.len(), off) })?;
+        Ok(n as i32)
+    }
+
+    fn write(
+        &self,
+        _path: &str,
+        buf: &[u8],
+        off: off_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        let fd = fd(&fi).unwrap();
+        let n = cvt(unsafe { libc::pwrite(fd, buf.as_ptr() as *const _, buf.len(), off) })?;
+        Ok(n as i32)
+    }
+
+    fn statfs(&self, path: &str, stat: Option<&mut statvfs>) -> Result<i32> {
+        let stat = stat.unwrap() as *mut statvfs as *mut libc::statvfs;
+        cvt(unsafe { libc::statvfs(self.c_source(path).as_ptr(), stat) })?;
+        Ok(0)
+    }
+
+    fn flush(&self, _path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        // Called on every close of a descriptor, so close a duplicate to
+        // get the same errors the backing filesystem would report.
+        let fd = fd(&fi).unwrap();
+        cvt(unsafe { libc::close(cvt(libc::dup(fd))?) })?;
+        Ok(0)
+    }
+
+    fn release(&self, _path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        unsafe { libc::close(fd(&fi).unwrap()) };
+        Ok(0)
+    }
+
+    fn fsync(&self, _path: &str, datasync: i32, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let fd = fd(&fi).unwrap();
+
+        match datasync {
+            0 => cvt(unsafe { libc::fsync(fd) })?,
+            _ => cvt(unsafe { libc::fdatasync(fd) })?,
+        };
+
+        Ok(0)
+    }
+
+    fn fallocate(
+        &self,
+        _path: &str,
+        mode: i32,
+        off: off_t,
+        len: off_t,
+        fi: Option<&mut fuse_file_info>,
+    ) -> Result<i32> {
+        cvt(unsafe { libc::fallocate(fd(&fi).unwrap(), mode, off, len) })?;
+        Ok(0)
+    }
+
+    fn setxattr(&self, path: &str, name: &str, value: &[u8], flags: i32) -> Result<i32> {
+        let name = CString::new(name).unwrap();
+
+        cvt(unsafe {
+            libc::lsetxattr(
+                self.c_source(path).as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const _,
+                value.len(),
+                flags,
+            )
+        })?;
+
+        Ok(0)
+    }
+
+    fn getxattr(&self, path: &str, name: &str, value: &mut [u8]) -> Result<i32> {
+        let name = CString::new(name).unwrap();
+
+        let n = cvt(unsafe {
+            libc::lgetxattr(
+                self.c_source(path).as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut _,
+                value.len(),
+            )
+        })?;
+
+        Ok(n as i32)
+    }
+
+    fn listxattr(&self, path: &str, list: &mut [u8]) -> Result<i32> {
+        let n = cvt(unsafe {
+            libc::llistxattr(
+                self.c_source(path).as_ptr(),
+                list.as_mut_ptr() as *mut _,
+                list.len(),
+            )
+        })?;
+
+        Ok(n as i32)
+    }
+
+    fn removexattr(&self, path: &str, name: &str) -> Result<i32> {
+        let name = CString::new(name).unwrap();
+        cvt(unsafe { libc::lremovexattr(self.c_source(path).as_ptr(), name.as_ptr()) })?;
+        Ok(0)
+    }
+
+    fn opendir(&self, path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let dir = unsafe { libc::opendir(self.c_source(path).as_ptr()) };
+        if dir.is_null() {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let handle = Box::new(DirHandle {
+            dir,
+            entry: std::ptr::null_mut(),
+            offset: 0,
+        });
+        fi.unwrap().fh = Box::into_raw(handle) as u64;
+
+        Ok(0)
+    }
+
+    fn readdir(
+        &self,
+        _path: &str,
+        buf: Option<&mut c_void>,
+        filler: fuse_fill_dir_t,
+        off: off_t,
+        fi: Option<&mut fuse_file_info>,
+        _flags: fuse_readdir_flags,
+    ) -> Result<i32> {
+        let handle = unsafe { &mut *(fi.unwrap().fh as *mut DirHandle) };
+        let mut filler = match DirFiller::new(buf, filler) {
+            Some(filler) => filler,
+            None => return Ok(0),
+        };
+
+        if off != handle.offset {
+            unsafe { libc::seekdir(handle.dir, off as _) };
+            handle.entry = std::ptr::null_mut();
+            handle.offset = off;
+        }
+
+        loop {
+            if handle.entry.is_null() {
+                handle.entry = unsafe { libc::readdir(handle.dir) };
+                if handle.entry.is_null() {
+                    break;
+                }
+            }
+
+            let entry = unsafe { &*handle.entry };
+            let name =
+                OsStr::from_bytes(unsafe { CStr::from_ptr(entry.d_name.as_ptr()) }.to_bytes());
+            let stat = stat {
+                st_ino: entry.d_ino,
+                st_mode: (entry.d_type as mode_t) << 12,
+                ..Default::default()
+            };
+            let next = unsafe { libc::telldir(handle.dir) } as off_t;
+
+            // Leave the entry to be returned by the next call.
+            if !filler.add(name, Some(&stat), next) {
+                break;
+            }
+
+            handle.entry = std::ptr::null_mut();
+            handle.offset = next;
+        }
+
+        Ok(0)
+    }
+
+    fn releasedir(&self, _path: &str, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let handle = unsafe { Box::from_raw(fi.unwrap().fh as *mut DirHandle) };
+        unsafe { libc::closedir(handle.dir) };
+        Ok(0)
+    }
+
+    fn fsyncdir(&self, _path: &str, datasync: i32, fi: Option<&mut fuse_file_info>) -> Result<i32> {
+        let handle = unsafe { &*(fi.unwrap().fh as *const DirHandle) };
+        let fd = unsafe { libc::dirfd(handle.dir) };
+
+        match datasync {
+            0 => cvt(unsafe { libc::fsync(fd) })?,
+            _ => cvt(unsafe { libc::fdatasync(fd) })?,
+        };
+
+        Ok(0)
+    }
+}
@@ -0,0 +1,237 @@
+//! Serves several filesystems from one mount, each under its own directory.
+
+use crate::{
+    dir_filler::DirFiller, fuse_config, fuse_conn_info, off_t, stat, timespec, Call, Dispatch,
+    FileSystem,
+};
+use anyhow::Result;
+use nix::errno::Errno;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    time::SystemTime,
+};
+
+type Backend = Box<dyn FileSystem + Send + Sync>;
+
+/// Where a path leads.
+enum Route<'r> {
+    /// Into a backend, at `path` within it.
+    Backend {
+        prefix: &'r str,
+        fs: &'r Backend,
+        path: String,
+    },
+    /// To a directory above some of the backends, which only exists to
+    /// reach them.
+    Directory,
+}
+
+fn normalize(prefix: &str) -> String {
+    format!("/{}", prefix.trim_matches('/'))
+}
+
+/// Whether `path` is `dir` or inside it.
+fn is_within(path: &str, dir: &str) -> bool {
+    dir == "/"
+        || path == dir
+        || path
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Dispatches every operation to the filesystem routed to the directory the
+/// path is in, with the path made relative to that directory.
+///
+/// The directories leading to the routes are made up: they're read-only,
+/// list the next directory down towards each route, and report `EROFS` to
+/// anything trying to change them. `rename` and `link` between different
+/// filesystems fail with `EXDEV`, which `mv` handles by copying.
+///
+/// ```no_run
+/// use fuse_sys::{memfs::MemFs, passthrough::Passthrough, prelude::*, router::Router};
+///
+/// Router::new()
+///     .route("/scratch", MemFs::new())
+///     .route("/data", Passthrough::new("/srv/data"))
+///     .run(&["router", "/mnt"])
+///     .unwrap();
+/// ```
+pub struct Router {
+    routes: BTreeMap<String, Backend>,
+    created: timespec,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Router {
+    /// A router with no routes, which is just an empty directory.
+    pub fn new() -> Self {
+        let created = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Self {
+            routes: BTreeMap::new(),
+            created: timespec {
+                tv_sec: created.as_secs() as _,
+                tv_nsec: created.subsec_nanos() as _,
+            },
+        }
+    }
+
+    /// Serves `fs` at `prefix`, e.g. `/data`.
+    ///
+    /// # Panics
+    ///
+    /// If `prefix` is already routed, or is inside or above another route.
+    pub fn route(mut self, prefix: &str, fs: impl FileSystem + Send + Sync + 'static) -> Self {
+        let prefix = normalize(prefix);
+
+        if let Some(existing) = self
+            .routes
+            .keys()
+            .find(|existing| is_within(&prefix, existing) || is_within(existing, &prefix))
+        {
+            panic!("Route {prefix} overlaps {existing}");
+        }
+
+        self.routes.insert(prefix, Box::new(fs));
+        self
+    }
+
+    fn resolve(&self, path: &str) -> Result<Route<'_>, Errno> {
+        for (prefix, fs) in &self.routes {
+            if is_within(path, prefix) {
+                let rest = match prefix.as_str() {
+                    "/" => path,
+                    prefix => &path[prefix.len()..],
+                };
+
+                return Ok(Route::Backend {
+                    prefix,
+                    fs,
+                    path: normalize(rest),
+                });
+            }
+        }
+
+        // The root is there even with nothing routed.
+        match path == "/" || !self.children(path).is_empty() {
+            true => Ok(Route::Directory),
+            false => Err(Errno::ENOENT),
+        }
+    }
+
+    /// The names in a made up directory, which lead towards routes.
+    fn children(&self, dir: &str) -> BTreeSet<&str> {
+        let dir = dir.trim_end_matches('/');
+
+        self.routes
+            .keys()
+            .filter_map(|prefix| prefix.strip_prefix(dir)?.strip_prefix('/'))
+            .filter_map(|rest| rest.split('/').next())
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+
+    fn directory_stat(&self) -> stat {
+        stat {
+            st_mode: libc::S_IFDIR | 0o555,
+            st_nlink: 2,
+            st_uid: unsafe { libc::geteuid() },
+            st_gid: unsafe { libc::getegid() },
+            st_atim: self.created,
+            st_mtim: self.created,
+            st_ctim: self.created,
+            ..Default::default()
+        }
+    }
+
+    /// Answers a call on one of the made up directories.
+    fn directory(&self, path: &str, call: Call<'_>) -> Result<i32> {
+        match call {
+            Call::Getattr(_, stat, _) => {
+                *stat.unwrap() = self.directory_stat();
+            }
+            Call::Access(_, mask) if mask & libc::W_OK != 0 => return Err(Errno::EROFS.into()),
+            Call::Access(..) | Call::Opendir(..) | Call::Releasedir(..) | Call::Fsyncdir(..) => {}
+            Call::Readdir(_, buf, filler, off, _, _) => {
+                let mut filler = match DirFiller::new(buf, filler) {
+                    Some(filler) => filler,
+                    None => return Ok(0),
+                };
+                let stat = self.directory_stat();
+                let names = [".", ".."].into_iter().chain(self.children(path));
+
+                for (i, name) in names.enumerate().skip(off as usize) {
+                    if !filler.add(OsStr::new(name), Some(&stat), i as off_t + 1) {
+                        break;
+                    }
+                }
+            }
+            Call::Open(..) => return Err(Errno::EISDIR.into()),
+            Call::Mknod(..) | Call::Mkdir(..) | Call::Symlink(..) | Call::Create(..) => {
+                return Err(Errno::EEXIST.into())
+            }
+            Call::Getxattr(..) => return Err(Errno::ENODATA.into()),
+            Call::Listxattr(..) => {}
+            call if call.operation().is_mutating() => return Err(Errno::EROFS.into()),
+            _ => return Err(Errno::ENOSYS.into()),
+        }
+
+        Ok(0)
+    }
+}
+
+impl Dispatch for Router {
+    fn init(&self, mut conn: Option<&mut fuse_conn_info>, mut config: Option<&mut fuse_config>) {
+        for fs in self.routes.values() {
+            fs.init(conn.as_deref_mut(), config.as_deref_mut());
+        }
+
+        // The backends' inode numbers would collide.
+        if let Some(config) = config {
+            config.use_ino = 0;
+        }
+    }
+
+    fn dispatch(&self, call: Call<'_>) -> Result<i32> {
+        let paths = call.paths();
+        let mut routes = paths.iter().map(|path| self.resolve(path));
+        let first = routes.next().unwrap()?;
+        let mut inner = vec![];
+
+        let (prefix, fs) = match &first {
+            Route::Backend { prefix, fs, path } => {
+                inner.push(path.clone());
+                (*prefix, *fs)
+            }
+            Route::Directory if paths.len() == 1 => {
+                let path = paths[0].to_string();
+                return self.directory(&path, call);
+            }
+            Route::Directory => return Err(Errno::EXDEV.into()),
+        };
+
+        // `rename`, `link` and `copy_file_range` can't cross between
+        // backends.
+        for route in routes {
+            match route {
+                Ok(Route::Backend {
+                    prefix: other,
+                    path,
+                    ..
+                }) if other == prefix => inner.push(path),
+                // Including a new name in a made up directory, or nowhere.
+                _ => return Err(Errno::EXDEV.into()),
+            }
+        }
+
+        call.with_paths(&inner).forward(&**fs)
+    }
+}
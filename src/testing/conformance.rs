@@ -0,0 +1,292 @@
+//! Checks that a filesystem behaves the way programs expect a POSIX one to.
+//!
+//! Each check runs against a fresh filesystem from the factory, through
+//! either a [`MockSession`] or a [`TempMount`], and panics describing the
+//! first thing that didn't behave.
+
+use super::{MockSession, TempMount};
+use crate::FileSystemRaw;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Read},
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    path::Path,
+};
+
+/// The handful of operations the checks are written against, so they can
+/// run through the mock and through the kernel alike. Paths are absolute
+/// within the filesystem.
+trait Target {
+    /// Creates `path`, failing if it already exists.
+    fn create_new(&mut self, path: &str) -> io::Result<()>;
+    fn write_file(&mut self, path: &str, data: &[u8]) -> io::Result<()>;
+    fn read_file(&mut self, path: &str) -> io::Result<Vec<u8>>;
+    fn rename(&mut self, from: &str, to: &str) -> io::Result<()>;
+    fn mkdir(&mut self, path: &str) -> io::Result<()>;
+    fn rmdir(&mut self, path: &str) -> io::Result<()>;
+    fn unlink(&mut self, path: &str) -> io::Result<()>;
+    fn link(&mut self, from: &str, to: &str) -> io::Result<()>;
+    fn truncate(&mut self, path: &str, size: u64) -> io::Result<()>;
+    fn nlink(&mut self, path: &str) -> io::Result<u64>;
+    /// Opens `path`, unlinks it and reads it through the still open file.
+    fn read_unlinked(&mut self, path: &str) -> io::Result<Vec<u8>>;
+}
+
+impl<F> Target for MockSession<F> {
+    fn create_new(&mut self, path: &str) -> io::Result<()> {
+        let mut fi = self.create(path, 0o644, libc::O_WRONLY | libc::O_EXCL)?;
+        self.release(path, &mut fi)
+    }
+
+    fn write_file(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.write_all(path, data)
+    }
+
+    fn read_file(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        self.read_all(path)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> io::Result<()> {
+        MockSession::rename(self, from, to)
+    }
+
+    fn mkdir(&mut self, path: &str) -> io::Result<()> {
+        MockSession::mkdir(self, path, 0o755)
+    }
+
+    fn rmdir(&mut self, path: &str) -> io::Result<()> {
+        MockSession::rmdir(self, path)
+    }
+
+    fn unlink(&mut self, path: &str) -> io::Result<()> {
+        MockSession::unlink(self, path)
+    }
+
+    fn link(&mut self, from: &str, to: &str) -> io::Result<()> {
+        MockSession::link(self, from, to)
+    }
+
+    fn truncate(&mut self, path: &str, size: u64) -> io::Result<()> {
+        MockSession::truncate(self, path, size as _)
+    }
+
+    fn nlink(&mut self, path: &str) -> io::Result<u64> {
+        Ok(self.getattr(path)?.st_nlink as u64)
+    }
+
+    // Unlike libfuse, the mock doesn't rename open files out of the way
+    // instead of unlinking them, so this only passes for filesystems that
+    // read through their file handles.
+    fn read_unlinked(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        let mut fi = self.open(path, libc::O_RDONLY)?;
+        MockSession::unlink(self, path)?;
+        let data = self.read(path, &mut fi, 4096, 0);
+        self.release(path, &mut fi)?;
+        data
+    }
+}
+
+/// A mounted filesystem, driven through `std::fs`.
+struct Mounted<'a>(&'a Path);
+
+impl Mounted<'_> {
+    fn path(&self, path: &str) -> std::path::PathBuf {
+        self.0.join(path.trim_start_matches('/'))
+    }
+}
+
+impl Target for Mounted<'_> {
+    fn create_new(&mut self, path: &str) -> io::Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o644)
+            .open(self.path(path))
+            .map(drop)
+    }
+
+    fn write_file(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        fs::write(self.path(path), data)
+    }
+
+    fn read_file(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(path))
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.path(from), self.path(to))
+    }
+
+    fn mkdir(&mut self, path: &str) -> io::Result<()> {
+        fs::create_dir(self.path(path))
+    }
+
+    fn rmdir(&mut self, path: &str) -> io::Result<()> {
+        fs::remove_dir(self.path(path))
+    }
+
+    fn unlink(&mut self, path: &str) -> io::Result<()> {
+        fs::remove_file(self.path(path))
+    }
+
+    fn link(&mut self, from: &str, to: &str) -> io::Result<()> {
+        fs::hard_link(self.path(from), self.path(to))
+    }
+
+    fn truncate(&mut self, path: &str, size: u64) -> io::Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .open(self.path(path))?
+            .set_len(size)
+    }
+
+    fn nlink(&mut self, path: &str) -> io::Result<u64> {
+        Ok(fs::metadata(self.path(path))?.nlink())
+    }
+
+    fn read_unlinked(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        let mut file = fs::File::open(self.path(path))?;
+        fs::remove_file(self.path(path))?;
+
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+fn expect_errno<T: std::fmt::Debug>(check: &str, result: io::Result<T>, errno: i32) {
+    match result {
+        Err(e) if e.raw_os_error() == Some(errno) => {}
+        other => panic!(
+            "{check}: expected {}, got {other:?}",
+            io::Error::from_raw_os_error(errno)
+        ),
+    }
+}
+
+fn create_excl(target: &mut dyn Target) {
+    target
+        .create_new("/a")
+        .expect("create_excl: creating a new file");
+    expect_errno(
+        "create_excl: creating an existing file",
+        target.create_new("/a"),
+        libc::EEXIST,
+    );
+}
+
+fn rename_over_existing(target: &mut dyn Target) {
+    target.write_file("/a", b"from").unwrap();
+    target.write_file("/b", b"to").unwrap();
+    target
+        .rename("/a", "/b")
+        .expect("rename_over_existing: renaming over a file");
+
+    expect_errno(
+        "rename_over_existing: source after the rename",
+        target.read_file("/a"),
+        libc::ENOENT,
+    );
+    assert_eq!(
+        target.read_file("/b").unwrap(),
+        b"from",
+        "rename_over_existing: destination should have the source's contents"
+    );
+}
+
+fn rmdir_not_empty(target: &mut dyn Target) {
+    target.mkdir("/dir").unwrap();
+    target.write_file("/dir/a", b"").unwrap();
+
+    expect_errno(
+        "rmdir_not_empty: removing a non-empty directory",
+        target.rmdir("/dir"),
+        libc::ENOTEMPTY,
+    );
+
+    target.unlink("/dir/a").unwrap();
+    target
+        .rmdir("/dir")
+        .expect("rmdir_not_empty: removing the emptied directory");
+}
+
+fn unlink_open(target: &mut dyn Target) {
+    target.write_file("/a", b"still here").unwrap();
+
+    assert_eq!(
+        target
+            .read_unlinked("/a")
+            .expect("unlink_open: reading an unlinked open file"),
+        b"still here",
+        "unlink_open: an unlinked file should stay readable while open"
+    );
+    expect_errno(
+        "unlink_open: reading the unlinked path",
+        target.read_file("/a"),
+        libc::ENOENT,
+    );
+}
+
+fn link_count(target: &mut dyn Target) {
+    target.write_file("/a", b"shared").unwrap();
+    assert_eq!(target.nlink("/a").unwrap(), 1, "link_count: new file");
+
+    target.link("/a", "/b").expect("link_count: linking");
+    assert_eq!(target.nlink("/a").unwrap(), 2, "link_count: after linking");
+    assert_eq!(target.read_file("/b").unwrap(), b"shared");
+
+    target.unlink("/a").unwrap();
+    assert_eq!(
+        target.nlink("/b").unwrap(),
+        1,
+        "link_count: after unlinking"
+    );
+}
+
+fn truncate_zero_fill(target: &mut dyn Target) {
+    target.write_file("/a", b"abc").unwrap();
+
+    target.truncate("/a", 6).unwrap();
+    assert_eq!(
+        target.read_file("/a").unwrap(),
+        b"abc\0\0\0",
+        "truncate_zero_fill: extending should fill with zeros"
+    );
+
+    target.truncate("/a", 1).unwrap();
+    target.truncate("/a", 3).unwrap();
+    assert_eq!(
+        target.read_file("/a").unwrap(),
+        b"a\0\0",
+        "truncate_zero_fill: shrinking should discard the old contents"
+    );
+}
+
+const CHECKS: &[fn(&mut dyn Target)] = &[
+    create_excl,
+    rename_over_existing,
+    rmdir_not_empty,
+    unlink_open,
+    link_count,
+    truncate_zero_fill,
+];
+
+/// Runs every check against a fresh filesystem driven by a [`MockSession`].
+pub fn check_mock<const UNTHREADED: bool, F>(factory: impl Fn() -> F)
+where
+    F: FileSystemRaw<UNTHREADED>,
+{
+    for check in CHECKS {
+        check(&mut MockSession::new::<UNTHREADED>(factory()));
+    }
+}
+
+/// Runs every check against a fresh filesystem mounted with [`TempMount`].
+pub fn check_mounted<const UNTHREADED: bool, F>(factory: impl Fn() -> F)
+where
+    F: FileSystemRaw<UNTHREADED> + Send + 'static,
+{
+    for check in CHECKS {
+        TempMount::with::<UNTHREADED, F, _>(factory(), |path| check(&mut Mounted(path)));
+    }
+}
@@ -0,0 +1,525 @@
+//! Helpers for testing filesystems built on this crate.
+//!
+//! [`MockSession`] plays the part of libfuse, calling a filesystem's
+//! generated callbacks with real C arguments, so implementations and the
+//! glue between them and libfuse can be tested without a mount.
+//! [`TempMount`] mounts one for real, for tests that go through the kernel.
+//! [`memfs`] makes an in-memory one with some files in it to work on.
+
+pub mod conformance;
+pub mod replay;
+
+use crate::{
+    dev_t, fuse_config, fuse_conn_info, fuse_file_info, fuse_fill_dir_flags, fuse_operations,
+    fusermount,
+    lowlevel::{LowLevelFileSystem, Session},
+    memfs::MemFs,
+    mode_t,
+    mount::Mount,
+    notify::Notifier,
+    off_t, stat, FileSystemRaw, UserData, MOCK_PRIVATE_DATA,
+};
+use std::{
+    ffi::{c_void, CStr, CString, OsStr, OsString},
+    io,
+    os::{raw::c_char, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread::{self, JoinHandle},
+};
+use tempfile::TempDir;
+
+/// How much `read_all` asks for at a time.
+const READ_SIZE: usize = 64 * 1024;
+
+/// Owns a filesystem and drives it through its `fuse_operations` table the
+/// way libfuse would, one call at a time on the current thread.
+pub struct MockSession<F> {
+    user_data: Box<UserData<F>>,
+    fs: Box<F>,
+}
+
+fn check(out: i32) -> io::Result<i32> {
+    match out {
+        e if e < 0 => Err(io::Error::from_raw_os_error(-e)),
+        out => Ok(out),
+    }
+}
+
+/// libfuse treats a missing `open`, `release` and friends as success.
+fn optional(out: i32) -> io::Result<()> {
+    match out {
+        e if e == -libc::ENOSYS => Ok(()),
+        out => check(out).map(drop),
+    }
+}
+
+fn c_path(path: &str) -> CString {
+    CString::new(path).unwrap()
+}
+
+unsafe extern "C" fn collect_names(
+    buf: *mut c_void,
+    name: *const c_char,
+    _stat: *const stat,
+    _off: off_t,
+    _flags: fuse_fill_dir_flags,
+) -> i32 {
+    let names = &mut *(buf as *mut Vec<OsString>);
+    names.push(OsStr::from_bytes(CStr::from_ptr(name).to_bytes()).to_owned());
+    0
+}
+
+impl<F> MockSession<F> {
+    /// Takes ownership of `fs` and calls its `init`, as mounting would.
+    pub fn new<const UNTHREADED: bool>(fs: F) -> Self
+    where
+        F: FileSystemRaw<UNTHREADED>,
+    {
+        let mut fs = Box::new(fs);
+        let user_data = Box::new(UserData::new(F::operations(), &mut *fs as *mut F));
+        let mut session = Self { user_data, fs };
+
+        session.call(|ops| unsafe {
+            let mut conn = fuse_conn_info::default();
+            let mut config = fuse_config::default();
+            ops.init.unwrap()(&mut conn, &mut config);
+        });
+
+        session
+    }
+
+    /// The filesystem being driven.
+    pub fn filesystem(&self) -> &F {
+        &self.fs
+    }
+
+    /// Ends the session, handing back the filesystem.
+    pub fn into_filesystem(self) -> F {
+        *self.fs
+    }
+
+    /// Runs `call` with the filesystem's operations table, for anything the
+    /// helpers don't cover. The generated callbacks find the filesystem as
+    /// they would under libfuse for the duration of the call.
+    pub fn call<T>(&mut self, call: impl FnOnce(&fuse_operations) -> T) -> T {
+        struct Reset;
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                MOCK_PRIVATE_DATA.with(|data| data.set(std::ptr::null_mut()));
+            }
+        }
+
+        let raw = &mut *self.user_data as *mut UserData<F> as *mut c_void;
+        MOCK_PRIVATE_DATA.with(|data| data.set(raw));
+        let _reset = Reset;
+
+        call(&self.user_data.ops)
+    }
+
+    pub fn getattr(&mut self, path: &str) -> io::Result<stat> {
+        let path = c_path(path);
+        let mut stat = stat::default();
+
+        check(self.call(|ops| unsafe {
+            ops.getattr.unwrap()(path.as_ptr(), &mut stat, std::ptr::null_mut())
+        }))?;
+
+        Ok(stat)
+    }
+
+    /// Opens `path` with `open(2)` style `flags`, returning the file info
+    /// later calls on the open file take.
+    pub fn open(&mut self, path: &str, flags: i32) -> io::Result<fuse_file_info> {
+        let path = c_path(path);
+        let mut fi = fuse_file_info {
+            flags,
+            ..Default::default()
+        };
+
+        optional(self.call(|ops| unsafe { ops.open.unwrap()(path.as_ptr(), &mut fi) }))?;
+        Ok(fi)
+    }
+
+    /// Creates and opens `path`, falling back to `mknod` and `open` like
+    /// libfuse does for filesystems without `create`.
+    pub fn create(&mut self, path: &str, mode: mode_t, flags: i32) -> io::Result<fuse_file_info> {
+        let c_path = c_path(path);
+        let mut fi = fuse_file_info {
+            flags: flags | libc::O_CREAT,
+            ..Default::default()
+        };
+
+        let out = self.call(|ops| unsafe {
+            ops.create.unwrap()(c_path.as_ptr(), libc::S_IFREG | mode, &mut fi)
+        });
+
+        match out {
+            e if e == -libc::ENOSYS => {
+                self.mknod(path, libc::S_IFREG | mode, 0)?;
+                self.open(path, flags)
+            }
+            out => check(out).map(|_| fi),
+        }
+    }
+
+    /// Reads up to `size` bytes at `off` from a file opened with `fi`.
+    pub fn read(
+        &mut self,
+        path: &str,
+        fi: &mut fuse_file_info,
+        size: usize,
+        off: off_t,
+    ) -> io::Result<Vec<u8>> {
+        let path = c_path(path);
+        let mut data = vec![0; size];
+
+        let out = check(self.call(|ops| unsafe {
+            ops.read.unwrap()(
+                path.as_ptr(),
+                data.as_mut_ptr() as *mut c_char,
+                size,
+                off,
+                fi,
+            )
+        }))?;
+
+        data.truncate(out as usize);
+        Ok(data)
+    }
+
+    /// Writes `data` at `off` to a file opened with `fi`, returning how much
+    /// was written.
+    pub fn write(
+        &mut self,
+        path: &str,
+        fi: &mut fuse_file_info,
+        data: &[u8],
+        off: off_t,
+    ) -> io::Result<usize> {
+        let path = c_path(path);
+
+        let out = check(self.call(|ops| unsafe {
+            ops.write.unwrap()(
+                path.as_ptr(),
+                data.as_ptr() as *const c_char,
+                data.len(),
+                off,
+                fi,
+            )
+        }))?;
+
+        Ok(out as usize)
+    }
+
+    /// Copies up to `size` bytes at `off_in` in a file opened with `fi_in`
+    /// to `off_out` in one opened with `fi_out`, returning how much was
+    /// copied.
+    #[cfg(fuse_3_4)]
+    #[allow(clippy::too_many_arguments)]
+    pub fn copy_file_range(
+        &mut self,
+        path_in: &str,
+        fi_in: &mut fuse_file_info,
+        off_in: off_t,
+        path_out: &str,
+        fi_out: &mut fuse_file_info,
+        off_out: off_t,
+        size: usize,
+    ) -> io::Result<usize> {
+        let (path_in, path_out) = (c_path(path_in), c_path(path_out));
+
+        let out = self.call(|ops| unsafe {
+            ops.copy_file_range.unwrap()(
+                path_in.as_ptr(),
+                fi_in,
+                off_in,
+                path_out.as_ptr(),
+                fi_out,
+                off_out,
+                size,
+                0,
+            )
+        });
+
+        check(out as i32).map(|n| n as usize)
+    }
+
+    pub fn release(&mut self, path: &str, fi: &mut fuse_file_info) -> io::Result<()> {
+        let path = c_path(path);
+        optional(self.call(|ops| unsafe { ops.release.unwrap()(path.as_ptr(), fi) }))
+    }
+
+    /// Flushes a file opened with `fi`, as every `close` does.
+    pub fn flush(&mut self, path: &str, fi: &mut fuse_file_info) -> io::Result<()> {
+        let path = c_path(path);
+        optional(self.call(|ops| unsafe { ops.flush.unwrap()(path.as_ptr(), fi) }))
+    }
+
+    pub fn fsync(&mut self, path: &str, fi: &mut fuse_file_info) -> io::Result<()> {
+        let path = c_path(path);
+        optional(self.call(|ops| unsafe { ops.fsync.unwrap()(path.as_ptr(), 0, fi) }))
+    }
+
+    /// Opens `path`, reads it until the filesystem returns no more data and
+    /// releases it.
+    pub fn read_all(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        let mut fi = self.open(path, libc::O_RDONLY)?;
+
+        let mut contents = vec![];
+        let read = loop {
+            match self.read(path, &mut fi, READ_SIZE, contents.len() as off_t) {
+                Ok(data) if data.is_empty() => break Ok(()),
+                Ok(data) => contents.extend(data),
+                Err(e) => break Err(e),
+            }
+        };
+
+        self.release(path, &mut fi)?;
+        read.map(|_| contents)
+    }
+
+    /// Replaces the contents of `path` with `data`, creating it if needed.
+    pub fn write_all(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        let mut fi = match self.getattr(path) {
+            Ok(_) => {
+                self.truncate(path, 0)?;
+                self.open(path, libc::O_WRONLY)?
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                self.create(path, 0o644, libc::O_WRONLY)?
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut written = 0;
+        let write = loop {
+            if written == data.len() {
+                break Ok(());
+            }
+
+            match self.write(path, &mut fi, &data[written..], written as off_t) {
+                Ok(0) => break Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => written += n,
+                Err(e) => break Err(e),
+            }
+        };
+
+        self.release(path, &mut fi)?;
+        write
+    }
+
+    pub fn mknod(&mut self, path: &str, mode: mode_t, dev: dev_t) -> io::Result<()> {
+        let path = c_path(path);
+        check(self.call(|ops| unsafe { ops.mknod.unwrap()(path.as_ptr(), mode, dev) })).map(drop)
+    }
+
+    pub fn mkdir(&mut self, path: &str, mode: mode_t) -> io::Result<()> {
+        let path = c_path(path);
+        check(self.call(|ops| unsafe { ops.mkdir.unwrap()(path.as_ptr(), mode) })).map(drop)
+    }
+
+    pub fn unlink(&mut self, path: &str) -> io::Result<()> {
+        let path = c_path(path);
+        check(self.call(|ops| unsafe { ops.unlink.unwrap()(path.as_ptr()) })).map(drop)
+    }
+
+    pub fn rmdir(&mut self, path: &str) -> io::Result<()> {
+        let path = c_path(path);
+        check(self.call(|ops| unsafe { ops.rmdir.unwrap()(path.as_ptr()) })).map(drop)
+    }
+
+    /// Makes `path` a symlink to `target`.
+    pub fn symlink(&mut self, target: &str, path: &str) -> io::Result<()> {
+        let (target, path) = (c_path(target), c_path(path));
+        check(self.call(|ops| unsafe { ops.symlink.unwrap()(target.as_ptr(), path.as_ptr()) }))
+            .map(drop)
+    }
+
+    pub fn readlink(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        let path = c_path(path);
+        let mut buf = vec![0u8; libc::PATH_MAX as usize + 1];
+
+        check(self.call(|ops| unsafe {
+            ops.readlink.unwrap()(path.as_ptr(), buf.as_mut_ptr() as *mut c_char, buf.len())
+        }))?;
+
+        buf.truncate(buf.iter().position(|&b| b == 0).unwrap_or(buf.len()));
+        Ok(buf)
+    }
+
+    pub fn chmod(&mut self, path: &str, mode: mode_t) -> io::Result<()> {
+        let path = c_path(path);
+        check(
+            self.call(|ops| unsafe {
+                ops.chmod.unwrap()(path.as_ptr(), mode, std::ptr::null_mut())
+            }),
+        )
+        .map(drop)
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (c_path(from), c_path(to));
+        check(self.call(|ops| unsafe { ops.rename.unwrap()(from.as_ptr(), to.as_ptr(), 0) }))
+            .map(drop)
+    }
+
+    /// Makes `to` a hard link to `from`.
+    pub fn link(&mut self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (c_path(from), c_path(to));
+        check(self.call(|ops| unsafe { ops.link.unwrap()(from.as_ptr(), to.as_ptr()) })).map(drop)
+    }
+
+    pub fn truncate(&mut self, path: &str, size: off_t) -> io::Result<()> {
+        let path = c_path(path);
+        check(self.call(|ops| unsafe {
+            ops.truncate.unwrap()(path.as_ptr(), size, std::ptr::null_mut())
+        }))
+        .map(drop)
+    }
+
+    /// Opens the directory at `path`, lists it and releases it.
+    pub fn readdir(&mut self, path: &str) -> io::Result<Vec<OsString>> {
+        let path = c_path(path);
+        let mut fi = fuse_file_info::default();
+        let mut names: Vec<OsString> = vec![];
+
+        optional(self.call(|ops| unsafe { ops.opendir.unwrap()(path.as_ptr(), &mut fi) }))?;
+
+        let read = check(self.call(|ops| unsafe {
+            ops.readdir.unwrap()(
+                path.as_ptr(),
+                &mut names as *mut Vec<OsString> as *mut c_void,
+                Some(collect_names),
+                0,
+                &mut fi,
+                0,
+            )
+        }));
+
+        optional(self.call(|ops| unsafe { ops.releasedir.unwrap()(path.as_ptr(), &mut fi) }))?;
+        read?;
+
+        Ok(names)
+    }
+}
+
+/// A [`MemFs`] holding `files`, along with the directories they're in, for
+/// tests that need something to work on.
+pub fn memfs(files: &[(&str, &[u8])]) -> MemFs {
+    let mut session = MockSession::new(MemFs::new());
+
+    for (path, data) in files {
+        for (end, _) in path.match_indices('/').skip(1) {
+            let dir = &path[..end];
+            if session.getattr(dir).is_err() {
+                session.mkdir(dir, 0o755).unwrap();
+            }
+        }
+        session.write_all(path, data).unwrap();
+    }
+
+    session.into_filesystem()
+}
+
+/// A filesystem mounted on a temporary directory and served from a
+/// background thread. Dropping it unmounts, so a failing test doesn't leave
+/// a mount behind.
+///
+/// Mounting needs `/dev/fuse` and the `fusermount3` helper.
+pub struct TempMount {
+    dir: TempDir,
+    notifier: Notifier,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl TempMount {
+    pub fn new<const UNTHREADED: bool, F>(fs: F) -> io::Result<Self>
+    where
+        F: FileSystemRaw<UNTHREADED> + Send + 'static,
+    {
+        let dir = tempfile::tempdir()?;
+        let mut mount = Mount::new::<UNTHREADED>(fs, dir.path(), &["fuse-sys-test"])?;
+        let notifier = mount.notifier();
+        let thread = thread::spawn(move || mount.run());
+
+        Ok(Self {
+            dir,
+            notifier,
+            thread: Some(thread),
+        })
+    }
+
+    /// Mounts a [`LowLevelFileSystem`] instead, through a [`Session`].
+    pub fn lowlevel<F>(fs: F) -> io::Result<Self>
+    where
+        F: LowLevelFileSystem + 'static,
+    {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().to_owned();
+        let (started, notifier) = mpsc::channel();
+
+        // Sessions stay on the thread they were made on.
+        let thread = thread::spawn(move || {
+            let session = Session::new(fs, &["fuse-sys-test"]).and_then(|mut session| {
+                session.mount(&path)?;
+                Ok(session)
+            });
+
+            match session {
+                Ok(mut session) => {
+                    let _ = started.send(Ok(session.notifier()));
+                    session.run()
+                }
+                Err(e) => {
+                    let _ = started.send(Err(e));
+                    Ok(())
+                }
+            }
+        });
+
+        let notifier = notifier.recv().expect("Mount thread panicked")?;
+        Ok(Self {
+            dir,
+            notifier,
+            thread: Some(thread),
+        })
+    }
+
+    /// Mounts `fs` for the duration of `test`, which is handed the mountpoint.
+    pub fn with<const UNTHREADED: bool, F, T>(fs: F, test: impl FnOnce(&Path) -> T) -> T
+    where
+        F: FileSystemRaw<UNTHREADED> + Send + 'static,
+    {
+        let mount = Self::new::<UNTHREADED, F>(fs).expect("Could not mount filesystem");
+        test(mount.path())
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn notifier(&self) -> Notifier {
+        self.notifier.clone()
+    }
+}
+
+impl Drop for TempMount {
+    fn drop(&mut self) {
+        // The loop is blocked reading from the kernel, so unmount from out
+        // here; that makes the read fail and the loop return. The mount's own
+        // teardown then finds it already gone.
+        let helper = fusermount::path().unwrap_or_else(|| PathBuf::from("fusermount3"));
+        let _ = Command::new(helper)
+            .args(["-u", "-z"])
+            .arg(self.dir.path())
+            .stderr(Stdio::null())
+            .status();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
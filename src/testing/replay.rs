@@ -0,0 +1,376 @@
+//! Replays a log written by a [`Recorder`](crate::adapters::Recorder)
+//! against a filesystem through a [`MockSession`], to reproduce a workload
+//! and find where the filesystem behaves differently from the one it was
+//! recorded on.
+//!
+//! ```no_run
+//! use fuse_sys::{adapters::Record, memfs::MemFs, testing::{replay::replay, MockSession}};
+//! use std::{fs::File, io::BufReader};
+//!
+//! let log = BufReader::new(File::open("workload.log").unwrap());
+//! let records = Record::read_all(log).unwrap();
+//!
+//! let mut session = MockSession::new(MemFs::new());
+//! for divergence in replay(&mut session, &records) {
+//!     println!("{divergence}");
+//! }
+//! ```
+
+use super::{c_path, MockSession};
+use crate::{
+    adapters::{checksum, Op, Record},
+    flock, fuse_file_info, statvfs, timespec,
+};
+use std::{collections::HashMap, fmt, io};
+
+/// An operation that came out differently when replayed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// Where the record is in the recording, counting from 0.
+    pub index: usize,
+    pub record: Record,
+    /// What the operation returned when replayed, or the negated errno it
+    /// failed with.
+    pub result: i32,
+    /// Whether a read returned different data, even if as much of it.
+    pub data_differs: bool,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Record {} ({}) replayed with {}",
+            self.index, self.record, self.result
+        )?;
+        if self.data_differs {
+            write!(f, " and different data")?;
+        }
+        Ok(())
+    }
+}
+
+fn outcome<T>(out: io::Result<T>, value: impl FnOnce(T) -> i32) -> i32 {
+    match out {
+        Ok(out) => value(out),
+        Err(e) => -e.raw_os_error().unwrap_or(libc::EIO),
+    }
+}
+
+/// Makes each recorded operation on `session`'s filesystem in turn, and
+/// returns the ones that didn't give the recorded result.
+///
+/// Open files are tracked by the handles they were recorded with, so the
+/// filesystem can hand out different ones. `ioctl`, `poll` and the buffer
+/// versions of `read` and `write` aren't replayed, as they hand over memory
+/// the log doesn't hold, and neither are operations the log only names or
+/// ones on files that failed to open.
+pub fn replay<F>(session: &mut MockSession<F>, records: &[Record]) -> Vec<Divergence> {
+    let mut open: HashMap<u64, fuse_file_info> = HashMap::new();
+    // Directory handles are kept apart, as filesystems may number them the
+    // same way as files.
+    let mut dirs: HashMap<u64, fuse_file_info> = HashMap::new();
+    let mut divergences = vec![];
+
+    for (index, record) in records.iter().enumerate() {
+        let mut data_differs = false;
+
+        let result = match &record.op {
+            Op::Getattr { path } => outcome(session.getattr(path), |_| 0),
+            Op::Readlink { path } => outcome(session.readlink(path), |_| 0),
+            Op::Mknod { path, mode, rdev } => outcome(session.mknod(path, *mode, *rdev), |_| 0),
+            Op::Mkdir { path, mode } => outcome(session.mkdir(path, *mode), |_| 0),
+            Op::Unlink { path } => outcome(session.unlink(path), |_| 0),
+            Op::Rmdir { path } => outcome(session.rmdir(path), |_| 0),
+            Op::Symlink { target, path } => outcome(session.symlink(target, path), |_| 0),
+            Op::Rename { from, to, flags } => {
+                let (from, to) = (c_path(from), c_path(to));
+                session
+                    .call(|ops| unsafe { ops.rename.unwrap()(from.as_ptr(), to.as_ptr(), *flags) })
+            }
+            Op::Link { from, to } => outcome(session.link(from, to), |_| 0),
+            Op::Chmod { path, mode } => outcome(session.chmod(path, *mode), |_| 0),
+            Op::Chown { path, uid, gid } => {
+                let path = c_path(path);
+                session.call(|ops| unsafe {
+                    ops.chown.unwrap()(path.as_ptr(), *uid, *gid, std::ptr::null_mut())
+                })
+            }
+            Op::Utimens { path, times } => {
+                let path = c_path(path);
+                let times = times.map(|times| {
+                    times.map(|(sec, nsec)| timespec {
+                        tv_sec: sec as _,
+                        tv_nsec: nsec as _,
+                    })
+                });
+                let tv = times
+                    .as_ref()
+                    .map_or(std::ptr::null(), |times| times.as_ptr());
+                session.call(|ops| unsafe {
+                    ops.utimens.unwrap()(path.as_ptr(), tv, std::ptr::null_mut())
+                })
+            }
+            Op::Truncate { path, size } => outcome(session.truncate(path, *size), |_| 0),
+            Op::Setxattr {
+                path,
+                name,
+                value,
+                flags,
+            } => {
+                let (path, name) = (c_path(path), c_path(name));
+                session.call(|ops| unsafe {
+                    ops.setxattr.unwrap()(
+                        path.as_ptr(),
+                        name.as_ptr(),
+                        value.as_ptr() as *const _,
+                        value.len(),
+                        *flags,
+                    )
+                })
+            }
+            Op::Removexattr { path, name } => {
+                let (path, name) = (c_path(path), c_path(name));
+                session
+                    .call(|ops| unsafe { ops.removexattr.unwrap()(path.as_ptr(), name.as_ptr()) })
+            }
+            Op::Readdir { path } => outcome(session.readdir(path), |_| 0),
+            Op::Opendir { path, fh } => {
+                let path = c_path(path);
+                let mut fi = fuse_file_info::default();
+                let out =
+                    session.call(|ops| unsafe { ops.opendir.unwrap()(path.as_ptr(), &mut fi) });
+                if out >= 0 || out == -libc::ENOSYS {
+                    dirs.insert(*fh, fi);
+                }
+                out
+            }
+            Op::Releasedir { path, fh } => {
+                let Some(mut fi) = dirs.remove(fh) else {
+                    continue;
+                };
+                let path = c_path(path);
+                session.call(|ops| unsafe { ops.releasedir.unwrap()(path.as_ptr(), &mut fi) })
+            }
+            // These are made directly rather than through the helpers, which
+            // fill in for what libfuse does when they're missing.
+            Op::Open { path, flags, fh } => {
+                let path = c_path(path);
+                let mut fi = fuse_file_info {
+                    flags: *flags,
+                    ..Default::default()
+                };
+                let out = session.call(|ops| unsafe { ops.open.unwrap()(path.as_ptr(), &mut fi) });
+                if out >= 0 || out == -libc::ENOSYS {
+                    open.insert(*fh, fi);
+                }
+                out
+            }
+            Op::Create {
+                path,
+                mode,
+                flags,
+                fh,
+            } => {
+                let path = c_path(path);
+                let mut fi = fuse_file_info {
+                    flags: *flags,
+                    ..Default::default()
+                };
+                let out = session
+                    .call(|ops| unsafe { ops.create.unwrap()(path.as_ptr(), *mode, &mut fi) });
+                if out >= 0 {
+                    open.insert(*fh, fi);
+                }
+                out
+            }
+            Op::Read {
+                path,
+                fh,
+                off,
+                size,
+                checksum: recorded,
+            } => {
+                let Some(fi) = open.get_mut(fh) else {
+                    continue;
+                };
+                let out = session.read(path, fi, *size, *off);
+                if let Ok(data) = &out {
+                    data_differs = checksum(data) != *recorded;
+                }
+                outcome(out, |data| data.len() as i32)
+            }
+            Op::Write {
+                path,
+                fh,
+                off,
+                data,
+            } => {
+                let Some(fi) = open.get_mut(fh) else {
+                    continue;
+                };
+                outcome(session.write(path, fi, data, *off), |n| n as i32)
+            }
+            Op::Flush { path, fh } | Op::Fsync { path, fh } | Op::Release { path, fh } => {
+                let Some(mut fi) = open.get(fh).copied() else {
+                    continue;
+                };
+                let path = c_path(path);
+                let out = session.call(|ops| unsafe {
+                    match &record.op {
+                        Op::Flush { .. } => ops.flush.unwrap()(path.as_ptr(), &mut fi),
+                        Op::Fsync { .. } => ops.fsync.unwrap()(path.as_ptr(), 0, &mut fi),
+                        _ => ops.release.unwrap()(path.as_ptr(), &mut fi),
+                    }
+                });
+                if let Op::Release { .. } = record.op {
+                    open.remove(fh);
+                }
+                out
+            }
+            Op::Fallocate {
+                path,
+                fh,
+                mode,
+                off,
+                len,
+            } => {
+                let Some(fi) = open.get_mut(fh) else {
+                    continue;
+                };
+                let path = c_path(path);
+                session.call(|ops| unsafe {
+                    ops.fallocate.unwrap()(path.as_ptr(), *mode, *off, *len, fi)
+                })
+            }
+            #[cfg(fuse_3_4)]
+            Op::CopyFileRange {
+                path_in,
+                fh_in,
+                off_in,
+                path_out,
+                fh_out,
+                off_out,
+                size,
+                flags,
+            } => {
+                let (Some(mut fi_in), Some(mut fi_out)) =
+                    (open.get(fh_in).copied(), open.get(fh_out).copied())
+                else {
+                    continue;
+                };
+                let (path_in, path_out) = (c_path(path_in), c_path(path_out));
+                let out = session.call(|ops| unsafe {
+                    ops.copy_file_range.unwrap()(
+                        path_in.as_ptr(),
+                        &mut fi_in,
+                        *off_in,
+                        path_out.as_ptr(),
+                        &mut fi_out,
+                        *off_out,
+                        *size,
+                        *flags,
+                    )
+                });
+                out as i32
+            }
+            Op::Fsyncdir { path, fh } => {
+                let Some(mut fi) = dirs.get(fh).copied() else {
+                    continue;
+                };
+                let path = c_path(path);
+                session.call(|ops| unsafe { ops.fsyncdir.unwrap()(path.as_ptr(), 0, &mut fi) })
+            }
+            Op::Statfs { path } => {
+                let path = c_path(path);
+                let mut stat = statvfs::default();
+                session.call(|ops| unsafe { ops.statfs.unwrap()(path.as_ptr(), &mut stat) })
+            }
+            Op::Getxattr { path, name, size } => {
+                let (path, name) = (c_path(path), c_path(name));
+                let mut value = vec![0u8; *size];
+                session.call(|ops| unsafe {
+                    ops.getxattr.unwrap()(
+                        path.as_ptr(),
+                        name.as_ptr(),
+                        value.as_mut_ptr() as *mut _,
+                        value.len(),
+                    )
+                })
+            }
+            Op::Listxattr { path, size } => {
+                let path = c_path(path);
+                let mut list = vec![0u8; *size];
+                session.call(|ops| unsafe {
+                    ops.listxattr.unwrap()(path.as_ptr(), list.as_mut_ptr() as *mut _, list.len())
+                })
+            }
+            Op::Access { path, mask } => {
+                let path = c_path(path);
+                session.call(|ops| unsafe { ops.access.unwrap()(path.as_ptr(), *mask) })
+            }
+            Op::Lock {
+                path,
+                fh,
+                cmd,
+                kind,
+                whence,
+                start,
+                len,
+            } => {
+                let Some(mut fi) = open.get(fh).copied() else {
+                    continue;
+                };
+                let path = c_path(path);
+                let mut lock = flock {
+                    l_type: *kind as _,
+                    l_whence: *whence as _,
+                    l_start: *start,
+                    l_len: *len,
+                    ..Default::default()
+                };
+                session.call(|ops| unsafe {
+                    ops.lock.unwrap()(path.as_ptr(), &mut fi, *cmd, &mut lock)
+                })
+            }
+            Op::Flock {
+                path,
+                fh,
+                operation,
+            } => {
+                let Some(mut fi) = open.get(fh).copied() else {
+                    continue;
+                };
+                let path = c_path(path);
+                session
+                    .call(|ops| unsafe { ops.flock.unwrap()(path.as_ptr(), &mut fi, *operation) })
+            }
+            Op::Bmap {
+                path,
+                blocksize,
+                index,
+            } => {
+                let path = c_path(path);
+                let mut index = *index;
+                session
+                    .call(|ops| unsafe { ops.bmap.unwrap()(path.as_ptr(), *blocksize, &mut index) })
+            }
+            Op::Ioctl { .. }
+            | Op::Poll { .. }
+            | Op::WriteBuf { .. }
+            | Op::ReadBuf { .. }
+            | Op::Other { .. } => continue,
+        };
+
+        if result != record.result || data_differs {
+            divergences.push(Divergence {
+                index,
+                record: record.clone(),
+                result,
+                data_differs,
+            });
+        }
+    }
+
+    divergences
+}
@@ -0,0 +1,816 @@
+//! Drives the adapters through the mock, over an in-memory filesystem.
+#![cfg(all(feature = "testing", not(feature = "fuse2")))]
+
+use fuse_sys::{
+    adapters::{
+        Cached, Class, Fault, FaultInjector, Limits, Metered, Policy, ReadCache, ReadOnly, Rule,
+        Throttled, WriteBack,
+    },
+    fuse_file_info,
+    memfs::MemFs,
+    testing::{memfs, MockSession},
+    Call, Dispatch, FileSystem, Operation,
+};
+use nix::errno::Errno;
+use std::{
+    io,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+fn memfs_with_file() -> MemFs {
+    memfs(&[("/dir/a", b"hello")])
+}
+
+#[test]
+fn read_only() {
+    let mut session = MockSession::new(ReadOnly::new(memfs_with_file()));
+    let erofs = Some(libc::EROFS);
+
+    assert_eq!(session.read_all("/dir/a").unwrap(), b"hello");
+    assert_eq!(session.readdir("/dir").unwrap(), [".", "..", "a"]);
+
+    assert_eq!(
+        session
+            .write_all("/dir/a", b"bye")
+            .unwrap_err()
+            .raw_os_error(),
+        erofs
+    );
+    assert_eq!(
+        session
+            .open("/dir/a", libc::O_RDWR)
+            .unwrap_err()
+            .raw_os_error(),
+        erofs
+    );
+    assert_eq!(
+        session.mkdir("/new", 0o755).unwrap_err().raw_os_error(),
+        erofs
+    );
+    assert_eq!(session.unlink("/dir/a").unwrap_err().raw_os_error(), erofs);
+    assert_eq!(
+        session.rename("/dir/a", "/b").unwrap_err().raw_os_error(),
+        erofs
+    );
+    assert_eq!(
+        session.truncate("/dir/a", 0).unwrap_err().raw_os_error(),
+        erofs
+    );
+
+    // Copying writes to the destination without it being opened for writing.
+    #[cfg(fuse_3_4)]
+    {
+        let mut fi = session.open("/dir/a", libc::O_RDONLY).unwrap();
+        let mut fi_out = fi;
+        let err = session
+            .copy_file_range("/dir/a", &mut fi, 0, "/dir/a", &mut fi_out, 5, 5)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), erofs);
+        session.release("/dir/a", &mut fi).unwrap();
+    }
+
+    assert_eq!(session.read_all("/dir/a").unwrap(), b"hello");
+}
+
+#[test]
+fn metered() {
+    let mut session = MockSession::new(Metered::new(memfs_with_file()));
+
+    assert_eq!(session.read_all("/dir/a").unwrap(), b"hello");
+    session.write_all("/dir/b", b"bye").unwrap();
+    assert!(session.unlink("/missing").is_err());
+
+    let metrics = session.filesystem().metrics();
+    let snapshot = metrics.snapshot();
+
+    assert_eq!(snapshot[&Operation::Read].bytes, 5);
+    assert_eq!(snapshot[&Operation::Write].bytes, 3);
+    assert_eq!(snapshot[&Operation::Unlink].calls, 1);
+    assert_eq!(snapshot[&Operation::Unlink].errors[&libc::ENOENT], 1);
+    assert_eq!(
+        snapshot[&Operation::Read].latency.count,
+        snapshot[&Operation::Read].calls
+    );
+
+    let mut text = vec![];
+    metrics.write_prometheus(&mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+
+    assert!(text.contains("fuse_bytes_total{op=\"read\"} 5"));
+    assert!(text.contains("fuse_errors_total{op=\"unlink\",errno=\"ENOENT\"} 1"));
+}
+
+#[test]
+fn cached() {
+    let fs = Cached::new(Metered::new(memfs_with_file())).with_ttl(Duration::from_millis(100));
+    let mut session = MockSession::new(fs);
+    let calls = |session: &MockSession<Cached<Metered<MemFs>>>, operation: Operation| {
+        let snapshot = session.filesystem().inner().snapshot();
+        snapshot.get(&operation).map_or(0, |metrics| metrics.calls)
+    };
+
+    session.getattr("/dir/a").unwrap();
+    session.getattr("/dir/a").unwrap();
+    assert!(session.getattr("/missing").is_err());
+    assert!(session.getattr("/missing").is_err());
+    assert_eq!(calls(&session, Operation::Getattr), 2);
+
+    assert_eq!(session.readdir("/dir").unwrap(), [".", "..", "a"]);
+    assert_eq!(session.readdir("/dir").unwrap(), [".", "..", "a"]);
+    assert_eq!(calls(&session, Operation::Readdir), 1);
+
+    // Changes through the cache are seen straight away.
+    session.write_all("/dir/b", b"bye").unwrap();
+    assert_eq!(session.readdir("/dir").unwrap(), [".", "..", "a", "b"]);
+    assert_eq!(session.getattr("/dir/b").unwrap().st_size, 3);
+    session.truncate("/dir/a", 1).unwrap();
+    assert_eq!(session.getattr("/dir/a").unwrap().st_size, 1);
+
+    let before = calls(&session, Operation::Getattr);
+    session.getattr("/dir/a").unwrap();
+    assert_eq!(calls(&session, Operation::Getattr), before);
+
+    thread::sleep(Duration::from_millis(150));
+    session.getattr("/dir/a").unwrap();
+    assert_eq!(calls(&session, Operation::Getattr), before + 1);
+}
+
+#[cfg(fuse_3_4)]
+#[test]
+fn cached_copy_file_range() {
+    let fs = Cached::new(memfs_with_file()).with_ttl(Duration::from_secs(60));
+    let mut session = MockSession::new(fs);
+
+    session.write_all("/dir/b", b"bye").unwrap();
+    assert_eq!(session.getattr("/dir/b").unwrap().st_size, 3);
+
+    // Copying changes the destination, which was never opened for writing
+    // through the cache.
+    let mut fi_in = session.open("/dir/a", libc::O_RDONLY).unwrap();
+    let mut fi_out = session.open("/dir/b", libc::O_WRONLY).unwrap();
+    let copied = session
+        .copy_file_range("/dir/a", &mut fi_in, 0, "/dir/b", &mut fi_out, 0, 5)
+        .unwrap();
+    assert_eq!(copied, 5);
+    session.release("/dir/a", &mut fi_in).unwrap();
+    session.release("/dir/b", &mut fi_out).unwrap();
+
+    assert_eq!(session.getattr("/dir/b").unwrap().st_size, 5);
+}
+
+/// Ten 4 KiB blocks of data in `/big`.
+fn memfs_with_blocks() -> (MemFs, Vec<u8>) {
+    let data: Vec<u8> = (0..10 * 4096).map(|i| (i % 251) as u8).collect();
+    (memfs(&[("/big", &data[..])]), data)
+}
+
+#[test]
+fn read_cache() {
+    let (fs, data) = memfs_with_blocks();
+    let fs = ReadCache::new(Metered::new(fs))
+        .with_block_size(4096)
+        .with_readahead(3);
+    let mut session = MockSession::new(fs);
+    let reads = |session: &MockSession<ReadCache<Metered<MemFs>>>| {
+        let snapshot = session.filesystem().inner().snapshot();
+        snapshot[&Operation::Read].calls
+    };
+
+    let mut fi = session.open("/big", libc::O_RDONLY).unwrap();
+    let mut read = vec![];
+    loop {
+        let chunk = session
+            .read("/big", &mut fi, 1024, read.len() as _)
+            .unwrap();
+        if chunk.is_empty() {
+            break;
+        }
+        read.extend(chunk);
+    }
+    session.release("/big", &mut fi).unwrap();
+
+    assert_eq!(read, data);
+    // Three fetches of four blocks, the last reaching past the end.
+    let stats = session.filesystem().stats();
+    assert_eq!((stats.misses, stats.prefetched), (3, 9));
+    assert_eq!(reads(&session), 4);
+
+    assert_eq!(session.read_all("/big").unwrap(), data);
+    assert_eq!(reads(&session), 4);
+
+    // Writing drops the cached blocks.
+    let mut fi = session.open("/big", libc::O_RDWR).unwrap();
+    session.write("/big", &mut fi, b"changed", 0).unwrap();
+    assert_eq!(session.read("/big", &mut fi, 7, 0).unwrap(), b"changed");
+    session.release("/big", &mut fi).unwrap();
+    assert_eq!(session.filesystem().stats().misses, 4);
+}
+
+#[cfg(fuse_3_4)]
+#[test]
+fn read_cache_copy_file_range() {
+    let fs = ReadCache::new(memfs(&[("/a", b"hello"), ("/b", b"world")])).with_block_size(4096);
+    let mut session = MockSession::new(fs);
+
+    let mut fi_in = session.open("/a", libc::O_RDONLY).unwrap();
+    let mut fi_out = session.open("/b", libc::O_RDWR).unwrap();
+    assert_eq!(session.read("/b", &mut fi_out, 64, 0).unwrap(), b"world");
+
+    session
+        .copy_file_range("/a", &mut fi_in, 0, "/b", &mut fi_out, 0, 5)
+        .unwrap();
+    assert_eq!(session.read("/b", &mut fi_out, 64, 0).unwrap(), b"hello");
+
+    session.release("/a", &mut fi_in).unwrap();
+    session.release("/b", &mut fi_out).unwrap();
+}
+
+/// Takes a while over every read, like a remote filesystem.
+struct Slow {
+    inner: MemFs,
+    reads: AtomicUsize,
+}
+
+impl Dispatch for Slow {
+    fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+        if let Call::Read(..) = call {
+            thread::sleep(Duration::from_millis(100));
+            self.reads.fetch_add(1, Ordering::Relaxed);
+        }
+        call.forward(&self.inner)
+    }
+}
+
+#[test]
+fn read_cache_coalesces() {
+    let (inner, data) = memfs_with_blocks();
+    let slow = Slow {
+        inner,
+        reads: AtomicUsize::new(0),
+    };
+    let fs = ReadCache::new(slow).with_block_size(4096).with_readahead(0);
+
+    let mut fi = fuse_file_info::default();
+    fs.open("/big", Some(&mut fi)).unwrap();
+
+    thread::scope(|scope| {
+        for _ in 0..4 {
+            let (fs, data) = (&fs, &data);
+            let mut fi = fi;
+
+            scope.spawn(move || {
+                let mut buf = vec![0; 4096];
+                let n = fs.read("/big", &mut buf, 0, Some(&mut fi)).unwrap();
+                assert_eq!(&buf[..n as usize], &data[..4096]);
+            });
+        }
+    });
+
+    assert_eq!(fs.inner().reads.load(Ordering::Relaxed), 1);
+    let stats = fs.stats();
+    assert_eq!((stats.misses, stats.hits + stats.coalesced), (1, 3));
+}
+
+/// Panics in the middle of every read.
+struct Panicking(MemFs);
+
+impl Dispatch for Panicking {
+    fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+        if let Call::Read(..) = call {
+            thread::sleep(Duration::from_millis(100));
+            panic!("read failed");
+        }
+        call.forward(&self.0)
+    }
+}
+
+#[test]
+fn read_cache_survives_panics() {
+    let (inner, _) = memfs_with_blocks();
+    let fs = ReadCache::new(Panicking(inner)).with_block_size(4096);
+
+    let mut fi = fuse_file_info::default();
+    fs.open("/big", Some(&mut fi)).unwrap();
+
+    // Whoever's waiting on the read that panicked gets an error instead.
+    thread::scope(|scope| {
+        let fs = &fs;
+        let reader = |delay| {
+            let mut fi = fi;
+            scope.spawn(move || {
+                thread::sleep(delay);
+                let mut buf = vec![0; 4096];
+                fs.read("/big", &mut buf, 0, Some(&mut fi))
+            })
+        };
+
+        let fetching = reader(Duration::ZERO);
+        let waiting = reader(Duration::from_millis(20));
+
+        assert!(fetching.join().is_err());
+        let err = waiting.join().unwrap().unwrap_err();
+        assert_eq!(err.downcast_ref::<Errno>(), Some(&Errno::EIO));
+    });
+    assert_eq!(fs.stats().coalesced, 1);
+}
+
+#[test]
+fn read_cache_evicts() {
+    let fs = ReadCache::new(memfs(&[
+        ("/0", &[0; 4096]),
+        ("/1", &[1; 4096]),
+        ("/2", &[2; 4096]),
+        ("/3", &[3; 4096]),
+    ]))
+    .with_block_size(4096)
+    .with_capacity(2 * 4096)
+    .with_readahead(0);
+    let mut session = MockSession::new(fs);
+
+    for i in 0..4u8 {
+        assert_eq!(session.read_all(&format!("/{i}")).unwrap(), [i; 4096]);
+    }
+
+    // Changes behind the cache's back are still noticed on opening, whether
+    // the file's blocks were evicted or not.
+    for i in [0u8, 3] {
+        let path = format!("/{i}");
+        FileSystem::truncate(session.filesystem().inner(), &path, 1, None).unwrap();
+        assert_eq!(session.read_all(&path).unwrap(), [i]);
+    }
+}
+
+#[test]
+fn write_back() {
+    let fs = WriteBack::new(Metered::new(MemFs::new())).with_threshold(16);
+    let mut session = MockSession::new(fs);
+    let writes = |session: &MockSession<WriteBack<Metered<MemFs>>>| {
+        let snapshot = session.filesystem().inner().snapshot();
+        snapshot
+            .get(&Operation::Write)
+            .map_or(0, |metrics| metrics.calls)
+    };
+
+    let mut fi = session.create("/a", 0o644, libc::O_RDWR).unwrap();
+    session.write("/a", &mut fi, b"hel", 0).unwrap();
+    session.write("/a", &mut fi, b"lo", 3).unwrap();
+    assert_eq!(writes(&session), 0);
+    assert_eq!(session.filesystem().dirty(), 5);
+
+    // The open file sees its own writes, as does the file's size.
+    assert_eq!(session.read("/a", &mut fi, 64, 0).unwrap(), b"hello");
+    assert_eq!(session.getattr("/a").unwrap().st_size, 5);
+
+    // Going over the threshold writes everything back in one go.
+    session.write("/a", &mut fi, b" there, world", 5).unwrap();
+    assert_eq!(writes(&session), 1);
+    assert_eq!(session.filesystem().dirty(), 0);
+
+    session.write("/a", &mut fi, b"!", 18).unwrap();
+    session.flush("/a", &mut fi).unwrap();
+    assert_eq!(writes(&session), 2);
+    session.release("/a", &mut fi).unwrap();
+
+    assert_eq!(session.read_all("/a").unwrap(), b"hello there, world!");
+
+    // Renaming an open file writes it back first, then follows it.
+    let mut fi = session.open("/a", libc::O_WRONLY).unwrap();
+    session.write("/a", &mut fi, b"H", 0).unwrap();
+    session.rename("/a", "/b").unwrap();
+    session.write("/b", &mut fi, b"W", 13).unwrap();
+    session.release("/b", &mut fi).unwrap();
+    assert_eq!(session.read_all("/b").unwrap(), b"Hello there, World!");
+
+    // Writes out of order are joined up once they meet.
+    let mut fi = session.create("/c", 0o644, libc::O_RDWR).unwrap();
+    session.write("/c", &mut fi, b"ab", 0).unwrap();
+    session.write("/c", &mut fi, b"efg", 4).unwrap();
+    session.write("/c", &mut fi, b"cd", 2).unwrap();
+    session.write("/c", &mut fi, b"DE", 3).unwrap();
+    assert_eq!(session.filesystem().dirty(), 7);
+    assert_eq!(session.read("/c", &mut fi, 64, 0).unwrap(), b"abcDEfg");
+    session.release("/c", &mut fi).unwrap();
+    assert_eq!(session.read_all("/c").unwrap(), b"abcDEfg");
+}
+
+#[cfg(fuse_3_4)]
+#[test]
+fn write_back_copy_file_range() {
+    let fs = WriteBack::new(memfs(&[("/a", b"hello"), ("/b", b"xxxxxxx")]));
+    let mut session = MockSession::new(fs);
+
+    // Both ends have writes buffered. The source's must be copied, and the
+    // destination's mustn't land on top of the copy later.
+    let mut fi_in = session.open("/a", libc::O_RDWR).unwrap();
+    let mut fi_out = session.open("/b", libc::O_RDWR).unwrap();
+    session.write("/a", &mut fi_in, b"HE", 0).unwrap();
+    session.write("/b", &mut fi_out, b"yy", 0).unwrap();
+
+    session
+        .copy_file_range("/a", &mut fi_in, 0, "/b", &mut fi_out, 0, 5)
+        .unwrap();
+    assert_eq!(session.filesystem().dirty(), 0);
+    assert_eq!(session.read("/b", &mut fi_out, 64, 0).unwrap(), b"HElloxx");
+
+    session.release("/a", &mut fi_in).unwrap();
+    session.release("/b", &mut fi_out).unwrap();
+    assert_eq!(session.read_all("/b").unwrap(), b"HElloxx");
+}
+
+/// Fails every write while it's full, like a disk that's run out of space.
+struct Full {
+    inner: MemFs,
+    full: AtomicBool,
+}
+
+impl Dispatch for Full {
+    fn dispatch(&self, call: Call<'_>) -> anyhow::Result<i32> {
+        if let Call::Write(..) = call {
+            if self.full.load(Ordering::Relaxed) {
+                return Err(io::Error::from_raw_os_error(libc::ENOSPC).into());
+            }
+        }
+        call.forward(&self.inner)
+    }
+}
+
+#[test]
+fn write_back_reports_errors() {
+    let full = Full {
+        inner: MemFs::new(),
+        full: AtomicBool::new(true),
+    };
+    let mut session = MockSession::new(WriteBack::new(full));
+
+    let mut fi = session.create("/a", 0o644, libc::O_WRONLY).unwrap();
+    session.write("/a", &mut fi, b"hello", 0).unwrap();
+
+    let err = session.flush("/a", &mut fi).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+    assert_eq!(session.filesystem().dirty(), 5);
+
+    // The writes are kept until they can be passed on.
+    session
+        .filesystem()
+        .inner()
+        .full
+        .store(false, Ordering::Relaxed);
+    session.fsync("/a", &mut fi).unwrap();
+    assert_eq!(session.filesystem().dirty(), 0);
+    session.release("/a", &mut fi).unwrap();
+
+    assert_eq!(session.read_all("/a").unwrap(), b"hello");
+
+    // Release still fails when the last writes can't be passed on.
+    let mut fi = session.open("/a", libc::O_WRONLY).unwrap();
+    session.write("/a", &mut fi, b"bye", 0).unwrap();
+    session
+        .filesystem()
+        .inner()
+        .full
+        .store(true, Ordering::Relaxed);
+    let err = session.release("/a", &mut fi).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+    assert_eq!(session.filesystem().dirty(), 0);
+}
+
+#[test]
+fn write_back_keeps_writes_over_the_threshold() {
+    let full = Full {
+        inner: MemFs::new(),
+        full: AtomicBool::new(true),
+    };
+    let mut session = MockSession::new(WriteBack::new(full).with_threshold(4));
+
+    // The write is taken even though passing it on fails, and the failure
+    // goes to the next flush.
+    let mut fi = session.create("/a", 0o644, libc::O_RDWR).unwrap();
+    assert_eq!(session.write("/a", &mut fi, b"hello", 0).unwrap(), 5);
+    assert_eq!(session.filesystem().dirty(), 5);
+    assert_eq!(session.read("/a", &mut fi, 64, 0).unwrap(), b"hello");
+
+    let err = session.flush("/a", &mut fi).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+
+    session
+        .filesystem()
+        .inner()
+        .full
+        .store(false, Ordering::Relaxed);
+    session.write("/a", &mut fi, b"!", 5).unwrap();
+    assert_eq!(session.filesystem().dirty(), 0);
+    session.release("/a", &mut fi).unwrap();
+
+    assert_eq!(session.read_all("/a").unwrap(), b"hello!");
+}
+
+#[test]
+fn fault_injector() {
+    let fs = FaultInjector::new(memfs_with_file())
+        .with_rule(
+            Rule::new(Fault::Error(Errno::EIO))
+                .on(Operation::Read)
+                .at("/dir/*")
+                .nth(2),
+        )
+        .with_rule(Rule::new(Fault::Short(2)).on(Operation::Write))
+        .with_control_file("/.faults");
+    let mut session = MockSession::new(fs);
+
+    let mut fi = session.open("/dir/a", libc::O_RDONLY).unwrap();
+    assert_eq!(session.read("/dir/a", &mut fi, 64, 0).unwrap(), b"hello");
+    assert_eq!(
+        session
+            .read("/dir/a", &mut fi, 64, 0)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EIO)
+    );
+    assert_eq!(session.read("/dir/a", &mut fi, 64, 0).unwrap(), b"hello");
+    session.release("/dir/a", &mut fi).unwrap();
+
+    let mut fi = session.create("/b", 0o644, libc::O_WRONLY).unwrap();
+    assert_eq!(session.write("/b", &mut fi, b"bye", 0).unwrap(), 2);
+    session.release("/b", &mut fi).unwrap();
+
+    assert_eq!(
+        session.read_all("/.faults").unwrap(),
+        b"read /dir/* EIO nth=2\nwrite * short=2\n"
+    );
+    assert_eq!(session.readdir("/").unwrap(), [".", "..", "b", "dir"]);
+
+    // Writing the control file replaces the rules.
+    session
+        .write_all(
+            "/.faults",
+            b"# No more short writes\nunlink /dir/** EACCES\n",
+        )
+        .unwrap();
+    assert_eq!(
+        session.unlink("/dir/a").unwrap_err().raw_os_error(),
+        Some(libc::EACCES)
+    );
+    session.write_all("/b", b"bye").unwrap();
+    assert_eq!(session.read_all("/b").unwrap(), b"bye");
+
+    // Rules that don't parse are refused, leaving the old ones.
+    assert_eq!(
+        session
+            .write_all("/.faults", b"read * EWHAT\n")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EINVAL)
+    );
+    assert_eq!(
+        session.read_all("/.faults").unwrap(),
+        b"unlink /dir/** EACCES\n"
+    );
+
+    // Each handle writes its own rules.
+    let mut first = session.open("/.faults", libc::O_WRONLY).unwrap();
+    let mut second = session.open("/.faults", libc::O_WRONLY).unwrap();
+    session
+        .write("/.faults", &mut first, b"read * EIO\n", 0)
+        .unwrap();
+    session
+        .write("/.faults", &mut second, b"write * EIO\n", 0)
+        .unwrap();
+    session.release("/.faults", &mut first).unwrap();
+    assert_eq!(session.read_all("/.faults").unwrap(), b"read * EIO\n");
+    session.release("/.faults", &mut second).unwrap();
+    assert_eq!(session.read_all("/.faults").unwrap(), b"write * EIO\n");
+}
+
+#[test]
+fn fault_injector_checks_arguments() {
+    let rule = Rule::new(Fault::Error(Errno::EIO)).with_probability(1.5);
+    assert_eq!(rule.to_string(), "* * EIO p=1");
+    let rule = Rule::new(Fault::Error(Errno::EIO)).with_probability(f64::NAN);
+    assert_eq!(rule.to_string(), "* * EIO p=0");
+
+    for line in ["read * EIO p=1.5", "read * EIO p=-0.1", "read * EIO p=NaN"] {
+        assert!(line.parse::<Rule>().is_err(), "{line}");
+    }
+    assert_eq!(
+        "read * EIO p=0.5".parse::<Rule>().unwrap().to_string(),
+        "read * EIO p=0.5"
+    );
+
+    let fs = FaultInjector::new(MemFs::new()).with_control_file("/.faults");
+    let err = fs.getattr("/.faults", None, None).unwrap_err();
+    assert_eq!(err.downcast_ref::<Errno>(), Some(&Errno::EINVAL));
+}
+
+#[test]
+fn throttled() {
+    let limits = Limits::new().ops(Class::Read, 2.0).write_bytes(10.0);
+    let fs = Throttled::new(memfs_with_file())
+        .with_limits(limits.clone())
+        .with_policy(Policy::Fail);
+    let mut session = MockSession::new(fs);
+    let eagain = Some(libc::EAGAIN);
+
+    let mut fi = session.open("/dir/a", libc::O_RDONLY).unwrap();
+    session.read("/dir/a", &mut fi, 5, 0).unwrap();
+    session.read("/dir/a", &mut fi, 5, 0).unwrap();
+    assert_eq!(
+        session
+            .read("/dir/a", &mut fi, 5, 0)
+            .unwrap_err()
+            .raw_os_error(),
+        eagain
+    );
+    session.release("/dir/a", &mut fi).unwrap();
+
+    let mut fi = session.create("/b", 0o644, libc::O_WRONLY).unwrap();
+    session.write("/b", &mut fi, b"12345678", 0).unwrap();
+    assert_eq!(
+        session
+            .write("/b", &mut fi, b"12345678", 8)
+            .unwrap_err()
+            .raw_os_error(),
+        eagain
+    );
+    session.release("/b", &mut fi).unwrap();
+
+    // Limits for a user replace the ones everyone else has.
+    let uid = unsafe { libc::geteuid() };
+    let fs = Throttled::new(memfs_with_file())
+        .with_limits(limits)
+        .with_uid_limits(uid, Limits::new())
+        .with_policy(Policy::Fail);
+    let mut session = MockSession::new(fs);
+    for _ in 0..3 {
+        assert_eq!(session.read_all("/dir/a").unwrap(), b"hello");
+    }
+
+    // By default, calls over the limits wait. Ten go through straight away
+    // and the next five take half a second.
+    let fs =
+        Throttled::new(memfs_with_file()).with_total_limits(Limits::new().ops(Class::All, 10.0));
+    let mut session = MockSession::new(fs);
+    let start = Instant::now();
+    for _ in 0..15 {
+        session.getattr("/dir/a").unwrap();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(400));
+}
+
+#[test]
+fn throttled_clamps_bad_rates() {
+    // Too slow to let two calls through in a test.
+    for rate in [0.0, -1.0, f64::NAN, 1e-9] {
+        let limits = Limits::new().ops(Class::All, rate);
+        let fs = Throttled::new(memfs_with_file())
+            .with_total_limits(limits)
+            .with_policy(Policy::Fail);
+        let mut session = MockSession::new(fs);
+
+        session.getattr("/dir/a").unwrap();
+        let err = session.getattr("/dir/a").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EAGAIN), "{rate}");
+    }
+
+    // No limit at all.
+    let limits = Limits::new()
+        .ops(Class::All, 1.0)
+        .ops(Class::All, f64::INFINITY);
+    let fs = Throttled::new(memfs_with_file())
+        .with_total_limits(limits)
+        .with_policy(Policy::Fail);
+    let mut session = MockSession::new(fs);
+    for _ in 0..10 {
+        session.getattr("/dir/a").unwrap();
+    }
+}
+
+#[cfg(feature = "tracing")]
+mod traced {
+    use super::memfs_with_file;
+    use fuse_sys::{adapters::Traced, testing::MockSession};
+    use std::{
+        collections::BTreeMap,
+        fmt,
+        sync::{Arc, Mutex},
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span, subscriber, Event, Metadata, Subscriber,
+    };
+
+    /// The spans and events a [`Traced`] emits, with each event's fields
+    /// merged with those of the span it's in.
+    #[derive(Clone, Default)]
+    struct Captured {
+        spans: Arc<Mutex<Vec<Fields>>>,
+        entered: Arc<Mutex<Vec<usize>>>,
+        events: Arc<Mutex<Vec<Fields>>>,
+    }
+
+    type Fields = BTreeMap<String, String>;
+
+    struct Visitor<'a>(&'a mut Fields);
+
+    impl Visit for Visitor<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    impl Captured {
+        /// The events with `message`, e.g. `finished`, of `op`'s spans.
+        fn events(&self, op: &str, message: &str) -> Vec<Fields> {
+            self.events
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|fields| {
+                    fields.get("op").map(String::as_str) == Some(op)
+                        && fields.get("message").map(String::as_str) == Some(message)
+                })
+                .cloned()
+                .collect()
+        }
+    }
+
+    impl Subscriber for Captured {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+            let mut fields = Fields::new();
+            span.record(&mut Visitor(&mut fields));
+
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(fields);
+            span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut Visitor(&mut spans[span.into_u64() as usize - 1]));
+        }
+
+        fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = match self.entered.lock().unwrap().last() {
+                Some(&span) => self.spans.lock().unwrap()[span - 1].clone(),
+                None => Fields::new(),
+            };
+            fields.insert("level".into(), event.metadata().level().to_string());
+            event.record(&mut Visitor(&mut fields));
+            self.events.lock().unwrap().push(fields);
+        }
+
+        fn enter(&self, span: &span::Id) {
+            self.entered.lock().unwrap().push(span.into_u64() as usize);
+        }
+
+        fn exit(&self, _span: &span::Id) {
+            self.entered.lock().unwrap().pop();
+        }
+    }
+
+    #[test]
+    fn traced() {
+        let captured = Captured::default();
+        let mut session = MockSession::new(Traced::new(memfs_with_file()));
+
+        subscriber::with_default(captured.clone(), || {
+            session.rename("/dir/a", "/b").unwrap();
+            assert_eq!(
+                session.unlink("/missing").unwrap_err().raw_os_error(),
+                Some(libc::ENOENT)
+            );
+        });
+
+        let started = captured.events("rename", "started");
+        assert_eq!(started.len(), 1);
+        assert_eq!(started[0]["level"], "TRACE");
+        assert!(started[0]["args"].contains("/dir/a"));
+
+        let finished = captured.events("rename", "finished");
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0]["level"], "DEBUG");
+        assert_eq!(finished[0]["path"], "/dir/a");
+        assert_eq!(finished[0]["to"], "/b");
+        assert_eq!(finished[0]["result"], "0");
+        assert!(finished[0].contains_key("latency"));
+
+        let failed = captured.events("unlink", "failed");
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0]["path"], "/missing");
+        assert!(!failed[0].contains_key("to"));
+        assert!(failed[0]["errno"].starts_with("ENOENT"));
+        assert!(captured.events("unlink", "finished").is_empty());
+    }
+}
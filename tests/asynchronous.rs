@@ -0,0 +1,120 @@
+//! Serves an async filesystem through the tokio adapter from a real mount.
+//! Skipped on hosts without `/dev/fuse`.
+#![cfg(all(feature = "async", feature = "testing", not(feature = "fuse2")))]
+
+use fuse_sys::{
+    lowlevel::{
+        asynchronous::{Attr, Entry},
+        AsyncFileSystem, Ino, Request, TokioAdapter, ROOT_INO,
+    },
+    stat,
+    testing::TempMount,
+};
+use nix::errno::Errno;
+use std::{
+    ffi::OsString,
+    fs,
+    future::{pending, Future},
+    io,
+    path::Path,
+    thread,
+    time::Duration,
+};
+use tokio::runtime::Builder;
+
+fn has_fuse() -> bool {
+    let present = Path::new("/dev/fuse").exists();
+    if !present {
+        eprintln!("Skipping, /dev/fuse is missing");
+    }
+    present
+}
+
+/// A root directory holding `file`, whose other lookups fail in each of the
+/// ways an error can be given.
+struct Backend;
+
+impl AsyncFileSystem for Backend {
+    fn getattr(
+        &self,
+        _req: Request,
+        ino: Ino,
+        _fh: Option<u64>,
+    ) -> impl Future<Output = anyhow::Result<Attr>> + Send {
+        async move {
+            match ino {
+                ROOT_INO => Ok(Attr {
+                    attr: stat {
+                        st_ino: ROOT_INO as _,
+                        st_mode: libc::S_IFDIR | 0o755,
+                        st_nlink: 2,
+                        ..Default::default()
+                    },
+                    ttl: Duration::ZERO,
+                }),
+                _ => Err(Errno::ENOENT.into()),
+            }
+        }
+    }
+
+    fn lookup(
+        &self,
+        _req: Request,
+        _parent: Ino,
+        name: OsString,
+    ) -> impl Future<Output = anyhow::Result<Entry>> + Send {
+        async move {
+            match name.to_str() {
+                Some("file") => Ok(Entry {
+                    attr: stat {
+                        st_ino: 2,
+                        st_mode: libc::S_IFREG | 0o644,
+                        st_nlink: 1,
+                        st_size: 5,
+                        ..Default::default()
+                    },
+                    ttl: Duration::ZERO,
+                    generation: 0,
+                }),
+                Some("io") => Err(io::Error::from_raw_os_error(libc::EACCES).into()),
+                Some("errno") => Err(Errno::EPERM.into()),
+                Some("other") => Err(io::Error::other("Unavailable").into()),
+                _ => Err(Errno::ENOENT.into()),
+            }
+        }
+    }
+}
+
+fn with_mount(test: impl FnOnce(&Path)) {
+    if !has_fuse() {
+        return;
+    }
+
+    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    let handle = runtime.handle().clone();
+    thread::spawn(move || runtime.block_on(pending::<()>()));
+
+    let mount = TempMount::lowlevel(TokioAdapter::new(Backend, handle))
+        .expect("Could not mount filesystem");
+    test(mount.path());
+}
+
+#[test]
+fn answers() {
+    with_mount(|mount| {
+        assert!(fs::metadata(mount).unwrap().is_dir());
+        assert_eq!(fs::metadata(mount.join("file")).unwrap().len(), 5);
+    });
+}
+
+#[test]
+fn maps_errors() {
+    with_mount(|mount| {
+        let errno = |name: &str| fs::metadata(mount.join(name)).unwrap_err().raw_os_error();
+
+        assert_eq!(errno("missing"), Some(libc::ENOENT));
+        assert_eq!(errno("io"), Some(libc::EACCES));
+        assert_eq!(errno("errno"), Some(libc::EPERM));
+        assert_eq!(errno("other"), Some(libc::ENOTRECOVERABLE));
+    });
+}
@@ -0,0 +1,24 @@
+//! Checks that the bindings checked into `bindings/` are what bindgen
+//! produces for the installed libfuse. Regenerate them with
+//! `FUSE_SYS_UPDATE_BINDINGS=1 cargo build`. Versions without checked-in
+//! bindings are skipped; CI notices those by regenerating into `bindings/`.
+#![cfg(feature = "bindgen")]
+
+use std::fs;
+
+#[test]
+fn pregenerated_bindings_match() {
+    let pregenerated = env!("FUSE_SYS_PREGENERATED");
+    if pregenerated.is_empty() {
+        eprintln!("Skipping, no pre-generated bindings for the installed libfuse");
+        return;
+    }
+
+    let generated = fs::read_to_string(concat!(env!("OUT_DIR"), "/bindings_generated.rs")).unwrap();
+    let checked_in = fs::read_to_string(pregenerated).unwrap();
+
+    assert!(
+        generated == checked_in,
+        "{pregenerated} is out of date, regenerate it with FUSE_SYS_UPDATE_BINDINGS=1"
+    );
+}
@@ -0,0 +1,51 @@
+//! Uses filesystems chosen at runtime through trait objects.
+#![cfg(all(feature = "testing", not(feature = "fuse2")))]
+
+use fuse_sys::{
+    adapters::ReadOnly,
+    memfs::MemFs,
+    overlay::Overlay,
+    testing::{memfs, MockSession},
+    FileSystem,
+};
+use std::sync::Arc;
+
+type DynFileSystem = Box<dyn FileSystem + Send + Sync>;
+
+#[test]
+fn boxed() {
+    for read_only in [false, true] {
+        let fs: DynFileSystem = match read_only {
+            true => Box::new(ReadOnly::new(memfs(&[("/a", b"hello")]))),
+            false => Box::new(memfs(&[("/a", b"hello")])),
+        };
+        let mut session = MockSession::new(fs);
+
+        assert_eq!(session.read_all("/a").unwrap(), b"hello");
+        assert_eq!(session.write_all("/b", b"").is_ok(), !read_only);
+    }
+}
+
+#[test]
+fn heterogeneous_layers() {
+    let lowers: Vec<DynFileSystem> = vec![
+        Box::new(ReadOnly::new(memfs(&[("/a", b"top")]))),
+        Box::new(memfs(&[("/b", b"bottom")])),
+    ];
+    let mut session = MockSession::new(Overlay::new(MemFs::new(), lowers));
+
+    assert_eq!(session.read_all("/a").unwrap(), b"top");
+    assert_eq!(session.read_all("/b").unwrap(), b"bottom");
+    session.write_all("/a", b"copied up").unwrap();
+    assert_eq!(session.read_all("/a").unwrap(), b"copied up");
+}
+
+#[test]
+fn shared() {
+    let fs = Arc::new(MemFs::new());
+    let mut first = MockSession::new(fs.clone());
+    let mut second = MockSession::new(fs);
+
+    first.write_all("/a", b"shared").unwrap();
+    assert_eq!(second.read_all("/a").unwrap(), b"shared");
+}
@@ -0,0 +1,158 @@
+//! Serves a low-level filesystem from a real mount. Skipped on hosts
+//! without `/dev/fuse`.
+#![cfg(all(feature = "testing", not(feature = "fuse2")))]
+
+use fuse_sys::{
+    fuse_file_info,
+    lowlevel::{
+        Ino, LowLevelFileSystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+        Session, ROOT_INO,
+    },
+    off_t, stat,
+    testing::TempMount,
+};
+use nix::errno::Errno;
+use std::{ffi::OsStr, fs, path::Path, thread, time::Duration};
+
+const FILE_INO: Ino = 2;
+const CONTENTS: &[u8] = b"hello";
+
+fn has_fuse() -> bool {
+    let present = Path::new("/dev/fuse").exists();
+    if !present {
+        eprintln!("Skipping, /dev/fuse is missing");
+    }
+    present
+}
+
+/// A root directory holding `file`, whose other lookups answer in unusual
+/// ways.
+struct Replies;
+
+fn attr(ino: Ino) -> Option<stat> {
+    let (mode, nlink, size) = match ino {
+        ROOT_INO => (libc::S_IFDIR | 0o755, 2, 0),
+        FILE_INO => (libc::S_IFREG | 0o644, 1, CONTENTS.len()),
+        _ => return None,
+    };
+
+    Some(stat {
+        st_ino: ino as _,
+        st_mode: mode,
+        st_nlink: nlink,
+        st_size: size as _,
+        ..Default::default()
+    })
+}
+
+impl LowLevelFileSystem for Replies {
+    fn getattr(&self, _req: &Request, ino: Ino, _fi: Option<&fuse_file_info>, reply: ReplyAttr) {
+        match attr(ino) {
+            Some(attr) => reply.attr(Duration::ZERO, &attr),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn lookup(&self, _req: &Request, _parent: Ino, name: &OsStr, reply: ReplyEntry) {
+        match name.to_str() {
+            Some("file") => reply.entry(Duration::ZERO, &attr(FILE_INO).unwrap(), 0),
+            Some("dropped") => drop(reply),
+            // Answered after the operation has returned.
+            Some("later") => {
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(50));
+                    reply.error(Errno::ENOENT);
+                });
+            }
+            _ => reply.negative(Duration::ZERO),
+        }
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        _ino: Ino,
+        size: usize,
+        off: off_t,
+        _fi: &fuse_file_info,
+        reply: ReplyData,
+    ) {
+        let start = (off as usize).min(CONTENTS.len());
+        let end = (start + size).min(CONTENTS.len());
+        reply.data(&CONTENTS[start..end]);
+    }
+
+    fn readdir(
+        &self,
+        _req: &Request,
+        _ino: Ino,
+        off: off_t,
+        _fi: &fuse_file_info,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = [
+            (ROOT_INO, libc::S_IFDIR, "."),
+            (ROOT_INO, libc::S_IFDIR, ".."),
+            (FILE_INO, libc::S_IFREG, "file"),
+        ];
+
+        for (i, (ino, mode, name)) in entries.into_iter().enumerate().skip(off as usize) {
+            if !reply.add(ino, i as off_t + 1, mode, OsStr::new(name)) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+}
+
+fn with_mount(test: impl FnOnce(&Path)) {
+    if !has_fuse() {
+        return;
+    }
+
+    let mount = TempMount::lowlevel(Replies).expect("Could not mount filesystem");
+    test(mount.path());
+}
+
+#[test]
+fn mounts() {
+    with_mount(|mount| {
+        assert!(fs::metadata(mount).unwrap().is_dir());
+        assert!(!mount.join("missing").exists());
+    });
+}
+
+#[test]
+fn rejects_unknown_options() {
+    assert!(Session::new(Replies, &["lowlevel", "-o", "no_such_option"]).is_err());
+}
+
+#[test]
+fn reads_and_lists() {
+    with_mount(|mount| {
+        assert_eq!(fs::read(mount.join("file")).unwrap(), CONTENTS);
+
+        let names: Vec<_> = fs::read_dir(mount)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["file"]);
+    });
+}
+
+#[test]
+fn dropped_reply_fails_with_eio() {
+    with_mount(|mount| {
+        let e = fs::metadata(mount.join("dropped")).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EIO));
+    });
+}
+
+#[test]
+fn replies_from_another_thread() {
+    with_mount(|mount| {
+        let e = fs::metadata(mount.join("later")).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+    });
+}
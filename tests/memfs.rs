@@ -0,0 +1,65 @@
+//! Runs the in-memory filesystem through the conformance suite. The
+//! mounted run is skipped on hosts without `/dev/fuse`.
+#![cfg(all(feature = "testing", not(feature = "fuse2")))]
+
+use fuse_sys::{
+    memfs::MemFs,
+    testing::{conformance, MockSession},
+};
+use std::path::Path;
+
+#[test]
+fn conformance_mock() {
+    conformance::check_mock(MemFs::new);
+}
+
+#[test]
+fn conformance_mounted() {
+    if Path::new("/dev/fuse").exists() {
+        conformance::check_mounted(MemFs::new);
+    } else {
+        eprintln!("Skipping, /dev/fuse is missing");
+    }
+}
+
+#[test]
+fn quota() {
+    let mut session = MockSession::new(MemFs::new().with_quota(8));
+
+    session.write_all("/a", b"12345678").unwrap();
+    let err = session.write_all("/b", b"9").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+
+    session.unlink("/a").unwrap();
+    session.write_all("/b", b"9").unwrap();
+    assert_eq!(session.filesystem().used(), 1);
+}
+
+#[test]
+fn readdir() {
+    let mut session = MockSession::new(MemFs::new());
+
+    session.mkdir("/dir", 0o755).unwrap();
+    session.write_all("/dir/a", b"").unwrap();
+    session.write_all("/dir/b", b"").unwrap();
+
+    assert_eq!(session.readdir("/dir").unwrap(), [".", "..", "a", "b"]);
+}
+
+#[cfg(fuse_3_4)]
+#[test]
+fn copy_file_range() {
+    let mut session = MockSession::new(MemFs::new());
+    session.write_all("/a", b"hello").unwrap();
+
+    let mut fi_in = session.open("/a", libc::O_RDONLY).unwrap();
+    let mut fi_out = session.create("/b", 0o644, libc::O_WRONLY).unwrap();
+    let copied = session
+        .copy_file_range("/a", &mut fi_in, 1, "/b", &mut fi_out, 2, 64)
+        .unwrap();
+    session.release("/a", &mut fi_in).unwrap();
+    session.release("/b", &mut fi_out).unwrap();
+
+    assert_eq!(copied, 4);
+    assert_eq!(session.read_all("/b").unwrap(), b"\0\0ello");
+}
@@ -0,0 +1,212 @@
+//! Invalidates the kernel's caches of real mounts through their notifiers.
+//! Skipped on hosts without `/dev/fuse`.
+#![cfg(all(feature = "testing", not(feature = "fuse2")))]
+
+use fuse_sys::{
+    fuse_file_info,
+    lowlevel::{Ino, LowLevelFileSystem, ReplyAttr, ReplyEntry, ReplyNone, Request, ROOT_INO},
+    memfs::MemFs,
+    off_t, stat,
+    testing::TempMount,
+};
+use nix::errno::Errno;
+use std::{
+    ffi::OsStr,
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+const FILE_INO: Ino = 2;
+/// Long enough that nothing expires while a test runs.
+const TTL: Duration = Duration::from_secs(3600);
+
+fn has_fuse() -> bool {
+    let present = Path::new("/dev/fuse").exists();
+    if !present {
+        eprintln!("Skipping, /dev/fuse is missing");
+    }
+    present
+}
+
+/// What the test changes behind the kernel's back.
+#[derive(Default)]
+struct Backing {
+    removed: AtomicBool,
+    size: AtomicUsize,
+}
+
+/// A root directory holding `file`, which the kernel is told to cache for
+/// as long as it likes. Retrieved data is sent to the test.
+struct Cached {
+    backing: Arc<Backing>,
+    retrieved: Mutex<Sender<(u64, Vec<u8>)>>,
+}
+
+impl Cached {
+    fn attr(&self, ino: Ino) -> Option<stat> {
+        let (mode, nlink, size) = match ino {
+            ROOT_INO => (libc::S_IFDIR | 0o755, 2, 0),
+            FILE_INO if !self.backing.removed.load(Ordering::SeqCst) => (
+                libc::S_IFREG | 0o644,
+                1,
+                self.backing.size.load(Ordering::SeqCst),
+            ),
+            _ => return None,
+        };
+
+        Some(stat {
+            st_ino: ino as _,
+            st_mode: mode,
+            st_nlink: nlink,
+            st_size: size as _,
+            ..Default::default()
+        })
+    }
+}
+
+impl LowLevelFileSystem for Cached {
+    fn lookup(&self, _req: &Request, parent: Ino, name: &OsStr, reply: ReplyEntry) {
+        match (parent, name.to_str(), self.attr(FILE_INO)) {
+            (ROOT_INO, Some("file"), Some(attr)) => reply.entry(TTL, &attr, 0),
+            _ => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn getattr(&self, _req: &Request, ino: Ino, _fi: Option<&fuse_file_info>, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(TTL, &attr),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn retrieve_reply(
+        &self,
+        _req: &Request,
+        cookie: u64,
+        _ino: Ino,
+        _off: off_t,
+        data: &[u8],
+        reply: ReplyNone,
+    ) {
+        let _ = self.retrieved.lock().unwrap().send((cookie, data.to_vec()));
+        reply.none();
+    }
+}
+
+/// Mounts [`Cached`], handing `test` the mount, what backs it and the
+/// retrieved data as it arrives.
+fn with_cached(test: impl FnOnce(&TempMount, &Backing, mpsc::Receiver<(u64, Vec<u8>)>)) {
+    if !has_fuse() {
+        return;
+    }
+
+    let backing = Arc::new(Backing {
+        size: 5.into(),
+        ..Default::default()
+    });
+    let (sender, retrieved) = mpsc::channel();
+    let fs = Cached {
+        backing: backing.clone(),
+        retrieved: Mutex::new(sender),
+    };
+
+    let mount = TempMount::lowlevel(fs).expect("Could not mount filesystem");
+    test(&mount, &backing, retrieved);
+}
+
+#[test]
+fn inval_inode_refreshes_attributes() {
+    with_cached(|mount, backing, _| {
+        let file = mount.path().join("file");
+        assert_eq!(fs::metadata(&file).unwrap().len(), 5);
+
+        backing.size.store(9, Ordering::SeqCst);
+        assert_eq!(fs::metadata(&file).unwrap().len(), 5);
+
+        mount.notifier().inval_inode(FILE_INO, -1, 0).unwrap();
+        assert_eq!(fs::metadata(&file).unwrap().len(), 9);
+    });
+}
+
+#[test]
+fn inval_entry_forgets_lookups() {
+    with_cached(|mount, backing, _| {
+        let file = mount.path().join("file");
+        assert!(file.exists());
+
+        backing.removed.store(true, Ordering::SeqCst);
+        mount
+            .notifier()
+            .inval_entry(ROOT_INO, OsStr::new("file"))
+            .unwrap();
+        assert!(!file.exists());
+
+        // It's no longer cached.
+        let e = mount
+            .notifier()
+            .inval_entry(ROOT_INO, OsStr::new("file"))
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+    });
+}
+
+#[test]
+fn store_then_retrieve() {
+    with_cached(|mount, _, retrieved| {
+        let notifier = mount.notifier();
+        assert!(mount.path().join("file").exists());
+
+        notifier.store(FILE_INO, 0, b"hello").unwrap();
+        notifier.retrieve(FILE_INO, 5, 0, 7).unwrap();
+
+        let (cookie, data) = retrieved.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(cookie, 7);
+        assert_eq!(data, b"hello");
+    });
+}
+
+#[test]
+fn fails_once_unmounted() {
+    if !has_fuse() {
+        return;
+    }
+
+    let notifier = TempMount::lowlevel(Cached {
+        backing: Arc::default(),
+        retrieved: Mutex::new(mpsc::channel().0),
+    })
+    .expect("Could not mount filesystem")
+    .notifier();
+
+    let e = notifier.inval_inode(FILE_INO, -1, 0).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENOTCONN));
+}
+
+#[test]
+fn inval_path_through_mount() {
+    if !has_fuse() {
+        return;
+    }
+
+    let mount = TempMount::new(MemFs::new()).expect("Could not mount filesystem");
+    let notifier = mount.notifier();
+    fs::write(mount.path().join("a"), b"hello").unwrap();
+    assert!(mount.path().join("a").exists());
+
+    notifier.inval_path("/a").unwrap();
+    assert_eq!(fs::read(mount.path().join("a")).unwrap(), b"hello");
+}
+
+#[test]
+fn inval_path_needs_the_high_level_api() {
+    with_cached(|mount, _, _| {
+        let e = mount.notifier().inval_path("/file").unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOSYS));
+    });
+}
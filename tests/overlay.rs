@@ -0,0 +1,124 @@
+//! Layers in-memory filesystems with the overlay, checking copy-up,
+//! whiteouts and the merged view through the mock.
+#![cfg(all(feature = "testing", not(feature = "fuse2")))]
+
+use fuse_sys::{
+    adapters::{Fault, FaultInjector, Rule},
+    memfs::MemFs,
+    overlay::Overlay,
+    testing::{conformance, MockSession},
+    Operation,
+};
+use nix::errno::Errno;
+
+/// A lower layer holding `/dir/a`, `/dir/sub/b` and `/c`.
+fn lower() -> MemFs {
+    memfs(&[
+        ("/dir/a", b"lower a"),
+        ("/dir/sub/b", b"lower b"),
+        ("/c", b"lower c"),
+    ])
+}
+
+fn overlay() -> MockSession<Overlay<MemFs, MemFs>> {
+    MockSession::new(Overlay::new(MemFs::new(), [lower()]))
+}
+
+#[test]
+fn conformance_mock() {
+    conformance::check_mock(|| Overlay::new(MemFs::new(), [MemFs::new()]));
+    conformance::check_mock(|| Overlay::new(MemFs::new(), [lower()]));
+}
+
+#[test]
+fn copy_up() {
+    let mut session = overlay();
+
+    assert_eq!(session.read_all("/dir/a").unwrap(), b"lower a");
+    session.write_all("/dir/a", b"upper a").unwrap();
+    assert_eq!(session.read_all("/dir/a").unwrap(), b"upper a");
+
+    let (upper, lowers) = session.into_filesystem().into_layers();
+    let mut upper = MockSession::new(upper);
+    let mut lower = MockSession::new(lowers.into_iter().next().unwrap());
+
+    assert_eq!(upper.read_all("/dir/a").unwrap(), b"upper a");
+    assert_eq!(lower.read_all("/dir/a").unwrap(), b"lower a");
+}
+
+#[test]
+fn failed_copy_up() {
+    let lower = FaultInjector::new(lower()).with_rule(
+        Rule::new(Fault::Error(Errno::EIO))
+            .on(Operation::Read)
+            .at("/dir/a"),
+    );
+    let mut session = MockSession::new(Overlay::new(MemFs::new(), [lower]));
+
+    assert_eq!(
+        session
+            .open("/dir/a", libc::O_RDWR)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EIO)
+    );
+
+    // Nothing is left behind in the upper layer to shadow the lower file.
+    let (upper, _) = session.into_filesystem().into_layers();
+    let mut upper = MockSession::new(upper);
+    assert!(upper.getattr("/dir").is_ok());
+    assert_eq!(
+        upper.getattr("/dir/a").unwrap_err().raw_os_error(),
+        Some(libc::ENOENT)
+    );
+}
+
+#[test]
+fn whiteouts() {
+    let mut session = overlay();
+
+    session.unlink("/c").unwrap();
+    assert_eq!(
+        session.read_all("/c").unwrap_err().raw_os_error(),
+        Some(libc::ENOENT)
+    );
+    assert_eq!(session.readdir("/").unwrap(), [".", "..", "dir"]);
+
+    session.write_all("/c", b"new c").unwrap();
+    assert_eq!(session.read_all("/c").unwrap(), b"new c");
+
+    assert_eq!(
+        session.rmdir("/dir").unwrap_err().raw_os_error(),
+        Some(libc::ENOTEMPTY)
+    );
+    session.unlink("/dir/sub/b").unwrap();
+    session.rmdir("/dir/sub").unwrap();
+    session.unlink("/dir/a").unwrap();
+    session.rmdir("/dir").unwrap();
+    assert_eq!(session.readdir("/").unwrap(), [".", "..", "c"]);
+
+    // A directory made where one was deleted doesn't show the old contents.
+    session.mkdir("/dir", 0o755).unwrap();
+    assert_eq!(session.readdir("/dir").unwrap(), [".", ".."]);
+}
+
+#[test]
+fn merged_readdir() {
+    let mut session = overlay();
+
+    session.write_all("/dir/new", b"").unwrap();
+    session.rename("/dir/a", "/dir/renamed").unwrap();
+
+    assert_eq!(
+        session.readdir("/dir").unwrap(),
+        [".", "..", "new", "renamed", "sub"]
+    );
+    assert_eq!(session.read_all("/dir/renamed").unwrap(), b"lower a");
+    assert_eq!(
+        session
+            .rename("/dir/sub", "/moved")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EXDEV)
+    );
+}
@@ -0,0 +1,121 @@
+//! Runs the passthrough filesystem through the conformance suite and a
+//! real mount. The mounted tests are skipped on hosts without `/dev/fuse`.
+#![cfg(all(feature = "testing", not(feature = "fuse2")))]
+
+use fuse_sys::{
+    passthrough::Passthrough,
+    testing::{conformance, TempMount},
+};
+use std::{cell::RefCell, fs, os::unix::fs as unix_fs, path::Path};
+use tempfile::TempDir;
+
+fn has_fuse() -> bool {
+    let present = Path::new("/dev/fuse").exists();
+    if !present {
+        eprintln!("Skipping, /dev/fuse is missing");
+    }
+    present
+}
+
+/// Mounts a passthrough of a fresh directory and runs `test` with the
+/// mountpoint and the backing directory.
+fn with_passthrough(test: impl FnOnce(&Path, &Path)) {
+    if !has_fuse() {
+        return;
+    }
+
+    let data = tempfile::tempdir().unwrap();
+    let fs = Passthrough::new(data.path());
+
+    TempMount::with(fs, |mount| test(mount, data.path()));
+}
+
+#[test]
+fn create_write_read() {
+    with_passthrough(|mount, data| {
+        fs::write(mount.join("a"), b"hello").unwrap();
+
+        assert_eq!(fs::read(mount.join("a")).unwrap(), b"hello");
+        assert_eq!(fs::read(data.join("a")).unwrap(), b"hello");
+    });
+}
+
+#[test]
+fn overwrite() {
+    with_passthrough(|mount, _| {
+        fs::write(mount.join("a"), b"hello world").unwrap();
+        fs::write(mount.join("a"), b"bye").unwrap();
+
+        assert_eq!(fs::read(mount.join("a")).unwrap(), b"bye");
+    });
+}
+
+#[test]
+fn rename() {
+    with_passthrough(|mount, data| {
+        fs::write(mount.join("a"), b"hello").unwrap();
+        fs::rename(mount.join("a"), mount.join("b")).unwrap();
+
+        assert!(!mount.join("a").exists());
+        assert_eq!(fs::read(mount.join("b")).unwrap(), b"hello");
+        assert!(data.join("b").exists());
+    });
+}
+
+#[test]
+fn readdir() {
+    with_passthrough(|mount, _| {
+        fs::create_dir(mount.join("dir")).unwrap();
+        fs::write(mount.join("a"), b"").unwrap();
+        fs::write(mount.join("dir/b"), b"").unwrap();
+
+        let mut names: Vec<_> = fs::read_dir(mount)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+
+        assert_eq!(names, ["a", "dir"]);
+        assert_eq!(
+            fs::read_dir(mount.join("dir"))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect::<Vec<_>>(),
+            ["b"]
+        );
+    });
+}
+
+#[test]
+fn symlink() {
+    with_passthrough(|mount, _| {
+        fs::write(mount.join("a"), b"hello").unwrap();
+        unix_fs::symlink("a", mount.join("link")).unwrap();
+
+        assert_eq!(fs::read_link(mount.join("link")).unwrap(), Path::new("a"));
+        assert_eq!(fs::read(mount.join("link")).unwrap(), b"hello");
+    });
+}
+
+/// A passthrough of a fresh directory, which is kept in `dirs` until the
+/// end of the test.
+fn fresh_passthrough(dirs: &RefCell<Vec<TempDir>>) -> Passthrough {
+    let dir = tempfile::tempdir().unwrap();
+    let fs = Passthrough::new(dir.path());
+    dirs.borrow_mut().push(dir);
+    fs
+}
+
+#[test]
+fn conformance_mock() {
+    let dirs = RefCell::new(vec![]);
+    conformance::check_mock(|| fresh_passthrough(&dirs));
+}
+
+#[test]
+fn conformance_mounted() {
+    if has_fuse() {
+        let dirs = RefCell::new(vec![]);
+        conformance::check_mounted(|| fresh_passthrough(&dirs));
+    }
+}
@@ -0,0 +1,187 @@
+//! Records a workload on an in-memory filesystem and replays it.
+#![cfg(all(feature = "testing", not(feature = "fuse2")))]
+
+use fuse_sys::{
+    adapters::{Op, Record, Recorder},
+    statvfs,
+    testing::{memfs, replay::replay, MockSession},
+    timespec,
+};
+use std::{
+    ffi::CString,
+    io, ptr,
+    sync::{Arc, Mutex},
+};
+
+/// Somewhere to record to that can be read back.
+#[derive(Clone, Default)]
+struct Log(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Log {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn record() -> Vec<Record> {
+    let log = Log::default();
+    let mut session = MockSession::new(Recorder::new(memfs(&[("/dir/a", b"hello")]), log.clone()));
+
+    session.write_all("/dir/my file", b"bye\n").unwrap();
+    assert_eq!(session.read_all("/dir/a").unwrap(), b"hello");
+    session.rename("/dir/my file", "/b").unwrap();
+    session.symlink("/b", "/link").unwrap();
+    session.mknod("/fifo", libc::S_IFIFO | 0o644, 0).unwrap();
+    assert_eq!(session.readdir("/dir").unwrap(), [".", "..", "a"]);
+
+    let path = CString::new("/b").unwrap();
+    let (name, value) = (CString::new("user.a").unwrap(), [0xff, 0, b'\n']);
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let times = [
+        timespec {
+            tv_sec: 1,
+            tv_nsec: 2,
+        },
+        timespec {
+            tv_sec: 3,
+            tv_nsec: 4,
+        },
+    ];
+    let mut fi = session.open("/b", libc::O_RDWR).unwrap();
+    let outs = session.call(|ops| unsafe {
+        [
+            ops.chown.unwrap()(path.as_ptr(), uid, gid, ptr::null_mut()),
+            ops.utimens.unwrap()(path.as_ptr(), times.as_ptr(), ptr::null_mut()),
+            ops.setxattr.unwrap()(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const _,
+                value.len(),
+                0,
+            ),
+            ops.removexattr.unwrap()(path.as_ptr(), name.as_ptr()),
+            ops.fallocate.unwrap()(path.as_ptr(), 0, 0, 4, &mut fi),
+        ]
+    });
+    assert_eq!(outs, [0; 5]);
+
+    // Whatever these give, replaying them should give the same.
+    let (mut stat, mut buf, mut index) = (statvfs::default(), [0u8; 64], 0);
+    session.call(|ops| unsafe {
+        ops.statfs.unwrap()(path.as_ptr(), &mut stat);
+        ops.access.unwrap()(path.as_ptr(), libc::R_OK);
+        ops.getxattr.unwrap()(
+            path.as_ptr(),
+            name.as_ptr(),
+            buf.as_mut_ptr() as *mut _,
+            buf.len(),
+        );
+        ops.listxattr.unwrap()(path.as_ptr(), buf.as_mut_ptr() as *mut _, buf.len());
+        ops.flock.unwrap()(path.as_ptr(), &mut fi, libc::LOCK_SH);
+        ops.bmap.unwrap()(path.as_ptr(), 4096, &mut index);
+    });
+    session.release("/b", &mut fi).unwrap();
+
+    assert!(session.unlink("/missing").is_err());
+    drop(session);
+
+    let log = log.0.lock().unwrap();
+    Record::read_all(&log[..]).unwrap()
+}
+
+#[test]
+fn records() {
+    let records = record();
+
+    let last = records.last().unwrap();
+    assert_eq!(
+        last.op,
+        Op::Unlink {
+            path: "/missing".to_string()
+        }
+    );
+    assert_eq!(last.result, -libc::ENOENT);
+    assert!(records.iter().any(|record| record.op
+        == Op::Rename {
+            from: "/dir/my file".to_string(),
+            to: "/b".to_string(),
+            flags: 0,
+        }));
+    assert!(records.iter().any(|record| record.op
+        == Op::Utimens {
+            path: "/b".to_string(),
+            times: Some([(1, 2), (3, 4)]),
+        }));
+    assert!(records.iter().any(|record| record.op
+        == Op::Access {
+            path: "/b".to_string(),
+            mask: libc::R_OK,
+        }));
+    assert!(records.iter().any(|record| record.op
+        == Op::Bmap {
+            path: "/b".to_string(),
+            blocksize: 4096,
+            index: 0,
+        }));
+    assert!(records.iter().any(|record| record.op
+        == Op::Setxattr {
+            path: "/b".to_string(),
+            name: "user.a".to_string(),
+            value: vec![0xff, 0, b'\n'],
+            flags: 0,
+        }));
+    for name in [
+        "mknod",
+        "chown",
+        "removexattr",
+        "fallocate",
+        "opendir",
+        "releasedir",
+        "statfs",
+        "access",
+        "getxattr",
+        "listxattr",
+        "flock",
+        "bmap",
+    ] {
+        assert!(
+            records
+                .iter()
+                .any(|record| record.to_string().split(' ').nth(2) == Some(name)),
+            "No {name} recorded"
+        );
+    }
+
+    // Every record comes back the same from its line.
+    for record in &records {
+        assert_eq!(record.to_string().parse::<Record>().unwrap(), *record);
+    }
+}
+
+#[test]
+fn replays() {
+    let records = record();
+
+    let mut session = MockSession::new(memfs(&[("/dir/a", b"hello")]));
+    assert!(replay(&mut session, &records).is_empty());
+    assert_eq!(session.read_all("/b").unwrap(), b"bye\n");
+    assert_eq!(session.readlink("/link").unwrap(), b"/b");
+    assert_eq!(
+        session.getattr("/fifo").unwrap().st_mode,
+        libc::S_IFIFO | 0o644
+    );
+    assert_eq!(session.getattr("/b").unwrap().st_mtim.tv_sec, 3);
+
+    // A filesystem that starts out different diverges where that shows.
+    let mut session = MockSession::new(memfs(&[("/dir/a", b"HELLO")]));
+    let divergences = replay(&mut session, &records);
+
+    assert_eq!(divergences.len(), 1);
+    assert!(divergences[0].data_differs);
+    assert!(matches!(divergences[0].record.op, Op::Read { .. }));
+}
@@ -0,0 +1,144 @@
+//! Routes paths to in-memory filesystems through the mock.
+#![cfg(all(feature = "testing", not(feature = "fuse2")))]
+
+use fuse_sys::{memfs::MemFs, router::Router, testing::MockSession};
+
+fn router() -> MockSession<Router> {
+    MockSession::new(
+        Router::new()
+            .route("/a", MemFs::new())
+            .route("/nested/b", MemFs::new())
+            .route("/nested/c/", MemFs::new()),
+    )
+}
+
+#[test]
+fn made_up_directories() {
+    let mut session = router();
+
+    assert_eq!(session.readdir("/").unwrap(), [".", "..", "a", "nested"]);
+    assert_eq!(session.readdir("/nested").unwrap(), [".", "..", "b", "c"]);
+
+    let stat = session.getattr("/nested").unwrap();
+    assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFDIR);
+
+    assert_eq!(
+        session.getattr("/missing").unwrap_err().raw_os_error(),
+        Some(libc::ENOENT)
+    );
+    assert_eq!(
+        session
+            .mkdir("/nested/d", 0o755)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOENT)
+    );
+    assert_eq!(
+        session.rmdir("/nested").unwrap_err().raw_os_error(),
+        Some(libc::EROFS)
+    );
+}
+
+#[test]
+fn empty() {
+    let mut session = MockSession::new(Router::new());
+
+    let stat = session.getattr("/").unwrap();
+    assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFDIR);
+    assert_eq!(session.readdir("/").unwrap(), [".", ".."]);
+    assert_eq!(
+        session.getattr("/missing").unwrap_err().raw_os_error(),
+        Some(libc::ENOENT)
+    );
+}
+
+#[test]
+fn routes() {
+    let mut session = router();
+
+    session.write_all("/a/file", b"in a").unwrap();
+    session.mkdir("/nested/b/dir", 0o755).unwrap();
+    session.write_all("/nested/b/dir/file", b"in b").unwrap();
+
+    assert_eq!(session.read_all("/a/file").unwrap(), b"in a");
+    assert_eq!(session.read_all("/nested/b/dir/file").unwrap(), b"in b");
+    assert_eq!(session.readdir("/a").unwrap(), [".", "..", "file"]);
+    assert_eq!(session.readdir("/nested/c").unwrap(), [".", ".."]);
+
+    session
+        .rename("/nested/b/dir/file", "/nested/b/moved")
+        .unwrap();
+    assert_eq!(session.read_all("/nested/b/moved").unwrap(), b"in b");
+}
+
+#[test]
+fn cross_backend() {
+    let mut session = router();
+    session.write_all("/a/file", b"").unwrap();
+
+    assert_eq!(
+        session
+            .rename("/a/file", "/nested/b/file")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EXDEV)
+    );
+    assert_eq!(
+        session
+            .link("/a/file", "/nested/c/file")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EXDEV)
+    );
+    assert_eq!(
+        session
+            .rename("/a/file", "/file")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EXDEV)
+    );
+}
+
+#[test]
+#[should_panic(expected = "overlaps")]
+fn overlapping_routes() {
+    let _ = Router::new()
+        .route("/a", MemFs::new())
+        .route("/a/b", MemFs::new());
+}
+
+#[cfg(fuse_3_4)]
+#[test]
+fn copy_file_range() {
+    let mut session = router();
+    session.write_all("/a/file", b"hello").unwrap();
+
+    let mut fi_in = session.open("/a/file", libc::O_RDONLY).unwrap();
+    let mut fi_out = session.create("/a/copy", 0o644, libc::O_WRONLY).unwrap();
+    let copied = session
+        .copy_file_range("/a/file", &mut fi_in, 0, "/a/copy", &mut fi_out, 0, 5)
+        .unwrap();
+    session.release("/a/copy", &mut fi_out).unwrap();
+    assert_eq!(copied, 5);
+    assert_eq!(session.read_all("/a/copy").unwrap(), b"hello");
+
+    // Copying into another route is left to the kernel's fallback.
+    let mut fi_out = session
+        .create("/nested/b/copy", 0o644, libc::O_WRONLY)
+        .unwrap();
+    let err = session
+        .copy_file_range(
+            "/a/file",
+            &mut fi_in,
+            0,
+            "/nested/b/copy",
+            &mut fi_out,
+            0,
+            5,
+        )
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+    session.release("/nested/b/copy", &mut fi_out).unwrap();
+    session.release("/a/file", &mut fi_in).unwrap();
+    assert_eq!(session.read_all("/nested/b/copy").unwrap(), b"");
+}
@@ -3,6 +3,14 @@
 #endif
 
 #define _FILE_OFFSET_BITS 64
+
+// build.rs passes -DFUSE_USE_VERSION=312 when generating bindings for
+// libfuse 3.12 and up, which is what exposes the fuse_loop_cfg_* API,
+// 32 for 3.2 and up, whose loops take a fuse_loop_config, and 26 for
+// libfuse 2 and macFUSE.
+#ifndef FUSE_USE_VERSION
 #define FUSE_USE_VERSION 31
+#endif
 
 #include <fuse.h>
+#include <fuse_lowlevel.h>